@@ -0,0 +1,56 @@
+use super::{report_while, CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
+use crate::utils::{flush_block_device_buffers, FileKind};
+use anyhow::Context;
+use std::fs::File;
+use std::path::Path;
+
+/// Drops the buffer cache of a block device with the BLKFLSBUF ioctl, after an fsync.
+///
+/// Meant for writing images to raw block devices, where there is no file system to unmount and
+/// no alignment constraint like with Direct IO. Needs root privileges.
+#[derive(Default, Debug)]
+pub struct BlkFlushCacheManager {}
+
+/// Returns an error if `path` is not a block device, or we are not root.
+fn check(path: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        FileKind::of_path(path)? == FileKind::Device,
+        "BlkFlushCacheManager only handles block devices, {} is not one",
+        path.display()
+    );
+    anyhow::ensure!(
+        nix::unistd::getuid().is_root() || std::env::var("CCCP_NO_ROOT").is_ok(),
+        "BlkFlushCacheManager needs root privileges for BLKFLSBUF"
+    );
+    Ok(())
+}
+
+impl CacheManager for BlkFlushCacheManager {
+    fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
+        check(path)
+    }
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        res.check(check(path));
+        Ok(res)
+    }
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        let f =
+            File::open(path).with_context(|| format!("open {} to drop cache", path.display()))?;
+        report_while(progress, &format!("Syncing {}", path.display()), || {
+            f.sync_all()
+        })
+        .with_context(|| format!("fsync({}) to drop cache", path.display()))?;
+        flush_block_device_buffers(&f)
+            .with_context(|| format!("dropping buffers of {}", path.display()))?;
+        Ok(None)
+    }
+    fn name(&self) -> &'static str {
+        "BlkFlushCacheManager"
+    }
+}
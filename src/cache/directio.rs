@@ -1,18 +1,95 @@
-use super::{CacheManager, Replacement};
-use crate::utils::FileKind;
+use super::{CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
+use crate::udev::underlying_device;
+use crate::utils::{block_sizes, cached_pages, FileKind};
 
 use anyhow::Context;
 
 use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use nix::errno::Errno;
 
+/// Size of the file written by the self check.
+const SELF_CHECK_SIZE: usize = 4 * 4096;
+
+#[repr(align(4096))]
+struct SelfCheckBuffer([u8; SELF_CHECK_SIZE]);
+
 #[derive(Default, Debug)]
 pub struct DirectIOCacheManager {}
 
+/// Returns the device node holding `path`, and its logical and physical block sizes.
+fn device_block_sizes(path: &Path) -> anyhow::Result<(PathBuf, u64, u64)> {
+    let node = if FileKind::of_path(path)? == FileKind::Device {
+        path.to_path_buf()
+    } else {
+        let dev = underlying_device(path)?;
+        match dev.devnode() {
+            Some(node) => node.to_path_buf(),
+            None => anyhow::bail!(
+                "No device node corresponding to {}",
+                dev.syspath().display()
+            ),
+        }
+    };
+    let f = File::open(&node).with_context(|| format!("opening {}", node.display()))?;
+    let (logical, physical) =
+        block_sizes(&f).with_context(|| format!("getting block sizes of {}", node.display()))?;
+    Ok((node, logical, physical))
+}
+
+impl DirectIOCacheManager {
+    /// Writes a pattern to a new file in `dir` with O_DIRECT, and reads it back with O_DIRECT.
+    /// Fails if the pattern is not read back, and otherwise returns how many pages of this file
+    /// ended up in the page cache, which is 0 if O_DIRECT was honored.
+    fn self_check(&self, dir: &Path) -> anyhow::Result<usize> {
+        let tmp_dir = tempfile::TempDir::new_in(dir).with_context(|| {
+            format!(
+                "creating a temporary directory in {} for the O_DIRECT self check",
+                dir.display()
+            )
+        })?;
+        let path = tmp_dir.path().join("self-check");
+        let mut written = Box::new(SelfCheckBuffer([0; SELF_CHECK_SIZE]));
+        for (i, byte) in written.0.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let mut f = self
+            .open_no_cache(
+                OpenOptions::new().read(true).write(true).create(true),
+                0,
+                &path,
+            )
+            .with_context(|| format!("open({}, O_DIRECT)", path.display()))?;
+        f.write_all(&written.0)
+            .with_context(|| format!("writing to {} with O_DIRECT", path.display()))?;
+        f.seek(std::io::SeekFrom::Start(0))
+            .with_context(|| format!("seeking in {}", path.display()))?;
+        let mut read = Box::new(SelfCheckBuffer([0; SELF_CHECK_SIZE]));
+        f.read_exact(&mut read.0)
+            .with_context(|| format!("reading from {} with O_DIRECT", path.display()))?;
+        anyhow::ensure!(
+            read.0[..] == written.0[..],
+            "reading {} with O_DIRECT did not return what was written",
+            path.display()
+        );
+        let cached = cached_pages(&f, SELF_CHECK_SIZE)
+            .with_context(|| format!("counting cached pages of {}", path.display()))?;
+        drop(f);
+        tmp_dir.close().with_context(|| {
+            format!(
+                "removing a temporary directory in {} after the O_DIRECT self check",
+                dir.display()
+            )
+        })?;
+        Ok(cached)
+    }
+}
+
 impl CacheManager for DirectIOCacheManager {
     fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
         fn test_file(x: &DirectIOCacheManager, path: &Path, create: bool) -> anyhow::Result<()> {
@@ -36,7 +113,11 @@ impl CacheManager for DirectIOCacheManager {
             }
         }
         match FileKind::of_path(path) {
-            Ok(FileKind::Symlink) | Ok(FileKind::Other) => Ok(()),
+            Ok(FileKind::Symlink)
+            | Ok(FileKind::Fifo)
+            | Ok(FileKind::CharDevice)
+            | Ok(FileKind::Socket)
+            | Ok(FileKind::Other) => Ok(()),
             Ok(FileKind::Device) | Ok(FileKind::Regular) => test_file(self, path, false),
             Ok(FileKind::Directory) => {
                 let tmp_dir = tempfile::TempDir::new_in(path).with_context(|| {
@@ -96,9 +177,68 @@ impl CacheManager for DirectIOCacheManager {
             .custom_flags(libc::O_DIRECT | custom_flags)
             .open(path)
     }
-    fn drop_cache(&mut self, _path: &Path) -> anyhow::Result<Option<Replacement>> {
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        // the check removes the files it creates
+        res.check(DirectIOCacheManager::default().permission_check(path));
+        if let Ok((node, logical, _)) = device_block_sizes(path) {
+            res.notes.push(format!(
+                "{} has a logical block size of {} bytes",
+                node.display(),
+                logical
+            ));
+        }
+        Ok(res)
+    }
+    fn drop_cache(
+        &mut self,
+        _progress: &Progress,
+        _path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
         Ok(None)
     }
+    fn report(&self, path: &Path) -> anyhow::Result<Vec<String>> {
+        let mut res = Vec::new();
+        res.push(match device_block_sizes(path) {
+            Ok((node, logical, physical)) => format!(
+                "directio: {} has a logical block size of {} bytes and a physical block size of {} bytes",
+                node.display(),
+                logical,
+                physical
+            ),
+            Err(e) => format!("directio: unknown block size: {:#}", e),
+        });
+        res.push(format!(
+            "directio: copy buffers are aligned to {} bytes",
+            crate::copy::buffer_alignment()
+        ));
+        let dir = match FileKind::of_path(path)? {
+            FileKind::Directory => Some(path),
+            FileKind::Device => None,
+            _ => path.parent(),
+        };
+        res.push(match dir {
+            None => format!(
+                "directio: no self check for {}, which is not on a file system",
+                path.display()
+            ),
+            Some(dir) => match self.self_check(dir) {
+                Ok(0) => format!(
+                    "directio: self check in {}: O_DIRECT was honored, {} bytes were written and read back without entering the page cache",
+                    dir.display(),
+                    SELF_CHECK_SIZE
+                ),
+                Ok(cached) => format!(
+                    "directio: self check in {}: O_DIRECT was NOT honored, {} of {} pages written and read back are in the page cache",
+                    dir.display(),
+                    cached,
+                    SELF_CHECK_SIZE / 4096
+                ),
+                Err(e) => format!("directio: self check in {} failed: {:#}", dir.display(), e),
+            },
+        });
+        Ok(res)
+    }
     fn name(&self) -> &'static str {
         "DirectIOCacheManager"
     }
@@ -0,0 +1,97 @@
+use super::{report_while, CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
+use crate::utils::{drop_page_cache, FileKind};
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Writes files normally, and before each round fsyncs every file of the copy and drops its
+/// page cache with fadvise.
+///
+/// For file systems which reject O_DIRECT. This is weaker than unmounting, as the file system
+/// may keep other caches and the drive its own, but it needs no privileges.
+///
+/// Only the first call to `drop_cache` for a path walks all the files below it, as they may
+/// have been read when resuming a copy. Later ones only flush the files written since then,
+/// which are the only ones read again.
+#[derive(Default, Debug)]
+pub struct FadviseCacheManager {
+    /// Paths whose cache was dropped at least once.
+    flushed: BTreeSet<PathBuf>,
+    /// Files written since the last call to `drop_cache`.
+    written: BTreeSet<PathBuf>,
+}
+
+/// Writes back the file at `path` and drops its page cache.
+fn flush_file(path: &Path) -> anyhow::Result<()> {
+    let f = File::open(path).with_context(|| format!("open({}) to flush it", path.display()))?;
+    f.sync_all()
+        .with_context(|| format!("fsync({}) to drop cache", path.display()))?;
+    drop_page_cache(&f).with_context(|| format!("dropping the page cache of {}", path.display()))
+}
+
+/// Writes back and drops the page cache of the regular files and block devices below `path`.
+pub(super) fn flush_below(path: &Path) -> anyhow::Result<()> {
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry.with_context(|| format!("iterating in {}", path.display()))?;
+        let meta = entry
+            .metadata()
+            .with_context(|| format!("stat({}) to drop cache", entry.path().display()))?;
+        match FileKind::of_metadata(&meta) {
+            FileKind::Regular | FileKind::Device => flush_file(entry.path())?,
+            FileKind::Directory
+            | FileKind::Symlink
+            | FileKind::Fifo
+            | FileKind::CharDevice
+            | FileKind::Socket
+            | FileKind::Other => (),
+        }
+    }
+    Ok(())
+}
+
+impl CacheManager for FadviseCacheManager {
+    fn permission_check(&mut self, _path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn probe(&self, _path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        res.notes
+            .push("does not bypass caches of the file system or of the drive".to_string());
+        Ok(res)
+    }
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        let what = format!("Flushing the files below {}", path.display());
+        if self.flushed.insert(path.to_path_buf()) {
+            report_while(progress, &what, || flush_below(path))?;
+        } else {
+            let written = &self.written;
+            report_while(progress, &what, || -> anyhow::Result<()> {
+                for file in written.iter().filter(|file| file.starts_with(path)) {
+                    match flush_file(file) {
+                        // replaced by another kind of file since
+                        Err(e)
+                            if e.downcast_ref::<std::io::Error>()
+                                .map_or(false, |e| e.kind() == ErrorKind::NotFound) => {}
+                        res => res?,
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        self.written.clear();
+        Ok(None)
+    }
+    fn note_written(&mut self, path: &Path) {
+        self.written.insert(path.to_path_buf());
+    }
+    fn name(&self) -> &'static str {
+        "FadviseCacheManager"
+    }
+}
@@ -0,0 +1,63 @@
+use super::fadvise::flush_below;
+use super::{report_while, CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
+use anyhow::Context;
+use nix::sys::statfs::{statfs, FsType};
+use std::path::Path;
+
+/// `f_type` of FUSE file systems, as returned by statfs.
+const FUSE_SUPER_MAGIC: FsType = FsType(0x6573_5546);
+
+/// Flushes and closes files on a FUSE file system between rounds.
+///
+/// FUSE file systems (sshfs, rclone mount, ...) often ignore O_DIRECT and syncfs, but their
+/// cache is per open file: `fix_path` reopens every file, so after an fsync and dropping the
+/// kernel page cache with fadvise the next read is fetched from the FUSE daemon again.
+#[derive(Default, Debug)]
+pub struct FuseCacheManager {}
+
+/// Returns whether `path`, or its nearest existing ancestor, is on a FUSE file system.
+fn is_fuse(path: &Path) -> anyhow::Result<bool> {
+    for ancestor in path.ancestors() {
+        match statfs(ancestor) {
+            Ok(stat) => return Ok(stat.filesystem_type() == FUSE_SUPER_MAGIC),
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("statfs({})", ancestor.display()));
+            }
+        }
+    }
+    anyhow::bail!("No ancestor of {} exists", path.display())
+}
+
+impl CacheManager for FuseCacheManager {
+    fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            is_fuse(path)?,
+            "{} is not on a FUSE file system, use another --mode",
+            path.display()
+        );
+        Ok(())
+    }
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        res.check(FuseCacheManager::default().permission_check(path));
+        Ok(res)
+    }
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        // fsync writes back the files to the FUSE daemon
+        report_while(
+            progress,
+            &format!("Flushing the files below {}", path.display()),
+            || flush_below(path),
+        )?;
+        Ok(None)
+    }
+    fn name(&self) -> &'static str {
+        "FuseCacheManager"
+    }
+}
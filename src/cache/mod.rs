@@ -1,21 +1,120 @@
+use crate::progress::Progress;
 use std::fs::{File, OpenOptions};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
+pub mod blkflush;
 pub mod directio;
+pub mod fadvise;
+pub mod fuse;
 pub mod umount;
 pub mod usbreset;
 pub mod vm;
 
+/// Interval between the status updates of `report_while` and `tick_while`.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs `f`, which may block for minutes like syncfs on a file system with many dirty pages,
+/// on another thread. Meanwhile, sets the status of `progress` to `what`, and every
+/// `LIVENESS_INTERVAL` to `what` and for how long it has been running, so that this does not
+/// look like a hang.
+pub fn report_while<T: Send>(progress: &Progress, what: &str, f: impl FnOnce() -> T + Send) -> T {
+    log::info!("{}", what);
+    progress.set_status(what);
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        // nothing is sent: the receiver is notified when the thread drops the sender
+        let (sender, receiver) = channel::<()>();
+        let thread = scope.spawn(move || {
+            let _sender = sender;
+            f()
+        });
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(LIVENESS_INTERVAL) {
+            progress.set_status(format!(
+                "{}, still running after {}s",
+                what,
+                start.elapsed().as_secs()
+            ));
+        }
+        let res = thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        log::debug!("{} took {:.1}s", what, start.elapsed().as_secs_f64());
+        res
+    })
+}
+
+/// Like `report_while`, but runs `f` on the current thread, for work which cannot be sent to
+/// another one like calls to udisks. Only the progress bar is updated while it runs, as
+/// `Progress` itself cannot be shared with the thread doing the updates.
+pub fn tick_while<T>(progress: &Progress, what: &str, f: impl FnOnce() -> T) -> T {
+    log::info!("{}", what);
+    progress.set_status(what);
+    let start = Instant::now();
+    let bar = progress.status_bar();
+    // nothing is sent: the ticker stops when the sender is dropped, even if `f` panics
+    let (sender, receiver) = channel::<()>();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(LIVENESS_INTERVAL) {
+                if let Some(bar) = bar.as_ref() {
+                    bar.set_message(&format!(
+                        "{}, still running after {}s",
+                        what,
+                        start.elapsed().as_secs()
+                    ));
+                }
+            }
+        });
+        let res = f();
+        drop(sender);
+        log::debug!("{} took {:.1}s", what, start.elapsed().as_secs_f64());
+        res
+    })
+}
+
 pub struct Replacement {
     pub before: PathBuf,
     pub after: PathBuf,
 }
 
+/// What `CacheManager::probe` found out about a path.
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    /// Why the cache manager cannot work for this path. Empty if it can.
+    pub problems: Vec<String>,
+    /// Other findings, like the privileges or the drive holding the path.
+    pub notes: Vec<String>,
+}
+
+impl Capabilities {
+    /// Whether the cache manager is expected to work for this path.
+    pub fn usable(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Records `result` as a problem if it is an error, and returns its value otherwise.
+    pub fn check<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.problems.push(format!("{:#}", e));
+                None
+            }
+        }
+    }
+}
+
 pub trait CacheManager {
     /// Returns an error if this Cache Manager is bound to fail (missing privileges, missing
     /// runtime deps, ...) for paths below `path`.
     fn permission_check(&mut self, path: &Path) -> anyhow::Result<()>;
+    /// Reports whether this Cache Manager can work for paths below `path`, like
+    /// `permission_check`, but without side effects: nothing is remounted, reset or left behind.
+    /// Errors are for unexpected failures, not for reasons why it cannot work.
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities>;
     /// Opens the spcified path with the specified open options.
     /// The custom_flags must be specified here, if set on the options, they will be ignored.
     fn open_no_cache(
@@ -30,7 +129,47 @@ pub trait CacheManager {
     /// read from a cache.
     /// If the result is not `None`, then the path at `result.before` is not mounted at
     /// `result.after`.
-    fn drop_cache(&mut self, path: &Path) -> anyhow::Result<Option<Replacement>>;
+    /// This may take minutes, so the status of `progress` should tell what is being waited for.
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>>;
+    /// Notifies that the regular file or block device `path` was written, and will be read
+    /// again after the next call to `drop_cache`.
+    fn note_written(&mut self, _path: &Path) {}
+    /// Describes, one line per item, how effectively caches are bypassed for paths below `path`.
+    /// Printed after the copy with `--verbose`.
+    fn report(&self, _path: &Path) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
     /// Just for debugging purposes
     fn name(&self) -> &'static str;
 }
+
+#[test]
+fn test_report_while() {
+    use crate::progress::ProgressEvent;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let statuses = Rc::new(RefCell::new(Vec::new()));
+    let recorded = statuses.clone();
+    let progress = Progress::with_callback(move |event| {
+        if let ProgressEvent::Status(msg) = event {
+            recorded.borrow_mut().push(msg.to_string());
+        }
+    });
+    let path = Path::new("/some/path");
+    let res = report_while(&progress, "Syncing", || path.join("file"));
+    assert_eq!(res, Path::new("/some/path/file"));
+    assert_eq!(*statuses.borrow(), vec!["Syncing".to_string()]);
+}
+
+#[test]
+fn test_tick_while() {
+    use std::rc::Rc;
+    // not Send, so it could not run in `report_while`
+    let path = Rc::new(PathBuf::from("/some/path"));
+    let res = tick_while(&Progress::quiet(), "Unmounting", || path.join("file"));
+    assert_eq!(res, Path::new("/some/path/file"));
+}
@@ -1,8 +1,10 @@
-use super::{CacheManager, Replacement};
+use super::{tick_while, CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
 use crate::udev::{ensure_mounted, get_udisk_blockdev_for, underlying_device};
 use crate::utils::{change_prefixes, get_mountpoint_in, FileKind};
 use anyhow::Context;
 use dbus_udisks2::{Block, UDisks2};
+use nix::sys::statvfs::{statvfs, FsFlags};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
@@ -11,7 +13,11 @@ const LONG_TIMEOUT: Duration = Duration::from_secs(3600);
 #[derive(Default)]
 /// Drops the page cache of a file system by unmounting then remounting it with
 /// udisks2.
-pub struct UmountCacheManager(Option<Inner>);
+pub struct UmountCacheManager {
+    inner: Option<Inner>,
+    /// Whether to remount the file system read-write if it is mounted read-only.
+    remount_rw: bool,
+}
 
 /// the content of UmountCacheManager after `permission_check` is called.
 struct Inner {
@@ -20,24 +26,48 @@ struct Inner {
     mountpoint: PathBuf,
 }
 
-impl CacheManager for UmountCacheManager {
-    fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
-        anyhow::ensure!(
-            !matches!(FileKind::of_path(path), Ok(FileKind::Device)),
-            "umount method can only handle files on a filesystem, not a block device {}",
-            path.display()
-        );
-        let udisks = UDisks2::new().context("Connecting to udisks dbus interface")?;
-        let dev = underlying_device(path)?;
-        let block = get_udisk_blockdev_for(&udisks, &dev)?;
-        anyhow::ensure!(
+impl UmountCacheManager {
+    pub fn new(remount_rw: bool) -> Self {
+        UmountCacheManager {
+            inner: None,
+            remount_rw,
+        }
+    }
+
+    /// Mount options to pass to udisks when remounting.
+    fn mount_options(&self) -> Option<&'static str> {
+        if self.remount_rw {
+            Some("rw")
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the file system mounted at `mountpoint` is mounted read-only.
+fn is_read_only(mountpoint: &Path) -> anyhow::Result<bool> {
+    let stat = statvfs(mountpoint).with_context(|| format!("statvfs({})", mountpoint.display()))?;
+    Ok(stat.flags().contains(FsFlags::ST_RDONLY))
+}
+
+/// Finds the file system bearing `path`, and where it is mounted.
+fn locate(path: &Path) -> anyhow::Result<(UDisks2, Block, PathBuf)> {
+    anyhow::ensure!(
+        !matches!(FileKind::of_path(path), Ok(FileKind::Device)),
+        "umount method can only handle files on a filesystem, not a block device {}",
+        path.display()
+    );
+    let udisks = UDisks2::new().context("Connecting to udisks dbus interface")?;
+    let dev = underlying_device(path)?;
+    let block = get_udisk_blockdev_for(&udisks, &dev)?;
+    anyhow::ensure!(
             block.has_fs(),
             "UDisks knows about no file system on block device {}, corresponding to sysfs {} and path {}",
             block.preferred_device.display(),
             dev.syspath().display(),
             path.display()
         );
-        let mountpoint = match get_mountpoint_in(&block, path) {
+    let mountpoint = match get_mountpoint_in(&block, path) {
             None => anyhow::bail!("File system on block device {}, corresponding to sysfs {}, does not looks like it bears {}: mount points {:?}",
             block.preferred_device.display(),
             dev.syspath().display(),
@@ -46,7 +76,60 @@ impl CacheManager for UmountCacheManager {
         ),
         Some(x) => x.to_path_buf(),
         };
-        self.0 = Some(Inner {
+    Ok((udisks, block, mountpoint))
+}
+
+/// Returns where `path`, on the file system formerly mounted on `mountpoint`, is now that it is
+/// mounted on `remounted`, or `None` if it did not move. `path` may be `mountpoint` itself.
+fn moved_path(path: &Path, mountpoint: &Path, remounted: &Path) -> Option<PathBuf> {
+    // comparing `path` to `remounted` instead would miss a move to a parent of `mountpoint`
+    if mountpoint == remounted {
+        None
+    } else {
+        Some(change_prefixes(mountpoint, remounted)(path))
+    }
+}
+
+impl CacheManager for UmountCacheManager {
+    fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
+        let (mut udisks, block, mountpoint) = locate(path)?;
+        if is_read_only(&mountpoint)? {
+            anyhow::ensure!(
+                self.remount_rw,
+                "Destination {} is mounted read-only on {}. Pass --remount-rw to remount it read-write.",
+                path.display(),
+                mountpoint.display()
+            );
+            udisks
+                .unmount(
+                    &block,
+                    /* interactive */ true,
+                    /* force */ false,
+                    LONG_TIMEOUT,
+                )
+                .with_context(|| {
+                    format!("Unmounting read-only {}", block.preferred_device.display())
+                })?;
+            let remounted_path = ensure_mounted(&mut udisks, &block, Some("rw"), LONG_TIMEOUT)
+                .with_context(|| {
+                    format!("Remounting {} read-write", block.preferred_device.display())
+                })?;
+            anyhow::ensure!(
+                remounted_path == mountpoint,
+                "Remounting {} read-write moved it from {} to {}, run cccp again with the new destination",
+                block.preferred_device.display(),
+                mountpoint.display(),
+                remounted_path.display()
+            );
+            anyhow::ensure!(
+                !is_read_only(&mountpoint)?,
+                "{} is still read-only after remounting it read-write, is the medium write-protected?",
+                mountpoint.display()
+            );
+            // refresh the udisks state after remounting
+            return self.permission_check(path);
+        }
+        self.inner = Some(Inner {
             udisks,
             fs: block,
             mountpoint,
@@ -54,27 +137,55 @@ impl CacheManager for UmountCacheManager {
         Ok(())
     }
 
-    fn drop_cache(&mut self, path: &Path) -> anyhow::Result<Option<Replacement>> {
-        let inner = self.0.as_mut().ok_or_else(|| {
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        if let Some((_, block, mountpoint)) = res.check(locate(path)) {
+            res.notes.push(format!(
+                "{} is mounted on {}",
+                block.preferred_device.display(),
+                mountpoint.display()
+            ));
+            if res.check(is_read_only(&mountpoint)) == Some(true) {
+                if self.remount_rw {
+                    res.notes
+                        .push("mounted read-only, would be remounted read-write".to_string());
+                } else {
+                    res.problems.push(format!(
+                        "{} is mounted read-only and --remount-rw was not passed",
+                        mountpoint.display()
+                    ));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        let options = self.mount_options();
+        let inner = self.inner.as_mut().ok_or_else(|| {
             anyhow::anyhow!("tried to drop_cache on uninitialised UmountCacheManager")
         })?;
-        inner
-            .udisks
-            .unmount(
+        // the udisks connection cannot be sent to another thread for `report_while`
+        let unmounting = format!("Unmounting {}", inner.fs.preferred_device.display());
+        tick_while(progress, &unmounting, || {
+            inner.udisks.unmount(
                 &inner.fs,
                 /* interactive */ true,
                 /* force */ false,
                 LONG_TIMEOUT,
             )
-            .with_context(|| format!("Unmounting {}", inner.fs.preferred_device.display()))?;
-        let remounted_path = ensure_mounted(&mut inner.udisks, &inner.fs, LONG_TIMEOUT)
-            .with_context(|| format!("Remounting {}", &inner.fs.preferred_device.display()))?;
-        let new_path = if path.starts_with(&remounted_path) {
-            None
-        } else {
-            let mut f = change_prefixes(inner.mountpoint.as_path(), remounted_path.as_path());
-            Some(f(path))
-        };
+        })
+        .context(unmounting)?;
+        let remounting = format!("Remounting {}", inner.fs.preferred_device.display());
+        let remounted_path = tick_while(progress, &remounting, || {
+            ensure_mounted(&mut inner.udisks, &inner.fs, options, LONG_TIMEOUT)
+        })
+        .context(remounting)?;
+        let new_path = moved_path(path, &inner.mountpoint, &remounted_path);
         // this refreshes the members and checks that the currently detected mountpoint corresponds
         // to new_path
         self.permission_check(match &new_path {
@@ -90,3 +201,25 @@ impl CacheManager for UmountCacheManager {
         "UmountCacheManager"
     }
 }
+
+#[test]
+fn test_moved_path() {
+    let p = |s: &str| PathBuf::from(s);
+    assert_eq!(
+        moved_path(&p("/media/a"), &p("/media/a"), &p("/media/a")),
+        None
+    );
+    assert_eq!(
+        moved_path(&p("/media/a"), &p("/media/a"), &p("/media/a1")),
+        Some(p("/media/a1"))
+    );
+    assert_eq!(
+        moved_path(&p("/media/a/dir"), &p("/media/a"), &p("/media/a1")),
+        Some(p("/media/a1/dir"))
+    );
+    assert_eq!(
+        moved_path(&p("/mnt/a/b/dir"), &p("/mnt/a/b"), &p("/mnt/a")),
+        Some(p("/mnt/a/dir"))
+    );
+    assert_eq!(moved_path(&p("/dir"), &p("/"), &p("/b")), Some(p("/b/dir")));
+}
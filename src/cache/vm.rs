@@ -1,5 +1,6 @@
-use super::{CacheManager, Replacement};
-use crate::utils::FileKind;
+use super::{report_while, CacheManager, Capabilities, Replacement};
+use crate::progress::Progress;
+use crate::utils::{drop_page_cache_below, flush_block_device_buffers, FileKind};
 use anyhow::anyhow;
 use anyhow::Context;
 use std::io::prelude::*;
@@ -18,8 +19,8 @@ fn syncfs<T: IntoRawFd + FromRawFd>(f: T) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn global_drop_cache(file: &Path) -> anyhow::Result<()> {
-    // first sync
+/// Writes back dirty pages of the file system of `file`, so that they can be dropped.
+fn sync_for_drop(file: &Path) -> anyhow::Result<()> {
     match FileKind::of_path(file)
         .with_context(|| format!("stat {} to drop cache", file.display()))?
     {
@@ -31,15 +32,16 @@ fn global_drop_cache(file: &Path) -> anyhow::Result<()> {
                 .with_context(|| format!("open({}) for sync to drop cache", file.display()))?;
             syncfs(f).with_context(|| format!("syncfs({}) to drop cache", file.display()))?;
         }
-        FileKind::Symlink => {
+        FileKind::Symlink | FileKind::Fifo | FileKind::CharDevice | FileKind::Socket => {
             // syncfs does not work on symlinks (how do I get a filedesc for a symlink ?) so let's
             // to syncfs on the parent. The parent always exists because / cannot be a symlink,
-            // right ?
+            // right ? Opening special files may block or have side effects, so they get the same
+            // treatment.
             let parent = match file.parent() {
                 Some(x) => x,
-                None => anyhow::bail!("Cannot syncfs(parent of {file}) because {file} is a symlink and has no parent. Is / a symlink ?", file = file.display()),
+                None => anyhow::bail!("Cannot syncfs(parent of {file}) because {file} is not a regular file or directory and has no parent. Is / a symlink ?", file = file.display()),
             };
-            return global_drop_cache(parent);
+            return sync_for_drop(parent);
         }
         FileKind::Device => {
             let f = std::fs::File::open(file)
@@ -54,7 +56,11 @@ fn global_drop_cache(file: &Path) -> anyhow::Result<()> {
             ))
         }
     }
-    // second drop cache
+    Ok(())
+}
+
+/// Drops the page cache of all file systems.
+fn global_drop_cache() -> anyhow::Result<()> {
     // tests need to skip this test, with an environment variable
     if std::env::var("CCCP_NO_ROOT").is_err() {
         let mut f = std::fs::File::create(VM_DROP_CACHES)
@@ -65,18 +71,79 @@ fn global_drop_cache(file: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Drops the page cache of `file` only, or of the files below it for a directory.
+fn targeted_drop_cache(file: &Path) -> anyhow::Result<()> {
+    match FileKind::of_path(file)
+        .with_context(|| format!("stat {} to drop cache", file.display()))?
+    {
+        FileKind::Device => {
+            let f = std::fs::File::open(file)
+                .with_context(|| format!("open {} to drop cache", file.display()))?;
+            flush_block_device_buffers(&f)
+                .with_context(|| format!("dropping buffers of {}", file.display()))?;
+        }
+        FileKind::Directory | FileKind::Regular => drop_page_cache_below(file)?,
+        // the content of symlinks is stored with their inode, which we cannot drop
+        FileKind::Symlink
+        | FileKind::Fifo
+        | FileKind::CharDevice
+        | FileKind::Socket
+        | FileKind::Other => (),
+    }
+    Ok(())
+}
+
 #[derive(Default, Debug)]
-pub struct PageCacheManager {}
+pub struct PageCacheManager {
+    /// Drop the page cache of all file systems, instead of only the one of the copy.
+    global: bool,
+}
+
+impl PageCacheManager {
+    pub fn new(global: bool) -> Self {
+        PageCacheManager { global }
+    }
+}
+
 impl CacheManager for PageCacheManager {
-    fn permission_check(&mut self, _path: &Path) -> anyhow::Result<()> {
-        if nix::unistd::getuid().is_root() || std::env::var("CCCP_NO_ROOT").is_ok() {
+    fn permission_check(&mut self, path: &Path) -> anyhow::Result<()> {
+        // BLKFLSBUF needs root privileges too
+        let needs_root = self.global || matches!(FileKind::of_path(path), Ok(FileKind::Device));
+        if !needs_root || nix::unistd::getuid().is_root() || std::env::var("CCCP_NO_ROOT").is_ok() {
             Ok(())
         } else {
             anyhow::bail!("PageCacheManager needs root privileges")
         }
     }
-    fn drop_cache(&mut self, path: &Path) -> anyhow::Result<Option<Replacement>> {
-        global_drop_cache(path)?;
+    fn probe(&self, path: &Path) -> anyhow::Result<Capabilities> {
+        let mut res = Capabilities::default();
+        res.notes.push(
+            if nix::unistd::getuid().is_root() {
+                "running as root"
+            } else {
+                "not running as root"
+            }
+            .to_string(),
+        );
+        // the check has no side effect
+        res.check(PageCacheManager::new(self.global).permission_check(path));
+        Ok(res)
+    }
+    fn drop_cache(
+        &mut self,
+        progress: &Progress,
+        path: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        report_while(
+            progress,
+            &format!("Syncing the file system of {}", path.display()),
+            || sync_for_drop(path),
+        )?;
+        if self.global {
+            global_drop_cache()?;
+        } else {
+            targeted_drop_cache(path)?;
+        }
         Ok(None)
     }
     fn name(&self) -> &'static str {
@@ -1,9 +1,214 @@
+use clap::arg_enum;
+use digest::Digest;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::JoinHandle;
+
+/// How many chunks may wait to be hashed by a threaded `Hasher` before `update` blocks.
+const HASHING_QUEUE_LEN: usize = 16;
+
+/// Length in bytes of the chunks that crc64 hashes in parallel with `set_parallel_chunks`.
+const PARALLEL_CHUNK_LEN: usize = 256 * 1024;
+
+/// Whether crc64 `Hasher`s split large inputs in chunks hashed in parallel.
+static PARALLEL_CHUNKS: AtomicBool = AtomicBool::new(false);
+
+/// Makes crc64 `Hasher`s created afterwards split inputs of several `PARALLEL_CHUNK_LEN` bytes
+/// in chunks hashed in parallel by rayon. The checksums do not change.
+pub fn set_parallel_chunks(enabled: bool) {
+    PARALLEL_CHUNKS.store(enabled, Ordering::Relaxed);
+}
+
+/// Length in bytes of the longest checksum of all `ChecksumAlgorithm`s.
+const MAX_CHECKSUM_LEN: usize = 32;
+
+/// The output of a `ChecksumAlgorithm`, from 8 to `MAX_CHECKSUM_LEN` bytes.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
-pub struct Checksum(u64);
+pub struct Checksum {
+    len: u8,
+    bytes: [u8; MAX_CHECKSUM_LEN],
+}
+
+impl Checksum {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut res = Checksum {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_CHECKSUM_LEN],
+        };
+        res.bytes[..bytes.len()].copy_from_slice(bytes);
+        res
+    }
+
+    /// Returns the bytes of the checksum.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+arg_enum! {
+    /// Hash function used to detect changes of the source between rounds.
+    /// `Crc64` is fast but only detects accidental changes, `Sha256` and `Blake3` are
+    /// cryptographic hashes, `Blake3` being the fastest of them on modern cpus.
+    /// `None` computes no checksum at all: copies are only checked by comparing bytes.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ChecksumAlgorithm {
+        Crc64,
+        Sha256,
+        Blake3,
+        None,
+    }
+}
+
+/// Computes a checksum with the chosen `ChecksumAlgorithm`.
+pub enum Hasher {
+    Crc64(Crc64Hasher),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+    /// Ignores its input, and finishes to a constant checksum.
+    None,
+    /// Sends the input to another `Hasher` running on its own thread.
+    Threaded {
+        sender: SyncSender<Vec<u8>>,
+        thread: JoinHandle<Checksum>,
+    },
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Crc64 => Hasher::Crc64(Crc64Hasher {
+                parallel: PARALLEL_CHUNKS.load(Ordering::Relaxed),
+                ..Crc64Hasher::default()
+            }),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::default()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            ChecksumAlgorithm::None => Hasher::None,
+        }
+    }
+
+    /// Like `new`, but hashes on a separate thread, so that the caller can read the next chunk
+    /// while the previous one is hashed.
+    pub fn threaded(algorithm: ChecksumAlgorithm) -> Self {
+        if algorithm == ChecksumAlgorithm::None {
+            return Hasher::None;
+        }
+        let (sender, receiver) = sync_channel::<Vec<u8>>(HASHING_QUEUE_LEN);
+        let thread = std::thread::spawn(move || {
+            let mut hasher = Hasher::new(algorithm);
+            for chunk in receiver {
+                hasher.update(chunk);
+            }
+            hasher.finish()
+        });
+        Hasher::Threaded { sender, thread }
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Hasher::Crc64(h) if h.parallel => h.write_parallel(data.as_ref()),
+            Hasher::Crc64(h) => h.write(data.as_ref()),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data.as_ref());
+            }
+            Hasher::None => (),
+            Hasher::Threaded { sender, .. } => {
+                // if the thread died, the panic is reported by `finish`
+                let _ = sender.send(data.as_ref().to_vec());
+            }
+        }
+    }
+
+    pub fn finish(self) -> Checksum {
+        match self {
+            Hasher::Crc64(h) => h.into(),
+            Hasher::Sha256(h) => h.into(),
+            Hasher::Blake3(h) => Checksum::from_bytes(h.finalize().as_bytes()),
+            Hasher::None => Checksum::from_bytes(&[0; 8]),
+            Hasher::Threaded { sender, thread } => {
+                drop(sender);
+                thread.join().expect("hashing thread panicked")
+            }
+        }
+    }
+}
+
+/// Returns the instructions `crc64fast` uses on this cpu, or `None` if it falls back to its
+/// portable table based implementation. It detects them at runtime the same way.
+fn crc64_acceleration() -> Option<&'static str> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1") {
+            return Some("pclmulqdq and sse4.1");
+        }
+    }
+    None
+}
+
+/// Returns the instructions `sha2` uses on this cpu, if any. It detects them at runtime the
+/// same way.
+fn sha256_acceleration() -> Option<&'static str> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sha") && is_x86_feature_detected!("sse4.1") {
+            return Some("the sha extensions");
+        }
+    }
+    None
+}
+
+/// Returns the widest vector instructions `blake3` uses on this cpu, if any. It detects them at
+/// runtime the same way.
+fn blake3_acceleration() -> Option<&'static str> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+            return Some("avx512");
+        }
+        if is_x86_feature_detected!("avx2") {
+            return Some("avx2");
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            return Some("sse4.1");
+        }
+        if is_x86_feature_detected!("sse2") {
+            return Some("sse2");
+        }
+    }
+    None
+}
+
+/// Describes whether `algorithm` is hardware accelerated on this machine, for `--verbose`.
+pub fn acceleration_report(algorithm: ChecksumAlgorithm) -> String {
+    match algorithm {
+        ChecksumAlgorithm::Crc64 => match crc64_acceleration() {
+            Some(instructions) => format!("checksum: crc64 is computed with {}", instructions),
+            None => "checksum: crc64 is not hardware accelerated on this cpu, hashing may be slower than reading".to_string(),
+        },
+        ChecksumAlgorithm::Sha256 => match sha256_acceleration() {
+            Some(instructions) => format!("checksum: sha256 is computed with {}", instructions),
+            None => "checksum: sha256 is not hardware accelerated on this cpu, consider --checksum=blake3".to_string(),
+        },
+        ChecksumAlgorithm::Blake3 => match blake3_acceleration() {
+            Some(instructions) => format!("checksum: blake3 is computed with {}", instructions),
+            None => "checksum: blake3 is not vectorized on this cpu, hashing may be slower than reading".to_string(),
+        },
+        ChecksumAlgorithm::None => "checksum: none computed".to_string(),
+    }
+}
 
 /// Sets `to_fill` to `Some(value)` and returns an error if `to_fill` is `Some(v2)` where
-/// `v2 != value`
-pub fn fill_checksum(to_fill: &mut Option<Checksum>, value: Checksum) -> anyhow::Result<()> {
+/// `v2 != value`. Does nothing with `ChecksumAlgorithm::None`, which has no checksum to compare.
+pub fn fill_checksum(
+    algorithm: ChecksumAlgorithm,
+    to_fill: &mut Option<Checksum>,
+    value: Checksum,
+) -> anyhow::Result<()> {
+    if algorithm == ChecksumAlgorithm::None {
+        *to_fill = Some(value);
+        return Ok(());
+    }
     match *to_fill {
         Some(v) if v != value => anyhow::bail!("wrong checksum"),
         _ => (),
@@ -12,40 +217,238 @@ pub fn fill_checksum(to_fill: &mut Option<Checksum>, value: Checksum) -> anyhow:
     Ok(())
 }
 
+/// Reversed generator polynomial of the crc64 of `crc64fast`, that of xz.
+const CRC64_POLY: u64 = 0xc96c_5795_d787_0f42;
+
+/// Multiplies two polynomials modulo `CRC64_POLY`, in the reversed bit order of crc64: the
+/// coefficient of x^0 is the most significant bit. Adapted from `multmodp` in zlib.
+const fn crc64_multmodp(a: u64, mut b: u64) -> u64 {
+    let mut m = 1 << 63;
+    let mut p = 0;
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                break;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 != 0 {
+            (b >> 1) ^ CRC64_POLY
+        } else {
+            b >> 1
+        };
+    }
+    p
+}
+
+/// `x^(2^n)` modulo `CRC64_POLY` for every `n`.
+const CRC64_X2N: [u64; 64] = {
+    let mut table = [0; 64];
+    // x^1
+    let mut p = 1 << 62;
+    table[0] = p;
+    let mut n = 1;
+    while n < 64 {
+        p = crc64_multmodp(p, p);
+        table[n] = p;
+        n += 1;
+    }
+    table
+};
+
+/// Returns the crc64 of the concatenation of a string of crc64 `crc1` and a string of
+/// `len2` bytes and crc64 `crc2`, as `crc32_combine` in zlib: appending `len2` bytes multiplies
+/// the crc of the first string by `x^(8 * len2)`, and the initial and final xor of crc64 cancel
+/// out.
+fn crc64_combine(crc1: u64, crc2: u64, mut len2: u64) -> u64 {
+    // x^0
+    let mut shift = 1 << 63;
+    // x^(8 * 2^k)
+    let mut k = 3;
+    while len2 != 0 {
+        if len2 & 1 != 0 {
+            shift = crc64_multmodp(CRC64_X2N[k & 63], shift);
+        }
+        len2 >>= 1;
+        k += 1;
+    }
+    crc64_multmodp(shift, crc1) ^ crc2
+}
+
+/// Computes the crc64 of `crc64fast`, of which checksums can be combined.
 #[derive(Clone, Default)]
-pub struct Crc64Hasher(crc64fast::Digest);
+pub struct Crc64Hasher {
+    /// crc64 of the `prefix_len` first bytes of the input, before what `digest` hashed.
+    prefix: u64,
+    prefix_len: u64,
+    digest: crc64fast::Digest,
+    /// Number of bytes hashed by `digest`.
+    digest_len: u64,
+    /// Whether `Hasher` hashes large inputs with `write_parallel`.
+    parallel: bool,
+}
+
+impl Crc64Hasher {
+    fn write(&mut self, data: &[u8]) {
+        self.digest.write(data);
+        self.digest_len += data.len() as u64;
+    }
+
+    /// Number of bytes hashed.
+    fn len(&self) -> u64 {
+        self.prefix_len + self.digest_len
+    }
+
+    fn sum64(&self) -> u64 {
+        crc64_combine(self.prefix, self.digest.sum64(), self.digest_len)
+    }
+
+    /// Appends the input of `other` to the input of `self`, as if `self` had also hashed it.
+    pub fn combine(&mut self, other: &Crc64Hasher) {
+        self.prefix = crc64_combine(self.sum64(), other.sum64(), other.len());
+        self.prefix_len = self.len() + other.len();
+        self.digest = crc64fast::Digest::new();
+        self.digest_len = 0;
+    }
+
+    /// Like `write`, but hashes chunks of `PARALLEL_CHUNK_LEN` bytes of `data` in parallel.
+    fn write_parallel(&mut self, data: &[u8]) {
+        if data.len() < 2 * PARALLEL_CHUNK_LEN {
+            return self.write(data);
+        }
+        let chunks: Vec<Crc64Hasher> = data
+            .par_chunks(PARALLEL_CHUNK_LEN)
+            .map(|chunk| {
+                let mut hasher = Crc64Hasher::default();
+                hasher.write(chunk);
+                hasher
+            })
+            .collect();
+        for chunk in &chunks {
+            self.combine(chunk);
+        }
+    }
+}
 
 impl digest::Update for Crc64Hasher {
     fn update(&mut self, data: impl AsRef<[u8]>) {
-        self.0.write(data.as_ref())
+        self.write(data.as_ref())
     }
 }
 
 impl digest::Reset for Crc64Hasher {
     fn reset(&mut self) {
-        self.0 = crc64fast::Digest::new();
+        *self = Crc64Hasher {
+            parallel: self.parallel,
+            ..Crc64Hasher::default()
+        };
     }
 }
 
 impl digest::FixedOutputDirty for Crc64Hasher {
     type OutputSize = typenum::U8;
     fn finalize_into_dirty(&mut self, out: &mut generic_array::GenericArray<u8, Self::OutputSize>) {
-        let res = self.0.sum64();
-        out.as_mut_slice().copy_from_slice(&res.to_ne_bytes());
+        let res = self.sum64();
+        out.as_mut_slice().copy_from_slice(&res.to_be_bytes());
     }
 }
 
 impl<T> From<T> for Checksum
 where
-    T: digest::Digest<OutputSize = typenum::U8>,
+    T: digest::Digest,
 {
     fn from(t: T) -> Checksum {
-        Checksum(u64::from_ne_bytes(t.finalize().into()))
+        Checksum::from_bytes(t.finalize().as_slice())
+    }
+}
+
+/// Lowercase hexadecimal.
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in &self.bytes[..self.len as usize] {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Checksum {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        anyhow::ensure!(
+            s.len() % 2 == 0 && s.len() <= 2 * MAX_CHECKSUM_LEN && s.is_ascii(),
+            "{:?} is not the hexadecimal representation of a checksum",
+            s
+        );
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+        Ok(Checksum::from_bytes(&bytes))
     }
 }
 
+/// Checksums of the same algorithm have the same length. Otherwise, the shorter one is
+/// padded with zeros.
 impl std::ops::BitXorAssign for Checksum {
     fn bitxor_assign(&mut self, rhs: Checksum) {
-        self.0 = self.0 ^ rhs.0
+        for (a, b) in self.bytes.iter_mut().zip(&rhs.bytes) {
+            *a ^= b;
+        }
+        self.len = self.len.max(rhs.len);
+    }
+}
+
+#[test]
+fn test_threaded_hasher() {
+    let mut sequential = Hasher::new(ChecksumAlgorithm::Crc64);
+    let mut threaded = Hasher::threaded(ChecksumAlgorithm::Crc64);
+    for i in 0..100u8 {
+        let chunk = vec![i; 1000];
+        sequential.update(&chunk);
+        threaded.update(&chunk);
+    }
+    assert_eq!(sequential.finish(), threaded.finish());
+}
+
+#[test]
+fn test_checksum_hex() {
+    let checksum: Checksum = "00000000deadbeef".parse().unwrap();
+    assert_eq!(checksum, Checksum::from_bytes(&0xdeadbeefu64.to_be_bytes()));
+    assert_eq!(checksum.to_string(), "00000000deadbeef");
+    let long = "ab".repeat(MAX_CHECKSUM_LEN);
+    assert_eq!(long.parse::<Checksum>().unwrap().to_string(), long);
+    assert!("abc".parse::<Checksum>().is_err());
+    assert!("zz".parse::<Checksum>().is_err());
+    assert!("ab"
+        .repeat(MAX_CHECKSUM_LEN + 1)
+        .parse::<Checksum>()
+        .is_err());
+}
+
+#[test]
+fn test_crc64_combine() {
+    let data: Vec<u8> = (0..5 * PARALLEL_CHUNK_LEN + 12345)
+        .map(|i| (i * 7 + i / 251) as u8)
+        .collect();
+    let mut sequential = Crc64Hasher::default();
+    sequential.write(&data);
+    let expected = sequential.sum64();
+    for &split in &[0, 1, 4096, PARALLEL_CHUNK_LEN, data.len() - 1, data.len()] {
+        let (a, b) = data.split_at(split);
+        let mut first = Crc64Hasher::default();
+        first.write(a);
+        let mut second = Crc64Hasher::default();
+        second.write(b);
+        first.combine(&second);
+        assert_eq!(first.sum64(), expected, "split at {}", split);
     }
+    let mut parallel = Crc64Hasher::default();
+    parallel.write(&data[..10]);
+    parallel.write_parallel(&data[10..]);
+    assert_eq!(parallel.sum64(), expected);
+    let mut check = Crc64Hasher::default();
+    check.write(b"123456789");
+    assert_eq!(check.sum64(), 0x995d_c9bb_df19_39fa);
 }
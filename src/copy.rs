@@ -1,29 +1,490 @@
-use crate::cache::CacheManager;
-use crate::checksum::{fill_checksum, Checksum, Crc64Hasher};
+use crate::cache::{CacheManager, Replacement};
+use crate::checksum::{fill_checksum, Checksum, ChecksumAlgorithm, Hasher};
 use crate::progress::Progress;
-use crate::utils::FileKind;
+use crate::throttle::Throttle;
+use crate::utils::{
+    block_device_size, change_prefixes, drop_page_cache, list_xattrs, lremove_xattr, lset_xattr,
+    next_data_region, no_space_error, read_xattr, ByteRange, ChmodSpec, FileKind, SplitMix64,
+};
+use crate::vhd::VhdFooter;
+use crate::xattr::XATTR_PREFIX;
 use anyhow::anyhow;
 use anyhow::Context;
-use digest::Digest;
+use clap::arg_enum;
+use globset::GlobSet;
 use nix::errno::Errno;
-use std::collections::HashSet;
+use nix::sys::stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, geteuid, FchownatFlags, Gid, Uid};
+use std::alloc::Layout;
+use std::collections::BTreeSet;
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
-use std::os::unix::io::{FromRawFd, IntoRawFd};
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-// 8 pages
-#[repr(align(4096))]
-struct Buffer([u8; 32768]);
+/// Default size of the buffers used for copying: 8 pages.
+pub const DEFAULT_BLOCK_SIZE: usize = 32768;
 
-/// Evaluates to a stack allocated buffer aligned to 4096. Used for Direct IO.
-// Costs an extra memcpy, but oh well...
-macro_rules! aligned_buffer({} => {Buffer([0; 32768]).0});
+/// A zeroed heap allocated buffer aligned to 4096. Used for Direct IO.
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(len.max(1), 4096).expect("buffer too large");
+        // safety: the layout has a non zero size
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl std::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        // safety: the allocation is initialized and lives as long as self
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // safety: the allocation is initialized and borrowed mutably with self
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // safety: allocated in `new` with this layout
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Returns the alignment of the buffers used for copying, up to a page, for reporting.
+pub fn buffer_alignment() -> usize {
+    let buffer = AlignedBuffer::new(DEFAULT_BLOCK_SIZE);
+    1 << (buffer.as_ptr() as usize).trailing_zeros().min(12)
+}
+
+arg_enum! {
+    /// What to do with symlinks of the source: `Preserve` copies them as symlinks, `Copy`
+    /// copies what they point to instead, and `Skip` does not copy them at all.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum LinkPolicy {
+        Preserve,
+        Copy,
+        Skip,
+    }
+}
+
+arg_enum! {
+    /// What to do with entries of an existing copy which are not in the source, or are in the
+    /// way of the copy: `Always` removes them, `Never` keeps them, and `Ask` asks on the
+    /// terminal for each of them.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum DeletePolicy {
+        Never,
+        Ask,
+        Always,
+    }
+}
+
+arg_enum! {
+    /// Attributes of the source which `--preserve` gives to the copy.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Preserve {
+        Timestamps,
+        Owner,
+        Xattr,
+    }
+}
+
+/// Tunables for copying and fixing, set from the command line.
+#[derive(Debug)]
+pub struct CopyOptions {
+    /// Also checksum the copy while rereading it in `fix_file`, and warn when this checksum and
+    /// the byte comparison disagree.
+    pub double_read: bool,
+    /// Do not reread regions of the copy where the source only contains zeros, trusting them to
+    /// be holes or zeros, and do not write the holes of the source to new copies.
+    pub sparse: bool,
+    /// Hash function used to detect changes of the source.
+    pub checksum: ChecksumAlgorithm,
+    /// Hash on separate threads in `fix_file`, so that reading is not stalled by hashing.
+    pub parallel_hashing: bool,
+    /// Make the copy of a single file a fixed VHD image by appending this footer to it.
+    pub vhd_footer: Option<VhdFooter>,
+    /// Names of marker files: directories containing one are not copied.
+    pub exclude_if_present: Vec<OsString>,
+    /// Entries which are not copied, and not removed from an existing copy by `fix_path`.
+    pub exclude: Option<Exclude>,
+    /// Regular files larger than this many bytes are not copied.
+    pub exclude_larger_than: Option<u64>,
+    /// Regular files smaller than this many bytes are not copied.
+    pub exclude_smaller_than: Option<u64>,
+    /// Directory where `fix_file` saves the differing bytes of the source and the copy.
+    pub mismatch_dump: Option<PathBuf>,
+    /// Create all directories of the copy before copying any file.
+    pub preallocate_dirs: bool,
+    /// Only copy these entries of the source, relative to it, and their parent directories,
+    /// instead of the whole tree.
+    pub files: Option<Vec<PathBuf>>,
+    /// Regular files of the source may grow during the copy: only compare and checksum the
+    /// length recorded for them, and copy what was appended afterwards.
+    pub append_tolerant: bool,
+    /// What to do with symlinks of the source.
+    pub links: LinkPolicy,
+    /// What to do with entries of an existing copy which are not in the source.
+    pub delete: DeletePolicy,
+    /// Offset in the copy of a single file where the copy of the source begins.
+    pub dest_offset: u64,
+    /// Only copy and checksum this range of a single source file.
+    pub source_range: Option<ByteRange>,
+    /// Permissions given to the files and directories created by the copy, instead of those of
+    /// the source.
+    pub chmod: Option<ChmodSpec>,
+    /// Give copied files and symlinks the access and modification times of the source, and fix
+    /// copies whose modification time differs.
+    pub preserve_timestamps: bool,
+    /// Give copies the owner and group of the source, and fix copies whose owner differs.
+    /// Needs root.
+    pub preserve_owner: bool,
+    /// Give copies the extended attributes of the source, and fix copies whose extended
+    /// attributes differ.
+    pub preserve_xattrs: bool,
+    /// Drop the page cache of each source file once it was read, so that reading the source
+    /// does not evict more useful data from the cache.
+    pub drop_source_cache: bool,
+    /// How many times `fix_file` reads each region of the copy. Only the last read is compared,
+    /// the previous ones may be served from the cache of the controller of the destination.
+    pub verify_reads: u32,
+    /// How many times a failed read of `copy_file` or `fix_file` is retried before giving up.
+    pub retry_block: u32,
+    /// How many bytes ahead of what `fix_file` compares to ask the kernel to read from the copy
+    /// in the background, if set.
+    pub readahead: Option<u64>,
+    /// Size of the buffers used to read and write files.
+    pub block_size: usize,
+    /// Do not change the access time of source files by reading them.
+    pub preserve_atime: bool,
+    /// Limit of the throughput of `copy_file`, and of `fix_file` without `verify_throttle`.
+    pub throttle: Option<Throttle>,
+    /// Limit of the throughput of `fix_file`.
+    pub verify_throttle: Option<Throttle>,
+    /// When `copy_file` fails, move what was written to `<target>.partial`.
+    pub keep_partial: bool,
+    /// Make `fix_path` only compare, and report that the copy needs fixing without writing to
+    /// it, and `copy_path` only report what it would copy. Not supported with `vhd_footer`.
+    pub read_only: bool,
+    /// Also cover these attributes by the checksums of regular files, and give the copy the
+    /// permissions of the source.
+    pub checksum_metadata: Option<MetadataFields>,
+}
+
+/// Entries of the source matching glob patterns, which are not copied.
+#[derive(Debug, Clone)]
+pub struct Exclude {
+    /// Roots of the trees being copied, or compared to the copy: patterns are matched against
+    /// paths relative to them.
+    pub roots: Vec<PathBuf>,
+    pub patterns: GlobSet,
+}
+
+impl Exclude {
+    /// Whether the entry at `path`, below one of the roots, is excluded. Roots themselves are
+    /// never excluded.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .map_or(false, |relative| {
+                !relative.as_os_str().is_empty() && self.patterns.is_match(relative)
+            })
+    }
+}
+
+/// Attributes of regular files which are covered by their checksum, besides their permissions,
+/// with `--checksum-include-metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataFields {
+    pub owner: bool,
+    pub mtime: bool,
+}
+
+/// Returns the bytes fed to the checksum of a regular file with metadata `meta` after its
+/// content, with `--checksum-include-metadata`. `mode` is the permissions the file has, or its
+/// copy must have.
+fn metadata_bytes(fields: MetadataFields, meta: &std::fs::Metadata, mode: u32) -> Vec<u8> {
+    let mut res = (mode & 0o7777).to_be_bytes().to_vec();
+    if fields.owner {
+        res.extend_from_slice(&meta.uid().to_be_bytes());
+        res.extend_from_slice(&meta.gid().to_be_bytes());
+    }
+    if fields.mtime {
+        res.extend_from_slice(&meta.mtime().to_be_bytes());
+        res.extend_from_slice(&meta.mtime_nsec().to_be_bytes());
+    }
+    res
+}
+
+/// Permissions that the copy of a regular file with metadata `meta` must have.
+fn copy_mode(options: &CopyOptions, meta: &std::fs::Metadata) -> u32 {
+    match options.chmod.as_ref() {
+        Some(spec) => spec.apply(meta.mode(), false),
+        None => meta.mode() & 0o7777,
+    }
+}
+
+impl Default for CopyOptions {
+    /// The defaults of the command line.
+    fn default() -> CopyOptions {
+        CopyOptions {
+            double_read: false,
+            sparse: false,
+            checksum: ChecksumAlgorithm::Crc64,
+            parallel_hashing: false,
+            vhd_footer: None,
+            exclude_if_present: Vec::new(),
+            exclude: None,
+            exclude_larger_than: None,
+            exclude_smaller_than: None,
+            mismatch_dump: None,
+            preallocate_dirs: false,
+            files: None,
+            append_tolerant: false,
+            links: LinkPolicy::Preserve,
+            delete: DeletePolicy::Always,
+            dest_offset: 0,
+            source_range: None,
+            chmod: None,
+            preserve_timestamps: false,
+            preserve_owner: false,
+            preserve_xattrs: false,
+            drop_source_cache: true,
+            verify_reads: 1,
+            retry_block: 0,
+            readahead: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            preserve_atime: false,
+            throttle: None,
+            verify_throttle: None,
+            keep_partial: false,
+            read_only: false,
+            checksum_metadata: None,
+        }
+    }
+}
+
+/// Returns the kind of `orig`, a path of the source, dereferencing it if it is a symlink and
+/// they are copied as what they point to.
+pub fn source_kind(options: &CopyOptions, orig: &Path) -> anyhow::Result<FileKind> {
+    if options.links == LinkPolicy::Copy {
+        let meta = std::fs::metadata(orig)
+            .with_context(|| format!("stat {} to determine file type", orig.display()))?;
+        Ok(FileKind::of_metadata(&meta))
+    } else {
+        FileKind::of_path(orig)
+    }
+}
+
+impl CopyOptions {
+    /// Returns the limit of the throughput of `fix_file`.
+    fn fix_throttle(&self) -> Option<&Throttle> {
+        self.verify_throttle.as_ref().or(self.throttle.as_ref())
+    }
+
+    /// Returns a hasher for `fix_file`, honoring `parallel_hashing`.
+    fn fix_hasher(&self) -> Hasher {
+        if self.parallel_hashing {
+            Hasher::threaded(self.checksum)
+        } else {
+            Hasher::new(self.checksum)
+        }
+    }
+
+    /// Positions `orig`, the source file, at the start of `source_range`, and returns how many
+    /// bytes of it to process at most, given the `limit` from `append_tolerant`.
+    fn seek_source(
+        &self,
+        orig: &mut File,
+        path: &Path,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Option<u64>> {
+        match self.source_range {
+            None => Ok(limit),
+            Some(range) => {
+                orig.seek(std::io::SeekFrom::Start(range.start))
+                    .with_context(|| format!("seeking to --source-range in {}", path.display()))?;
+                Ok(Some(limit.map_or(range.len, |l| l.min(range.len))))
+            }
+        }
+    }
+}
+
+/// Gives `target` the access and modification times in `meta`, the metadata of its source.
+/// Symlinks are not followed.
+fn copy_timestamps(meta: &std::fs::Metadata, target: &Path) -> anyhow::Result<()> {
+    set_timestamps(meta, target, UtimensatFlags::NoFollowSymlink)
+        .with_context(|| format!("setting the timestamps of {}", target.display()))
+}
+
+/// Gives `path` the access and modification times in `meta`.
+fn set_timestamps(meta: &std::fs::Metadata, path: &Path, flags: UtimensatFlags) -> nix::Result<()> {
+    let time = |sec: i64, nsec: i64| TimeSpec::nanoseconds(sec * 1_000_000_000 + nsec);
+    utimensat(
+        None,
+        path,
+        &time(meta.atime(), meta.atime_nsec()),
+        &time(meta.mtime(), meta.mtime_nsec()),
+        flags,
+    )
+}
+
+/// Opens the source file `path` for reading. With `options.preserve_atime`, it is opened with
+/// O_NOATIME so that reading it does not change its access time. Only its owner or root may do
+/// so: otherwise, the returned guard restores its access time when dropped, once it is read.
+fn open_source<'a>(
+    options: &CopyOptions,
+    progress: &'a Progress,
+    path: &'a Path,
+) -> anyhow::Result<(File, RestoreAtime<'a>)> {
+    let mut guard = RestoreAtime {
+        meta: None,
+        path,
+        progress,
+    };
+    if options.preserve_atime {
+        match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => (),
+            res => return Ok((res?, guard)),
+        }
+    }
+    let f = File::open(path)?;
+    if options.preserve_atime {
+        guard.meta = Some(f.metadata()?);
+    }
+    Ok((f, guard))
+}
+
+/// Gives back to `path`, when dropped, the access time in `meta`, its metadata before it was
+/// read. This happens on error paths too.
+struct RestoreAtime<'a> {
+    meta: Option<std::fs::Metadata>,
+    path: &'a Path,
+    progress: &'a Progress,
+}
+
+impl Drop for RestoreAtime<'_> {
+    fn drop(&mut self) {
+        if let Some(meta) = self.meta.as_ref() {
+            // whoever may not use O_NOATIME is usually not allowed to set timestamps either, so
+            // this is not worth failing the copy
+            if let Err(e) = set_timestamps(meta, self.path, UtimensatFlags::FollowSymlink) {
+                self.progress.warn(format!(
+                    "Failed to restore the access time of {}: {}",
+                    self.path.display(),
+                    e
+                ));
+            }
+        }
+    }
+}
+
+/// Gives `target` the owner and group in `meta`, the metadata of its source, if it does not
+/// have them already. Symlinks are not followed. Returns whether `target` was changed.
+fn copy_owner(meta: &std::fs::Metadata, target: &Path) -> anyhow::Result<bool> {
+    let before = std::fs::symlink_metadata(target)
+        .with_context(|| format!("stat({}) to check its owner", target.display()))?;
+    if (before.uid(), before.gid()) == (meta.uid(), meta.gid()) {
+        return Ok(false);
+    }
+    fchownat(
+        None,
+        target,
+        Some(Uid::from_raw(meta.uid())),
+        Some(Gid::from_raw(meta.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("changing the owner of {}", target.display()))?;
+    let mode = before.mode() & 0o7777;
+    if mode & 0o6000 != 0 && FileKind::of_metadata(&before) != FileKind::Symlink {
+        // chown clears the setuid and setgid bits
+        std::fs::set_permissions(target, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("chmod({}) after changing its owner", target.display()))?;
+    }
+    Ok(true)
+}
+
+/// Gives `target` the extended attributes of `orig`, following symlinks for `orig` if `follow`,
+/// and removes the other ones. The attributes stored by cccp itself are left alone.
+/// Returns whether `target` was changed.
+fn copy_xattrs(orig: &Path, follow: bool, target: &Path) -> anyhow::Result<bool> {
+    let ours = |name: &CString| name.as_bytes().starts_with(XATTR_PREFIX.as_bytes());
+    let mut changed = false;
+    let orig_names: BTreeSet<CString> = list_xattrs(orig, follow)?
+        .into_iter()
+        .filter(|name| !ours(name))
+        .collect();
+    for name in list_xattrs(target, false)? {
+        if !ours(&name) && !orig_names.contains(&name) {
+            lremove_xattr(target, &name)?;
+            changed = true;
+        }
+    }
+    for name in &orig_names {
+        let value = match read_xattr(orig, name, follow)? {
+            Some(value) => value,
+            // removed in the meantime
+            None => continue,
+        };
+        if read_xattr(target, name, false)?.as_ref() != Some(&value) {
+            lset_xattr(target, name, &value)?;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Gives `target`, a symlink or directory, the owner and extended attributes of `orig` which
+/// `options` preserve. Symlinks are followed for `orig` if `follow`.
+/// Returns whether `target` was changed.
+fn copy_attributes(
+    options: &CopyOptions,
+    orig: &Path,
+    follow: bool,
+    target: &Path,
+) -> anyhow::Result<bool> {
+    let mut changed = false;
+    if options.preserve_owner {
+        let meta = if follow {
+            std::fs::metadata(orig)
+        } else {
+            std::fs::symlink_metadata(orig)
+        }
+        .with_context(|| format!("stat({}) to copy its owner", orig.display()))?;
+        changed |= copy_owner(&meta, target)?;
+    }
+    if options.preserve_xattrs {
+        changed |= copy_xattrs(orig, follow, target)?;
+    }
+    Ok(changed)
+}
 
 /// Tells the system that this file descriptor will be read sequentially from offset 0 to end of
 /// file. The modified file descriptor is returned.
@@ -41,15 +502,160 @@ fn fadvise_sequential(f: File) -> anyhow::Result<File> {
     Ok(res)
 }
 
-/// Copies a file to another and computes the checksum of the original file
+/// How many bytes to read into `buffer` at `offset` so as not to read past `limit`.
+fn read_len(buffer: &[u8], offset: u64, limit: Option<u64>) -> usize {
+    match limit {
+        Some(limit) => std::cmp::min(buffer.len() as u64, limit - offset) as usize,
+        None => buffer.len(),
+    }
+}
+
+/// Moves `written`, the copy to `target` of a regular file which `copy_file` failed to finish,
+/// to `<target>.partial` to salvage what was written, unless it was written at an offset in a
+/// larger file or to a block device. Failures to do so are only warned about, as the copy
+/// already failed.
+fn keep_partial(progress: &Progress, options: &CopyOptions, written: &Path, target: &Path) {
+    if options.dest_offset != 0 || !matches!(FileKind::of_path(written), Ok(FileKind::Regular)) {
+        return;
+    }
+    let mut partial = target.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let res = std::fs::rename(written, &partial).and_then(|()| std::fs::metadata(&partial));
+    match res {
+        Ok(meta) => progress.warn(format!(
+            "kept the {} bytes written to {} before the failure in {}, none of them verified",
+            meta.len(),
+            target.display(),
+            partial.display()
+        )),
+        Err(e) => progress.warn(format!(
+            "could not keep the partial copy {} as {}: {}",
+            target.display(),
+            partial.display(),
+            e
+        )),
+    }
+}
+
+/// Number of temporary files created by `temporary_name`, to give them distinct names.
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the temporary name under which `copy_file` writes a new copy `target` of a regular
+/// file, in the same directory so that it is on the same file system. Block devices and copies
+/// at an offset in a larger file are written in place, and have no temporary name.
+fn temporary_name(options: &CopyOptions, target: &Path) -> Option<PathBuf> {
+    if options.dest_offset != 0 {
+        return None;
+    }
+    match std::fs::symlink_metadata(target) {
+        Ok(meta) if meta.file_type().is_file() => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        _ => return None,
+    }
+    let n = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+    Some(target.with_file_name(format!(".cccp-tmp-{}-{}", std::process::id(), n)))
+}
+
+/// Copies a file to another and computes the checksum of the original file, up to `limit` bytes.
+/// The copy is written under a temporary name and renamed to `target` once complete, so that an
+/// interrupted copy is never mistaken for a complete one.
 fn copy_file(
-    cache_manager: &dyn CacheManager,
+    cache_manager: &mut dyn CacheManager,
     progress: &Progress,
+    options: &CopyOptions,
     file: &Path,
     target: &Path,
+    limit: Option<u64>,
 ) -> anyhow::Result<Checksum> {
-    let mut crc = Crc64Hasher::default();
-    let orig_fd = File::open(file)
+    log::info!("copying {} to {}", file.display(), target.display());
+    let temporary = temporary_name(options, target);
+    let written = temporary.as_deref().unwrap_or(target);
+    let res = write_file(cache_manager, progress, options, file, written, limit).and_then(|crc| {
+        if let Some(temporary) = temporary.as_ref() {
+            std::fs::rename(temporary, target).with_context(|| {
+                format!(
+                    "renaming {} to {} once written",
+                    temporary.display(),
+                    target.display()
+                )
+            })?;
+        }
+        Ok(crc)
+    });
+    match res {
+        Ok(crc) => {
+            log::debug!("copied {} with checksum {}", target.display(), crc);
+            cache_manager.note_written(target);
+            Ok(crc)
+        }
+        Err(e) => {
+            if options.keep_partial {
+                keep_partial(progress, options, written, target);
+            } else if let Some(temporary) = temporary.as_ref() {
+                // it may not have been created
+                let _ = std::fs::remove_file(temporary);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Reads into `buffer` from `file` at `path`, which is at offset `pos`, like `Read::read`, for
+/// the reason `what`. A failed read is retried `options.retry_block` times, seeking back to `pos`
+/// first, and the final error tells the offset, to locate bad sectors.
+fn read_retrying(
+    progress: &Progress,
+    options: &CopyOptions,
+    file: &mut File,
+    path: &Path,
+    pos: u64,
+    buffer: &mut [u8],
+    what: &str,
+) -> anyhow::Result<usize> {
+    let mut failures = 0;
+    loop {
+        match file.read(buffer) {
+            Ok(n) => return Ok(n),
+            Err(e) if failures < options.retry_block => {
+                failures += 1;
+                progress.warn(format!(
+                    "Reading from {} {} at offset {:#x} failed, retrying ({}/{}): {}",
+                    path.display(),
+                    what,
+                    pos,
+                    failures,
+                    options.retry_block,
+                    e
+                ));
+                file.seek(std::io::SeekFrom::Start(pos))
+                    .with_context(|| format!("seeking back in {} to read again", path.display()))?;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Reading from {} {} at offset {:#x}",
+                        path.display(),
+                        what,
+                        pos
+                    )
+                })
+            }
+        }
+    }
+}
+
+/// Writes the copy of `file` to `target` for `copy_file`.
+fn write_file(
+    cache_manager: &mut dyn CacheManager,
+    progress: &Progress,
+    options: &CopyOptions,
+    file: &Path,
+    target: &Path,
+    limit: Option<u64>,
+) -> anyhow::Result<Checksum> {
+    let mut crc = Hasher::new(options.checksum);
+    let (orig_fd, _atime) = open_source(options, progress, file)
         .with_context(|| format!("Failed to open {} for copy input", file.display()))?;
     let mut orig_fd = fadvise_sequential(orig_fd)
         .with_context(|| format!("posix_fadvise({}, SEQUENTIAL)", file.display()))?;
@@ -67,45 +673,484 @@ fn copy_file(
             target,
         )
         .with_context(|| format!("Failed to open {} for copy output", target.display()))?;
-    let mut buffer = aligned_buffer!();
+    if options.chmod.is_some() || options.checksum_metadata.is_some() {
+        // the mode passed to open is only used for new files, and reduced by the umask
+        if FileKind::of_file(&target_fd)? == FileKind::Regular {
+            target_fd
+                .set_permissions(std::fs::Permissions::from_mode(copy_mode(options, &meta)))
+                .with_context(|| format!("chmod({})", target.display()))?;
+        }
+    }
+    let limit = options.seek_source(&mut orig_fd, file, limit)?;
+    target_fd
+        .seek(std::io::SeekFrom::Start(options.dest_offset))
+        .with_context(|| format!("seeking to --dest-offset in {}", target.display()))?;
+    let mut buffer = AlignedBuffer::new(options.block_size);
+    let mut copied = 0u64;
+    // offset in the source of what will be in the copy at offset 0
+    let start = options.source_range.map_or(0, |range| range.start);
+    // with --sparse, holes of the source are not written to a new copy, which thus keeps them.
+    // Block devices may report their unmapped or discarded regions as holes.
+    let mut skip_holes = options.sparse
+        && (meta.file_type().is_file() || meta.file_type().is_block_device())
+        && FileKind::of_file(&target_fd)? == FileKind::Regular
+        && target_fd
+            .metadata()
+            .with_context(|| format!("stat({}) to find if it is new", target.display()))?
+            .len()
+            <= options.dest_offset;
+    let source_len = if skip_holes && meta.file_type().is_block_device() {
+        block_device_size(&orig_fd)
+            .with_context(|| format!("getting the size of {}", file.display()))?
+    } else {
+        meta.len()
+    };
+    // offset in the source of the end of the current region of data, with `skip_holes`
+    let mut data_end = None;
     loop {
-        let n_read = orig_fd
-            .read(&mut buffer)
-            .with_context(|| format!("Reading from {} for copy input", file.display()))?;
+        if skip_holes && data_end.map_or(true, |end| start + copied >= end) {
+            let pos = start + copied;
+            match next_data_region(&orig_fd, pos, source_len)
+                .with_context(|| format!("finding holes in {}", file.display()))?
+            {
+                None => {
+                    // the file system cannot tell, copy everything
+                    skip_holes = false;
+                    data_end = None;
+                }
+                Some((data, hole)) => {
+                    let hole_len = match limit {
+                        Some(limit) => (data - pos).min(limit - copied),
+                        None => data - pos,
+                    };
+                    // holes read as zeros
+                    let zeros = [0u8; 4096];
+                    let mut hashed = 0;
+                    while hashed < hole_len {
+                        let n = (hole_len - hashed).min(zeros.len() as u64);
+                        crc.update(&zeros[..n as usize]);
+                        hashed += n;
+                    }
+                    copied += hole_len;
+                    progress.do_bytes(hole_len);
+                    data_end = Some(hole);
+                    orig_fd
+                        .seek(std::io::SeekFrom::Start(start + copied))
+                        .with_context(|| format!("seeking past a hole in {}", file.display()))?;
+                    target_fd
+                        .seek(std::io::SeekFrom::Start(options.dest_offset + copied))
+                        .with_context(|| format!("seeking past a hole in {}", target.display()))?;
+                }
+            }
+        }
+        let mut len = read_len(&buffer, copied, limit);
+        if let Some(end) = data_end {
+            len = len.min((end - start - copied) as usize);
+        }
+        if len == 0 {
+            if skip_holes {
+                // the source ends with a hole, which was not written
+                let end = options.dest_offset + copied;
+                let current = target_fd
+                    .metadata()
+                    .with_context(|| format!("stat({}) to extend it", target.display()))?
+                    .len();
+                if current < end {
+                    target_fd
+                        .set_len(end)
+                        .with_context(|| format!("Extending {}", target.display()))?;
+                }
+            }
+            break;
+        }
+        let n_read = read_retrying(
+            progress,
+            options,
+            &mut orig_fd,
+            file,
+            start + copied,
+            &mut buffer[..len],
+            "for copy input",
+        )?;
         if n_read == 0 {
             break;
         };
         let data = &buffer[..n_read];
         crc.update(data);
-        target_fd
-            .write_all(data)
-            .with_context(|| format!("writing to {} for copy output", target.display()))?;
+        target_fd.write_all(data).with_context(|| {
+            format!(
+                "writing to {} for copy output at offset {:#x}",
+                target.display(),
+                options.dest_offset + copied
+            )
+        })?;
+        copied += n_read as u64;
         progress.do_bytes(data.len() as u64);
+        if let Some(throttle) = options.throttle.as_ref() {
+            throttle.transferred(data.len() as u64);
+        }
+    }
+    if let Some(footer) = options.vhd_footer.as_ref() {
+        target_fd
+            .write_all(&footer.trailer(copied))
+            .with_context(|| format!("writing VHD footer to {}", target.display()))?;
+    }
+    if options.drop_source_cache {
+        drop_page_cache(&orig_fd)
+            .with_context(|| format!("dropping the page cache of {}", file.display()))?;
+    }
+    if FileKind::of_file(&target_fd)? == FileKind::Regular {
+        if options.preserve_owner {
+            copy_owner(&meta, target)?;
+        }
+        if options.preserve_xattrs {
+            copy_xattrs(file, true, target)?;
+        }
+        if options.preserve_timestamps {
+            copy_timestamps(&meta, target)?;
+        }
+    }
+    if let Some(fields) = options.checksum_metadata {
+        crc.update(metadata_bytes(fields, &meta, copy_mode(options, &meta)));
+    }
+    Ok(crc.finish())
+}
+
+/// Returns the checksum of at most `limit` bytes of the source file `file`, as `copy_file` would
+/// compute it, without copying it. It is read the same way, limited to `throttle`.
+pub fn source_checksum(
+    progress: &Progress,
+    options: &CopyOptions,
+    throttle: Option<&Throttle>,
+    file: &Path,
+    limit: Option<u64>,
+) -> anyhow::Result<Checksum> {
+    let mut crc = Hasher::new(options.checksum);
+    let (orig_fd, _atime) = open_source(options, progress, file)
+        .with_context(|| format!("Failed to open {} for checksum", file.display()))?;
+    let mut orig_fd = fadvise_sequential(orig_fd)
+        .with_context(|| format!("posix_fadvise({}, SEQUENTIAL)", file.display()))?;
+    let limit = options.seek_source(&mut orig_fd, file, limit)?;
+    let start = options.source_range.map_or(0, |range| range.start);
+    let mut buffer = AlignedBuffer::new(options.block_size);
+    let mut read = 0u64;
+    loop {
+        let len = read_len(&buffer, read, limit);
+        if len == 0 {
+            break;
+        }
+        let n_read = read_retrying(
+            progress,
+            options,
+            &mut orig_fd,
+            file,
+            start + read,
+            &mut buffer[..len],
+            "for checksum",
+        )?;
+        if n_read == 0 {
+            break;
+        }
+        crc.update(&buffer[..n_read]);
+        read += n_read as u64;
+        if let Some(throttle) = throttle {
+            throttle.transferred(n_read as u64);
+        }
+    }
+    if options.drop_source_cache {
+        drop_page_cache(&orig_fd)
+            .with_context(|| format!("dropping the page cache of {}", file.display()))?;
+    }
+    if let Some(fields) = options.checksum_metadata {
+        let meta = orig_fd
+            .metadata()
+            .with_context(|| format!("stat({}) for checksum", file.display()))?;
+        crc.update(metadata_bytes(fields, &meta, copy_mode(options, &meta)));
+    }
+    Ok(crc.finish())
+}
+
+/// Writes a file of random bytes in `dir`, drops the cache, and checks that the file still has
+/// the same content, to detect media which silently discard writes before copying anything.
+/// Returns how paths changed, if dropping the cache changed them.
+pub fn check_writes_persist(
+    cache_manager: &mut dyn CacheManager,
+    progress: &Progress,
+    dir: &Path,
+) -> anyhow::Result<Option<Replacement>> {
+    let mut expected = AlignedBuffer::new(DEFAULT_BLOCK_SIZE);
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut expected))
+        .context("reading /dev/urandom for the write check marker")?;
+    let path = dir.join(format!(".cccp-write-check-{}", std::process::id()));
+    let mut f = cache_manager
+        .open_no_cache(
+            std::fs::OpenOptions::new().write(true).create_new(true),
+            0,
+            &path,
+        )
+        .with_context(|| format!("creating write check marker {}", path.display()))?;
+    f.write_all(&expected)
+        .and_then(|()| f.sync_all())
+        .with_context(|| format!("writing write check marker {}", path.display()))?;
+    drop(f);
+    let replacement = cache_manager
+        .drop_cache(progress, &path)
+        .with_context(|| format!("Dropping cache of {}", path.display()))?;
+    let path = match replacement.as_ref() {
+        Some(r) => change_prefixes(&r.before, &r.after)(&path),
+        None => path,
+    };
+    let mut actual = AlignedBuffer::new(DEFAULT_BLOCK_SIZE);
+    let res = cache_manager
+        .open_no_cache(OpenOptions::new().read(true), libc::O_NOFOLLOW, &path)
+        .and_then(|mut f| f.read_exact(&mut actual))
+        .with_context(|| format!("reading write check marker {}", path.display()));
+    std::fs::remove_file(&path)
+        .with_context(|| format!("removing write check marker {}", path.display()))?;
+    res?;
+    anyhow::ensure!(
+        actual[..] == expected[..],
+        "destination file system of {} is not persisting writes: a marker file read back differently after dropping the cache",
+        dir.display()
+    );
+    Ok(replacement)
+}
+
+/// Writes a file of `size` pseudo random bytes in `dir`, drops the cache, reads it back and
+/// removes it, showing progress with `progress`, which the caller must call `done` on. Returns
+/// how paths changed, if dropping the cache changed them, and how many of the blocks of
+/// `block_size` bytes of the file were written and read back wrong.
+pub fn probe_reliability(
+    cache_manager: &mut dyn CacheManager,
+    progress: &mut Progress,
+    dir: &Path,
+    size: u64,
+    block_size: usize,
+) -> anyhow::Result<(Option<Replacement>, u64, u64)> {
+    let mut seed = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut seed))
+        .context("reading /dev/urandom for the seed of the reliability probe")?;
+    let seed = u64::from_le_bytes(seed);
+    // the same blocks are generated again to check them
+    let fill = |rng: &mut SplitMix64, buffer: &mut [u8]| {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = rng.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    };
+    let blocks = (size + block_size as u64 - 1) / block_size as u64;
+    let path = dir.join(format!(".cccp-reliability-probe-{}", std::process::id()));
+    let mut buffer = AlignedBuffer::new(block_size);
+    let mut f = cache_manager
+        .open_no_cache(
+            std::fs::OpenOptions::new().write(true).create_new(true),
+            0,
+            &path,
+        )
+        .with_context(|| format!("creating reliability probe {}", path.display()))?;
+    let mut rng = SplitMix64::new(seed);
+    progress.next_round(blocks * block_size as u64);
+    progress.set_status(format!("Writing {}", path.display()));
+    let written = (0..blocks)
+        .try_for_each(|_| {
+            fill(&mut rng, &mut buffer);
+            progress.do_bytes(block_size as u64);
+            f.write_all(&buffer)
+        })
+        .and_then(|()| f.sync_all())
+        .with_context(|| format!("writing reliability probe {}", path.display()));
+    drop(f);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    progress.syncing();
+    let replacement = match cache_manager
+        .drop_cache(progress, &path)
+        .with_context(|| format!("Dropping cache of {}", path.display()))
+    {
+        Ok(replacement) => replacement,
+        Err(e) => {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+    };
+    let path = match replacement.as_ref() {
+        Some(r) => change_prefixes(&r.before, &r.after)(&path),
+        None => path,
+    };
+    progress.next_round(blocks * block_size as u64);
+    progress.set_status(format!("Reading {}", path.display()));
+    let mut actual = AlignedBuffer::new(block_size);
+    let mut rng = SplitMix64::new(seed);
+    let mut bad = 0;
+    let res = cache_manager
+        .open_no_cache(OpenOptions::new().read(true), libc::O_NOFOLLOW, &path)
+        .and_then(|mut f| {
+            (0..blocks).try_for_each(|_| {
+                fill(&mut rng, &mut buffer);
+                f.read_exact(&mut actual)?;
+                progress.do_bytes(block_size as u64);
+                if actual[..] != buffer[..] {
+                    bad += 1;
+                }
+                Ok(())
+            })
+        })
+        .with_context(|| format!("reading reliability probe {}", path.display()));
+    std::fs::remove_file(&path)
+        .with_context(|| format!("removing reliability probe {}", path.display()))?;
+    res?;
+    Ok((replacement, blocks, bad))
+}
+
+/// How many bytes of each file `--checksum-on-mismatch-dump` saves at most per round.
+const MAX_DUMP_PER_FILE: u64 = 1 << 20;
+
+/// Saves `expected` and `actual`, the content of the source and of its copy `target` at `offset`,
+/// to files in `dir` named after `target` and `offset`.
+fn dump_mismatch(
+    dir: &Path,
+    target: &Path,
+    offset: u64,
+    expected: &[u8],
+    actual: &[u8],
+) -> anyhow::Result<()> {
+    let name: Vec<u8> = target
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .map(|&b| if b == b'/' { b'_' } else { b })
+        .collect();
+    for &(extension, content) in &[("expected", expected), ("actual", actual)] {
+        let mut path = dir.join(OsStr::from_bytes(&name)).into_os_string();
+        path.push(format!("@{}.{}", offset, extension));
+        std::fs::write(&path, content)
+            .with_context(|| format!("writing {}", Path::new(&path).display()))?;
+    }
+    Ok(())
+}
+
+/// Makes the bytes of `target` at `offset` equal to `trailer`, using `buffer` to read them.
+/// `target` must be at `offset` and is left after the trailer. Returns if `target` was modified.
+fn fix_trailer(
+    target: &mut File,
+    offset: u64,
+    trailer: &[u8],
+    buffer: &mut [u8],
+) -> std::io::Result<bool> {
+    let actual = &mut buffer[..trailer.len()];
+    let mut n_actual = 0;
+    while n_actual < trailer.len() {
+        let n_read = target.read(&mut actual[n_actual..])?;
+        if n_read == 0 {
+            break;
+        }
+        n_actual += n_read;
+    }
+    if &actual[..n_actual] == trailer {
+        return Ok(false);
+    }
+    target.seek(std::io::SeekFrom::Start(offset))?;
+    target.write_all(trailer)?;
+    Ok(true)
+}
+
+/// Bytes of a copy which differed from the source, found by `fix_file`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mismatch {
+    /// Offset in the copy of the first differing byte.
+    pub offset: u64,
+    /// How many bytes differed.
+    pub bytes: u64,
+    /// Checksum of the differing blocks read from the copy, and of their offsets.
+    pub checksum: Checksum,
+}
+
+/// What `fix_path` found and did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixReport {
+    /// Whether some fixing was needed.
+    pub changed: bool,
+    /// For files, the bytes of the copy which differed from the source.
+    pub mismatch: Option<Mismatch>,
+}
+
+impl From<bool> for FixReport {
+    fn from(changed: bool) -> FixReport {
+        FixReport {
+            changed,
+            mismatch: None,
+        }
     }
-    Ok(crc.into())
 }
 
-/// fixes a copy of a file, and checks that the checksum is correct. Returns if the copy was
-/// modified.
+/// fixes a copy of a file, and checks that the checksum is correct. Reports if the copy was
+/// modified, and which bytes differed.
 fn fix_file(
-    cache_manager: &dyn CacheManager,
+    cache_manager: &mut dyn CacheManager,
     progress: &Progress,
+    options: &CopyOptions,
     orig: &Path,
     target: &Path,
     checksum: &mut Option<Checksum>,
-) -> anyhow::Result<bool> {
+    limit: &mut Option<u64>,
+) -> anyhow::Result<FixReport> {
     let mut changed = false;
-    let mut crc = Crc64Hasher::default();
-    let mut target_fd = match cache_manager.open_no_cache(
-        std::fs::OpenOptions::new().read(true).write(true),
-        libc::O_NOFOLLOW,
-        target,
-    ) {
+    // offset in the copy of the first differing byte, number of differing bytes, and checksum of
+    // the differing blocks
+    let mut first_mismatch = None;
+    let mut diverged = 0u64;
+    let mut mismatch_crc = Hasher::new(ChecksumAlgorithm::Crc64);
+    let mut crc = options.fix_hasher();
+    // checksum of the whole source, in case it grew past `limit`
+    let mut full_crc = limit.map(|_| Hasher::new(options.checksum));
+    // checksum of what we read from the target, to compare verdicts with byte comparison
+    let mut target_crc = if options.double_read {
+        Some(options.fix_hasher())
+    } else {
+        None
+    };
+    let mut same_length = true;
+    // bytes saved by `dump_mismatch` for this file
+    let mut dumped = 0u64;
+    let mut target_fd = match cache_manager
+        .open_no_cache(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(!options.read_only),
+            libc::O_NOFOLLOW,
+            target,
+        )
+        .and_then(|fd| {
+            // a directory can be opened read only, but it is in the way all the same
+            if fd.metadata()?.is_dir() {
+                Err(Errno::EISDIR.into())
+            } else {
+                Ok(fd)
+            }
+        }) {
         Ok(x) => x,
         Err(e) => match e.raw_os_error().map(Errno::from_i32) {
+            Some(Errno::EISDIR) | Some(Errno::ELOOP) if options.read_only => {
+                progress.warn(format!("{} is not a regular file", target.display()));
+                // the caller still expects the checksum of the source
+                if checksum.is_none() {
+                    *checksum = Some(source_checksum(
+                        progress,
+                        options,
+                        options.fix_throttle(),
+                        orig,
+                        *limit,
+                    )?);
+                }
+                return Ok(true.into());
+            }
             Some(Errno::EISDIR) | Some(Errno::ELOOP) => {
                 // remove the target and copy it anew
-                remove_path(progress, &target).with_context(|| {
+                remove_in_the_way(progress, options, target).with_context(|| {
                     format!(
                         "removing copy target {} of file {} because it is not a file",
                         target.display(),
@@ -113,87 +1158,405 @@ fn fix_file(
                     )
                 })?;
                 let new_checksum =
-                    copy_file(cache_manager, progress, orig, target).with_context(|| {
-                        format!(
-                            "making a fresh copy of file {} to {}",
-                            orig.display(),
-                            target.display(),
-                        )
-                    })?;
+                    copy_file(cache_manager, progress, options, orig, target, *limit)
+                        .with_context(|| {
+                            format!(
+                                "making a fresh copy of file {} to {}",
+                                orig.display(),
+                                target.display(),
+                            )
+                        })?;
 
-                fill_checksum(checksum, new_checksum)
+                fill_checksum(options.checksum, checksum, new_checksum)
                     .with_context(|| format!("Bad checksum for file {}", orig.display()))?;
-                return Ok(true);
+                return Ok(true.into());
             }
             _ => {
                 Err(e).with_context(|| format!("Failed to open {} for fixing", target.display()))?
             }
         },
     };
-    let orig_fd = File::open(orig)
+    let (orig_fd, _atime) = open_source(options, progress, orig)
         .with_context(|| format!("Failed to open {} as fix input", orig.display()))?;
     let mut orig_fd = fadvise_sequential(orig_fd)
         .with_context(|| format!("posix_fadvise({}, SEQUENTIAL)", orig.display()))?;
-    let mut reference = aligned_buffer!();
-    let mut actual = aligned_buffer!();
+    let read_limit = options.seek_source(&mut orig_fd, orig, *limit)?;
+    target_fd
+        .seek(std::io::SeekFrom::Start(options.dest_offset))
+        .with_context(|| format!("seeking to --dest-offset in {}", target.display()))?;
+    let mut reference = AlignedBuffer::new(options.block_size);
+    let mut actual = AlignedBuffer::new(options.block_size);
     let mut offset = 0u64;
+    // offset in the source of what is in the copy at `dest_offset`
+    let start = options.source_range.map_or(0, |range| range.start);
+    // offset in the copy up to which `readahead` was requested
+    let mut advised = 0u64;
     loop {
-        // invariant: both fd are at offset `offset` and identical up to there.
+        // invariant: orig_fd is at offset `offset` from the start of `source_range` and target_fd
+        // at `offset + dest_offset`, and
+        // they are identical up to there.
         let mut append = false;
-        let n_orig = orig_fd
-            .read(&mut reference)
-            .with_context(|| format!("Reading from {} for comparing", orig.display()))?;
+        let len = read_len(&reference, offset, read_limit);
+        let n_orig = if len == 0 {
+            0
+        } else {
+            read_retrying(
+                progress,
+                options,
+                &mut orig_fd,
+                orig,
+                start + offset,
+                &mut reference[..len],
+                "for comparing",
+            )?
+        };
         if n_orig == 0 {
+            if let Some(footer) = options.vhd_footer.as_ref() {
+                // the footer is generated, not copied, so it is not part of the checksum
+                let trailer = footer.trailer(offset);
+                if fix_trailer(&mut target_fd, offset, &trailer, &mut actual)
+                    .with_context(|| format!("fixing the VHD footer of {}", target.display()))?
+                {
+                    changed = true;
+                }
+                offset += trailer.len() as u64;
+            }
             let is_block_device = FileKind::of_file(&target_fd)? == FileKind::Device;
-            if !is_block_device {
+            // when the copy is written at an offset, what follows it is not ours
+            if !is_block_device && options.dest_offset == 0 {
                 let n_read = target_fd
                     .read(&mut actual[..1])
                     .with_context(|| format!("Reading from {} for comparing", target.display()))?;
                 if n_read != 0 {
                     // target file is longer
-                    target_fd
-                        .set_len(offset)
-                        .with_context(|| format!("Truncating {}", target.display()))?;
+                    if !options.read_only {
+                        ensure_may_truncate(progress, options, target, offset)?;
+                        target_fd
+                            .set_len(offset)
+                            .with_context(|| format!("Truncating {}", target.display()))?;
+                    }
+                    first_mismatch.get_or_insert(offset);
                     changed = true;
+                    same_length = false;
+                } else if options.sparse {
+                    // regions full of zeros were skipped, so the target may be too short
+                    let len = target_fd
+                        .metadata()
+                        .with_context(|| format!("stat({}) to check its length", target.display()))?
+                        .len();
+                    if len < offset {
+                        if !options.read_only {
+                            target_fd
+                                .set_len(offset)
+                                .with_context(|| format!("Extending {}", target.display()))?;
+                        }
+                        first_mismatch.get_or_insert(len);
+                        changed = true;
+                        same_length = false;
+                    }
                 }
             }
             break;
         }
+        let data = &reference[..n_orig];
+        // zeros of the source need not be reread in the copy if it has a hole there, which reads
+        // as zeros, but the copy may hold stale data where a previous copy was not sparse
+        let in_hole = options.sparse && data.iter().all(|&b| b == 0) && {
+            let pos = offset + options.dest_offset;
+            let end = pos + n_orig as u64;
+            let region = next_data_region(&target_fd, pos, end)
+                .with_context(|| format!("finding holes in {}", target.display()))?;
+            target_fd
+                .seek(std::io::SeekFrom::Start(pos))
+                .with_context(|| format!("seeking back in {} after lseek", target.display()))?;
+            matches!(region, Some((data_start, _)) if data_start >= end)
+        };
+        if in_hole {
+            crc.update(data);
+            if let Some(full_crc) = full_crc.as_mut() {
+                full_crc.update(data);
+            }
+            if let Some(target_crc) = target_crc.as_mut() {
+                target_crc.update(data);
+            }
+            offset += n_orig as u64;
+            target_fd
+                .seek(std::io::SeekFrom::Start(offset + options.dest_offset))
+                .with_context(|| format!("seeking in {} past zeros", target.display()))?;
+            progress.do_bytes(n_orig as u64);
+            if let Some(throttle) = options.fix_throttle() {
+                throttle.transferred(n_orig as u64);
+            }
+            continue;
+        }
+        if let Some(window) = options.readahead {
+            let pos = offset + options.dest_offset;
+            // a huge --readahead must not overflow
+            let end = pos.saturating_add(window);
+            // request the next window when half of the previous one was compared, so that the
+            // device always has something to read while we compare
+            if advised < pos.saturating_add(window / 2) {
+                let from = advised.max(pos);
+                nix::fcntl::posix_fadvise(
+                    target_fd.as_raw_fd(),
+                    from as libc::off_t,
+                    (end - from).min(libc::off_t::MAX as u64) as libc::off_t,
+                    nix::fcntl::PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+                )
+                .with_context(|| format!("posix_fadvise({}, WILLNEED)", target.display()))?;
+                advised = end;
+            }
+        }
+        for _ in 1..options.verify_reads {
+            read_retrying(
+                progress,
+                options,
+                &mut target_fd,
+                target,
+                offset + options.dest_offset,
+                &mut actual[..n_orig],
+                "to discard",
+            )?;
+            target_fd
+                .seek(std::io::SeekFrom::Start(offset + options.dest_offset))
+                .with_context(|| format!("seeking back in {} to read again", target.display()))?;
+        }
         let mut n_actual = 0;
         while n_actual < n_orig {
-            let n_read = target_fd
-                .read(&mut actual[n_actual..n_orig])
-                .with_context(|| format!("Reading from {} for comparing", target.display()))?;
+            let n_read = read_retrying(
+                progress,
+                options,
+                &mut target_fd,
+                target,
+                offset + options.dest_offset + n_actual as u64,
+                &mut actual[n_actual..n_orig],
+                "for comparing",
+            )?;
             n_actual += n_read;
             if n_read == 0 {
                 // orig file is longer
                 append = true;
+                same_length = false;
                 break;
             };
         }
-        let data = &reference[..n_orig];
+        if let Some(target_crc) = target_crc.as_mut() {
+            target_crc.update(&actual[..n_actual]);
+        }
         crc.update(data);
+        if let Some(full_crc) = full_crc.as_mut() {
+            full_crc.update(data);
+        }
         if append || data != &actual[..n_orig] {
-            if !changed {
+            if !changed && !options.read_only {
                 progress.set_status(format!("Fixing {}", target.display()));
             }
             changed = true;
-            target_fd
-                .seek(std::io::SeekFrom::Start(offset))
-                .with_context(|| format!("seeking in {} for fixing output", target.display()))?;
-            target_fd
-                .write_all(data)
-                .with_context(|| format!("writing to {} for fixing output", target.display()))?;
+            let first_difference = data
+                .iter()
+                .zip(&actual[..n_actual])
+                .position(|(a, b)| a != b)
+                .unwrap_or(n_actual);
+            first_mismatch.get_or_insert(offset + options.dest_offset + first_difference as u64);
+            diverged += (n_orig - n_actual) as u64
+                + data
+                    .iter()
+                    .zip(&actual[..n_actual])
+                    .filter(|(a, b)| a != b)
+                    .count() as u64;
+            mismatch_crc.update(offset.to_be_bytes());
+            mismatch_crc.update(&actual[..n_actual]);
+            if let Some(dir) = options.mismatch_dump.as_ref() {
+                if dumped < MAX_DUMP_PER_FILE {
+                    // offset in the copy, as in mismatch reports
+                    let block_offset = offset + options.dest_offset;
+                    dump_mismatch(dir, target, block_offset, data, &actual[..n_actual])
+                        .with_context(|| {
+                            format!("saving differing bytes of {}", target.display())
+                        })?;
+                    dumped += n_orig as u64;
+                    progress.warn(format!(
+                        "{} differs from {} in the block at offset {}, saved both versions to {}",
+                        target.display(),
+                        orig.display(),
+                        block_offset,
+                        dir.display()
+                    ));
+                }
+            }
+            if options.read_only {
+                // the next comparison must start at the same offset in both
+                target_fd
+                    .seek(std::io::SeekFrom::Start(
+                        offset + n_orig as u64 + options.dest_offset,
+                    ))
+                    .with_context(|| format!("seeking in {} for comparing", target.display()))?;
+            } else {
+                target_fd
+                    .seek(std::io::SeekFrom::Start(offset + options.dest_offset))
+                    .with_context(|| {
+                        format!("seeking in {} for fixing output", target.display())
+                    })?;
+                target_fd.write_all(data).with_context(|| {
+                    format!(
+                        "writing to {} for fixing output at offset {:#x}",
+                        target.display(),
+                        offset + options.dest_offset
+                    )
+                })?;
+            }
         }
         offset += n_orig as u64;
         progress.do_bytes(n_orig as u64);
+        if let Some(throttle) = options.fix_throttle() {
+            throttle.transferred(n_orig as u64);
+        }
+    }
+    if let Some(fields) = options.checksum_metadata {
+        let orig_meta = orig_fd
+            .metadata()
+            .with_context(|| format!("stat({}) for its checksum", orig.display()))?;
+        let bytes = metadata_bytes(fields, &orig_meta, copy_mode(options, &orig_meta));
+        crc.update(&bytes);
+        if let Some(target_crc) = target_crc.as_mut() {
+            // only the content is compared
+            target_crc.update(&bytes);
+        }
+    }
+    let orig_checksum = crc.finish();
+    if let Some(target_crc) = target_crc {
+        let target_checksum = target_crc.finish();
+        // when lengths differ, we did not read the end of the target, so its checksum is partial
+        if same_length && changed && target_checksum == orig_checksum {
+            progress.warn(format!(
+                "{} differed from {} but had the same checksum: the checksum cannot detect this corruption",
+                target.display(),
+                orig.display()
+            ));
+        } else if same_length && !changed && target_checksum != orig_checksum {
+            progress.warn(format!(
+                "{} is identical to {} but had a different checksum: reads are not reproducible",
+                target.display(),
+                orig.display()
+            ));
+        }
     }
-    fill_checksum(checksum, crc.into())
+    fill_checksum(options.checksum, checksum, orig_checksum)
         .with_context(|| format!("Bad checksum for file {}", orig.display()))?;
-    Ok(changed)
+    if let (Some(len), Some(mut full_crc)) = (*limit, full_crc) {
+        // copy what was appended to the source since it was checksummed. It is checked next
+        // round.
+        target_fd
+            .seek(std::io::SeekFrom::Start(len + options.dest_offset))
+            .with_context(|| format!("seeking to the end of {}", target.display()))?;
+        let mut appended = 0u64;
+        loop {
+            let n_read = orig_fd
+                .read(&mut reference)
+                .with_context(|| format!("Reading from {} for appending", orig.display()))?;
+            if n_read == 0 {
+                break;
+            }
+            let data = &reference[..n_read];
+            full_crc.update(data);
+            target_fd
+                .write_all(data)
+                .with_context(|| format!("appending to {}", target.display()))?;
+            appended += n_read as u64;
+        }
+        if appended != 0 {
+            changed = true;
+            *limit = Some(len + appended);
+            *checksum = Some(full_crc.finish());
+        }
+    }
+    if options.drop_source_cache {
+        drop_page_cache(&orig_fd)
+            .with_context(|| format!("dropping the page cache of {}", orig.display()))?;
+    }
+    let preserve = options.preserve_owner
+        || options.preserve_xattrs
+        || options.preserve_timestamps
+        || options.checksum_metadata.is_some();
+    if preserve && !options.read_only && FileKind::of_file(&target_fd)? == FileKind::Regular {
+        let orig_meta = orig_fd
+            .metadata()
+            .with_context(|| format!("stat({}) to copy its attributes", orig.display()))?;
+        if options.checksum_metadata.is_some() {
+            let mode = copy_mode(options, &orig_meta);
+            let target_mode = target_fd
+                .metadata()
+                .with_context(|| format!("stat({}) to check its permissions", target.display()))?
+                .mode();
+            if target_mode & 0o7777 != mode {
+                if !changed {
+                    progress.set_status(format!("Fixing the permissions of {}", target.display()));
+                }
+                target_fd
+                    .set_permissions(std::fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("chmod({})", target.display()))?;
+                changed = true;
+            }
+        }
+        if options.preserve_owner && copy_owner(&orig_meta, target)? {
+            if !changed {
+                progress.set_status(format!("Fixing the owner of {}", target.display()));
+            }
+            changed = true;
+        }
+        if options.preserve_xattrs && copy_xattrs(orig, true, target)? {
+            if !changed {
+                progress.set_status(format!(
+                    "Fixing the extended attributes of {}",
+                    target.display()
+                ));
+            }
+            changed = true;
+        }
+        if options.preserve_timestamps {
+            let target_meta = target_fd
+                .metadata()
+                .with_context(|| format!("stat({}) to check its timestamps", target.display()))?;
+            // reading the copy may update its access time, so only the modification time counts
+            let same_mtime = (orig_meta.mtime(), orig_meta.mtime_nsec())
+                == (target_meta.mtime(), target_meta.mtime_nsec());
+            if changed || !same_mtime {
+                if !changed {
+                    progress.set_status(format!("Fixing timestamps of {}", target.display()));
+                }
+                copy_timestamps(&orig_meta, target)?;
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        // it is read again in the next round, even if only its attributes were fixed
+        cache_manager.note_written(target);
+    }
+    let mismatch = first_mismatch.map(|offset| Mismatch {
+        offset,
+        bytes: diverged,
+        checksum: mismatch_crc.finish(),
+    });
+    match mismatch.as_ref() {
+        Some(m) => log::debug!(
+            "{}: {} bytes differed from offset {}, checksum of the source {}",
+            target.display(),
+            m.bytes,
+            m.offset,
+            orig_checksum
+        ),
+        None => log::debug!(
+            "{}: content matches, checksum {}",
+            target.display(),
+            orig_checksum
+        ),
+    }
+    Ok(FixReport { changed, mismatch })
 }
 
-fn copy_symlink(orig: &Path, target: &Path) -> anyhow::Result<Checksum> {
+fn copy_symlink(options: &CopyOptions, orig: &Path, target: &Path) -> anyhow::Result<Checksum> {
+    let algorithm = options.checksum;
     match std::fs::remove_file(target) {
         Ok(()) => (),
         Err(e) => match e.kind() {
@@ -203,77 +1566,270 @@ fn copy_symlink(orig: &Path, target: &Path) -> anyhow::Result<Checksum> {
     }
     let content = std::fs::read_link(orig)
         .with_context(|| format!("reading symlink {} for copy", orig.display()))?;
-    let mut hasher = Crc64Hasher::default();
+    let checksum = symlink_target_checksum(algorithm, &content);
+    std::os::unix::fs::symlink(content.as_os_str(), target)
+        .map_err(|e| no_space_error(e, target))
+        .with_context(|| {
+            format!(
+                "creating a symlink from {} to {}",
+                orig.display(),
+                target.display()
+            )
+        })?;
+    copy_attributes(options, orig, false, target)?;
+    if options.preserve_timestamps {
+        let meta = std::fs::symlink_metadata(orig)
+            .with_context(|| format!("stat({}) to copy its timestamps", orig.display()))?;
+        copy_timestamps(&meta, target)?;
+    }
+    Ok(checksum)
+}
+
+/// Returns the checksum of a symlink pointing to `content`. It is computed on the raw bytes of
+/// the target, which need not be utf8, and does not depend on whether the target exists.
+fn symlink_target_checksum(algorithm: ChecksumAlgorithm, content: &Path) -> Checksum {
+    let mut hasher = Hasher::new(algorithm);
     hasher.update(content.as_os_str().as_bytes());
-    std::os::unix::fs::symlink(content.as_os_str(), target).with_context(|| {
-        format!(
-            "creating a symlink from {} to {}",
-            orig.display(),
-            target.display()
-        )
-    })?;
-    Ok(hasher.into())
+    hasher.finish()
 }
 
-fn symlink_checksum(path: &Path) -> anyhow::Result<Checksum> {
+fn symlink_checksum(algorithm: ChecksumAlgorithm, path: &Path) -> anyhow::Result<Checksum> {
     let content = std::fs::read_link(path)
         .with_context(|| format!("computing checksum of symlink {}", path.display()))?;
-    let mut hasher = Crc64Hasher::default();
-    hasher.update(content.as_os_str().as_bytes());
-    Ok(hasher.into())
+    Ok(symlink_target_checksum(algorithm, &content))
 }
 
-fn create_directory(target: &Path) -> anyhow::Result<()> {
+/// Creates the directory `target`, if it does not exist yet, with the permissions of
+/// `options.chmod` if set.
+pub fn create_directory(options: &CopyOptions, target: &Path) -> anyhow::Result<()> {
     match std::fs::create_dir(target) {
-        Ok(()) => Ok(()),
+        Ok(()) => (),
         Err(e) => match e.kind() {
-            ErrorKind::AlreadyExists => Ok(()),
-            _ => Err(e).with_context(|| format!("creating directory {}", target.display())),
+            ErrorKind::AlreadyExists => return Ok(()),
+            _ => Err(no_space_error(e, target))
+                .with_context(|| format!("creating directory {}", target.display()))?,
         },
     }
+    if let Some(spec) = options.chmod.as_ref() {
+        let mode = std::fs::metadata(target)
+            .with_context(|| format!("stat({}) for --chmod", target.display()))?
+            .mode();
+        std::fs::set_permissions(
+            target,
+            std::fs::Permissions::from_mode(spec.apply(mode, true)),
+        )
+        .with_context(|| format!("chmod({}) for --chmod", target.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the kind of the directory entry `entry`, as copied: with `follow_links`, symlinks
+/// count as what they point to, like with `LinkPolicy::Copy`.
+fn entry_kind(entry: &std::fs::DirEntry, follow_links: bool) -> anyhow::Result<FileKind> {
+    let kind = FileKind::of_file_type(
+        entry
+            .file_type()
+            .with_context(|| format!("stat({}) for checksum", entry.path().display()))?,
+    );
+    if follow_links && kind == FileKind::Symlink {
+        let meta = std::fs::metadata(entry.path())
+            .with_context(|| format!("stat({}) for checksum", entry.path().display()))?;
+        Ok(FileKind::of_metadata(&meta))
+    } else {
+        Ok(kind)
+    }
+}
+
+/// Adds the entry `name` of a directory, of kind `kind`, to the checksum `res` of the
+/// directory. The kind is included so that an entry which changed kind changes the checksum.
+fn add_entry_checksum(
+    res: &mut Checksum,
+    algorithm: ChecksumAlgorithm,
+    name: &OsStr,
+    kind: FileKind,
+) {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(name.as_bytes());
+    // a file name cannot contain a null byte
+    hasher.update([0]);
+    hasher.update([match kind {
+        FileKind::Regular => b'f',
+        FileKind::Directory => b'd',
+        FileKind::Symlink => b'l',
+        FileKind::Device => b'b',
+        FileKind::Fifo => b'p',
+        FileKind::CharDevice => b'c',
+        FileKind::Socket => b's',
+        FileKind::Other => b'?',
+    }]);
+    *res ^= hasher.finish();
 }
 
-fn directory_checksum(path: &Path) -> anyhow::Result<Checksum> {
+/// Returns the checksum of the listing of the directory `path`, with `follow_links` as for
+/// `entry_kind`.
+fn directory_checksum(
+    algorithm: ChecksumAlgorithm,
+    follow_links: bool,
+    path: &Path,
+) -> anyhow::Result<Checksum> {
     // the checksum must not depend on iteration order, so we xor the checksum of all entries
-    let hasher = Crc64Hasher::default();
-    let mut res = hasher.into();
+    let mut res = Hasher::new(algorithm).finish();
 
     for entry in std::fs::read_dir(path)
         .with_context(|| format!("computing checksum of {}", path.display()))?
     {
         let entry = entry?;
-        let mut hasher = Crc64Hasher::default();
-        hasher.update(entry.file_name().as_bytes());
-        res ^= hasher.into();
+        add_entry_checksum(
+            &mut res,
+            algorithm,
+            &entry.file_name(),
+            entry_kind(&entry, follow_links)?,
+        );
     }
 
     Ok(res)
 }
 
+/// Returns whether the entry `path` of an existing copy, which is not in the source or is in the
+/// way of the copy, may be removed according to `options.delete`.
+fn may_remove(progress: &Progress, options: &CopyOptions, path: &Path) -> anyhow::Result<bool> {
+    match options.delete {
+        DeletePolicy::Always => Ok(true),
+        DeletePolicy::Never => {
+            progress.info(format!(
+                "Keeping {}, which is not in the source",
+                path.display()
+            ));
+            Ok(false)
+        }
+        DeletePolicy::Ask => {
+            let yes = ask(
+                progress,
+                path,
+                &format!("Remove {}, which is not in the source?", path.display()),
+            )?;
+            if !yes {
+                progress.info(format!("Keeping {}", path.display()));
+            }
+            Ok(yes)
+        }
+    }
+}
+
+/// Returns an error unless `options.delete` allows truncating `path`, a copy longer than its
+/// source, to `len` bytes.
+fn ensure_may_truncate(
+    progress: &Progress,
+    options: &CopyOptions,
+    path: &Path,
+    len: u64,
+) -> anyhow::Result<()> {
+    let allowed = match options.delete {
+        DeletePolicy::Always => true,
+        DeletePolicy::Never => false,
+        DeletePolicy::Ask => ask(
+            progress,
+            path,
+            &format!(
+                "Remove the end of {} past {} bytes, which is not in the source?",
+                path.display(),
+                len
+            ),
+        )?,
+    };
+    anyhow::ensure!(
+        allowed,
+        "{} is longer than its source and its end was kept, as asked by --delete={}",
+        path.display(),
+        options.delete.to_string().to_lowercase()
+    );
+    Ok(())
+}
+
+/// Asks `question` about `path` on the terminal, and returns whether the answer is yes.
+fn ask(progress: &Progress, path: &Path, question: &str) -> anyhow::Result<bool> {
+    progress.set_status(format!("Asking whether to remove {}", path.display()));
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("reading whether to remove it")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Removes `path`, which is in the way of the copy, if `options.delete` allows it. Otherwise,
+/// the copy cannot be made.
+fn remove_in_the_way(
+    progress: &Progress,
+    options: &CopyOptions,
+    path: &Path,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        may_remove(progress, options, path)?,
+        "{} is in the way of the copy and was kept, as asked by --delete={}",
+        path.display(),
+        options.delete.to_string().to_lowercase()
+    );
+    remove_path(progress, path)
+}
+
+/// Removes `path`, recursively if it is a directory. Symlinks are removed, not followed.
+/// Succeeds if `path` vanished in the meantime, as it may be modified concurrently.
 fn remove_path(progress: &Progress, path: &Path) -> anyhow::Result<()> {
+    log::info!("removing {}", path.display());
     progress.set_status(format!("Removing {}", path.display()));
-    match FileKind::of_path(path)
-        .with_context(|| format!("stat({}) for removal", path.display()))?
-    {
+    let meta = match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        res => res.with_context(|| format!("stat({}) for removal", path.display()))?,
+    };
+    match FileKind::of_metadata(&meta) {
         FileKind::Directory => std::fs::remove_dir_all(path),
         _ => std::fs::remove_file(path),
     }
+    .or_else(|e| match e.kind() {
+        ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })
     .with_context(|| format!("removing {}", path.display()))?;
     Ok(())
 }
 
+/// Makes `target` a hard link to `link_source`, the copy of another name of the same source
+/// file. Returns `true` if `target` had to be (re)created, `false` if it already was such a link.
+pub fn link_path(progress: &Progress, link_source: &Path, target: &Path) -> anyhow::Result<bool> {
+    let source_meta = std::fs::metadata(link_source)
+        .with_context(|| format!("stat({}) to hard link to it", link_source.display()))?;
+    match std::fs::symlink_metadata(target) {
+        Ok(meta) if meta.dev() == source_meta.dev() && meta.ino() == source_meta.ino() => {
+            return Ok(false)
+        }
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("stat({}) to check hard link", target.display()))
+        }
+        Ok(_) => remove_path(progress, target)?,
+        Err(_) => (),
+    }
+    log::info!("linking {} to {}", target.display(), link_source.display());
+    progress.set_status(format!("Linking {}", target.display()));
+    std::fs::hard_link(link_source, target).with_context(|| {
+        format!(
+            "creating hard link {} to {}",
+            target.display(),
+            link_source.display()
+        )
+    })?;
+    Ok(true)
+}
+
 fn fix_directory(
     progress: &Progress,
+    options: &CopyOptions,
     orig: &Path,
     target: &Path,
     checksum: &mut Option<Checksum>,
 ) -> anyhow::Result<bool> {
     // the checksum must not depend on iteration order, so we xor the checksum of all entries
-    let hasher = Crc64Hasher::default();
-    let mut res: Checksum = hasher.into();
-
-    let mut orig_names = HashSet::new();
-    let mut target_names = HashSet::new();
+    let mut res = Hasher::new(options.checksum).finish();
 
     // unfortunately, read_dir follows symlinks, so we have to stat() before
     let raw_it_target = match FileKind::of_path(target).with_context(|| {
@@ -286,19 +1842,27 @@ fn fix_directory(
         _ => Err(Errno::ENOTDIR.into()),
     };
 
-    let mut it_target = match raw_it_target {
+    let it_target = match raw_it_target {
         Ok(x) => x,
         Err(e) => match e.raw_os_error().map(Errno::from_i32) {
+            Some(Errno::ENOTDIR) if options.read_only => {
+                progress.warn(format!("{} is not a directory", target.display()));
+                let new_checksum =
+                    directory_checksum(options.checksum, options.links == LinkPolicy::Copy, orig)?;
+                fill_checksum(options.checksum, checksum, new_checksum)
+                    .with_context(|| format!("Bad checksum for directory {}", orig.display()))?;
+                return Ok(true);
+            }
             Some(Errno::ENOTDIR) => {
                 // the target is not a directory, let's remove it and copy again
-                remove_path(progress, &target).with_context(|| {
+                remove_in_the_way(progress, options, target).with_context(|| {
                     format!(
                         "removing copy target {} of directory {} because it is not a directory",
                         target.display(),
                         orig.display()
                     )
                 })?;
-                let new_checksum = copy_directory(&orig, &target).with_context(|| {
+                let new_checksum = copy_directory(options, &orig, &target).with_context(|| {
                     format!(
                         "making a fresh copy of directory {} to {}",
                         orig.display(),
@@ -306,7 +1870,7 @@ fn fix_directory(
                     )
                 })?;
                 // check the checksum
-                fill_checksum(checksum, new_checksum)
+                fill_checksum(options.checksum, checksum, new_checksum)
                     .with_context(|| format!("Bad checksum for directory {}", orig.display()))?;
                 return Ok(true);
             }
@@ -318,61 +1882,82 @@ fn fix_directory(
     let it_orig = std::fs::read_dir(orig)
         .with_context(|| format!("reading directory for comparison {}", orig.display()))?;
 
+    // read_dir returns entries in no particular order, and not the same one for the source and
+    // its copy, so the listings are compared as sets
+    let mut orig_names = BTreeSet::new();
     for entry in it_orig {
         let entry = entry?;
-        let mut hasher = Crc64Hasher::default();
         let name = entry.file_name();
-        let bytes = name.as_bytes();
-        hasher.update(bytes);
-        res ^= hasher.into();
-        match it_target.next() {
-            Some(Err(e)) => return Err(e.into()),
-            None => {
-                orig_names.insert(name.to_owned());
-            }
-            Some(Ok(entry2)) => {
-                let name2 = entry2.file_name();
-                if name2 != name {
-                    target_names.insert(name2.to_owned());
-                    orig_names.insert(name.to_owned());
-                }
-            }
-        }
+        add_entry_checksum(
+            &mut res,
+            options.checksum,
+            &name,
+            entry_kind(&entry, options.links == LinkPolicy::Copy)?,
+        );
+        orig_names.insert(name);
     }
 
     // check the checksum
-    fill_checksum(checksum, res)
+    fill_checksum(options.checksum, checksum, res)
         .with_context(|| format!("Bad checksum for directory {}", orig.display()))?;
 
-    // consume remaining dentries
+    let mut target_names = BTreeSet::new();
     for entry2 in it_target {
         let entry2 = entry2?;
-        target_names.insert(entry2.file_name().to_owned());
+        target_names.insert(entry2.file_name());
     }
 
-    // files to be removed
-    let extra = target_names.difference(&orig_names);
+    // files to be removed, except the copies of excluded entries, which are not ours
+    let extra = target_names.difference(&orig_names).filter(|name| {
+        !options
+            .exclude
+            .as_ref()
+            .map_or(false, |exclude| exclude.matches(&orig.join(name)))
+    });
+    if options.read_only {
+        let mut changed = false;
+        for name in extra {
+            changed = true;
+            progress.warn(format!(
+                "{} is not in {}",
+                target.join(name).display(),
+                orig.display()
+            ));
+        }
+        return Ok(changed);
+    }
     let mut path = target.to_path_buf();
     let mut changed = false;
     for name in extra {
-        changed = true;
         path.push(name);
-        remove_path(progress, &path)
-            .with_context(|| format!("removing extra directory member {}", path.display()))?;
+        if may_remove(progress, options, &path)? {
+            changed = true;
+            remove_path(progress, &path)
+                .with_context(|| format!("removing extra directory member {}", path.display()))?;
+        }
         path.pop();
     }
 
+    changed |= copy_attributes(options, orig, true, target)?;
+
     Ok(changed)
 }
 
-fn file_checksum(cache_manager: &mut dyn CacheManager, path: &Path) -> anyhow::Result<Checksum> {
-    let mut hasher = Crc64Hasher::default();
+fn file_checksum(
+    cache_manager: &mut dyn CacheManager,
+    algorithm: ChecksumAlgorithm,
+    block_size: usize,
+    throttle: Option<&Throttle>,
+    metadata: Option<MetadataFields>,
+    path: &Path,
+) -> anyhow::Result<Checksum> {
+    let mut hasher = Hasher::new(algorithm);
     let fd = cache_manager
         .open_no_cache(OpenOptions::new().read(true), libc::O_NOFOLLOW, path)
         .with_context(|| format!("opening {} for checksum", path.display()))?;
     let mut fd = fadvise_sequential(fd)
         .with_context(|| format!("posix_fadvise({}, SEQUENTIAL)", path.display()))?;
-    let mut buffer = aligned_buffer!();
+    let mut buffer = AlignedBuffer::new(block_size);
     loop {
         let n_read = fd
             .read(&mut buffer)
@@ -381,67 +1966,232 @@ fn file_checksum(cache_manager: &mut dyn CacheManager, path: &Path) -> anyhow::R
             break;
         }
         hasher.update(&buffer[..n_read]);
+        if let Some(throttle) = throttle {
+            throttle.transferred(n_read as u64);
+        }
+    }
+    if let Some(fields) = metadata {
+        let meta = fd
+            .metadata()
+            .with_context(|| format!("stat({}) for checksum", path.display()))?;
+        hasher.update(metadata_bytes(fields, &meta, meta.mode()));
     }
-    Ok(hasher.into())
+    Ok(hasher.finish())
 }
 
 fn fix_symlink(
     progress: &Progress,
+    options: &CopyOptions,
     orig: &Path,
     target: &Path,
     checksum: &mut Option<Checksum>,
 ) -> anyhow::Result<bool> {
-    let c1 = symlink_checksum(orig)?;
-    fill_checksum(checksum, c1)
-        .with_context(|| format!("fixing the copy of {}", orig.display()))?;
+    let content = std::fs::read_link(orig)
+        .with_context(|| format!("reading symlink {} for fixing", orig.display()))?;
+    fill_checksum(
+        options.checksum,
+        checksum,
+        symlink_target_checksum(options.checksum, &content),
+    )
+    .with_context(|| format!("fixing the copy of {}", orig.display()))?;
 
-    let c2 = match symlink_checksum(target) {
+    // compare the content itself, as there may be no checksum
+    let content2 = match std::fs::read_link(target) {
         Ok(c2) => Some(c2),
-        Err(e) => {
-            match e.downcast::<std::io::Error>() {
-                Ok(io) => {
-                    match io.raw_os_error().map(Errno::from_i32) {
-                        Some(Errno::EINVAL) => {
-                            // target is not a symbolic link
-                            remove_path(progress, target).with_context(|| format!("removing copy target {} of symlink {} because it is not a symlink", target.display(), orig.display()))?;
-                            None
-                        }
-                        _ => return Err(io.into()),
-                    }
+        Err(io) => {
+            match io.raw_os_error().map(Errno::from_i32) {
+                Some(Errno::EINVAL) if options.read_only => {
+                    progress.warn(format!("{} is not a symlink", target.display()));
+                    return Ok(true);
+                }
+                Some(Errno::EINVAL) => {
+                    // target is not a symbolic link
+                    remove_in_the_way(progress, options, target).with_context(|| {
+                        format!(
+                            "removing copy target {} of symlink {} because it is not a symlink",
+                            target.display(),
+                            orig.display()
+                        )
+                    })?;
+                    None
+                }
+                _ => {
+                    return Err(io).with_context(|| {
+                        format!("reading symlink {} for fixing", target.display())
+                    })
                 }
-                Err(e) => return Err(e),
             }
         }
     };
-    if c2 != Some(c1) {
+    if options.read_only {
+        if content2.as_ref() != Some(&content) {
+            progress.warn(format!(
+                "{} points to {:?} instead of {:?}",
+                target.display(),
+                content2,
+                content
+            ));
+            return Ok(true);
+        }
+        Ok(false)
+    } else if content2.as_ref() != Some(&content) {
         // needs fixing
         progress.set_status(format!("Fixing {}", target.display()));
-        copy_symlink(orig, target)
+        copy_symlink(options, orig, target)
             .with_context(|| format!("copy symlink {} to fix", orig.display()))?;
         Ok(true)
     } else {
-        Ok(false)
+        copy_attributes(options, orig, false, target)
     }
 }
 
-pub fn copy_directory(orig: &Path, target: &Path) -> anyhow::Result<Checksum> {
-    create_directory(target)?;
-    directory_checksum(orig)
+/// Returns the checksum of a named pipe, character device or socket with metadata `meta`. It only
+/// depends on its kind and device number.
+fn node_checksum(algorithm: ChecksumAlgorithm, meta: &std::fs::Metadata) -> Checksum {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(match FileKind::of_metadata(meta) {
+        FileKind::Fifo => "fifo",
+        FileKind::CharDevice => "chr",
+        FileKind::Socket => "sock",
+        _ => "other",
+    });
+    hasher.update(meta.rdev().to_be_bytes());
+    hasher.finish()
 }
 
-/// Copies a file or directory or symlink `orig` to `target` and returns `orig`'s checksum
+/// Recreates the named pipe, character device or socket `orig` as `target` with mknod, and
+/// returns its checksum. Character devices can only be created by root.
+fn copy_node(options: &CopyOptions, orig: &Path, target: &Path) -> anyhow::Result<Checksum> {
+    let meta = std::fs::symlink_metadata(orig)
+        .with_context(|| format!("stat({}) to copy it", orig.display()))?;
+    let kind = match FileKind::of_metadata(&meta) {
+        FileKind::Fifo => SFlag::S_IFIFO,
+        FileKind::CharDevice => {
+            anyhow::ensure!(
+                geteuid().is_root(),
+                "cannot recreate character device {} as {} without root privileges",
+                orig.display(),
+                target.display()
+            );
+            SFlag::S_IFCHR
+        }
+        FileKind::Socket => SFlag::S_IFSOCK,
+        kind => anyhow::bail!("cannot mknod a copy of {:?} {}", kind, orig.display()),
+    };
+    let mode = meta.mode() & 0o7777;
+    mknod(
+        target,
+        kind,
+        Mode::from_bits_truncate(mode),
+        meta.rdev() as libc::dev_t,
+    )
+    .with_context(|| format!("mknod({}) to copy {}", target.display(), orig.display()))?;
+    if let Some(spec) = options.chmod.as_ref() {
+        std::fs::set_permissions(
+            target,
+            std::fs::Permissions::from_mode(spec.apply(mode, false)),
+        )
+        .with_context(|| format!("chmod({}) for --chmod", target.display()))?;
+    }
+    // user extended attributes are not allowed on special files
+    if options.preserve_owner {
+        copy_owner(&meta, target)?;
+    }
+    if options.preserve_timestamps {
+        copy_timestamps(&meta, target)?;
+    }
+    Ok(node_checksum(options.checksum, &meta))
+}
+
+/// Fixes the copy `target` of the named pipe, character device or socket `orig`, like
+/// `fix_symlink`.
+fn fix_node(
+    progress: &Progress,
+    options: &CopyOptions,
+    orig: &Path,
+    target: &Path,
+    checksum: &mut Option<Checksum>,
+) -> anyhow::Result<bool> {
+    let meta = std::fs::symlink_metadata(orig)
+        .with_context(|| format!("stat({}) for fixing", orig.display()))?;
+    fill_checksum(
+        options.checksum,
+        checksum,
+        node_checksum(options.checksum, &meta),
+    )
+    .with_context(|| format!("fixing the copy of {}", orig.display()))?;
+    let same = match std::fs::symlink_metadata(target) {
+        Ok(copy) => {
+            FileKind::of_metadata(&copy) == FileKind::of_metadata(&meta)
+                && copy.rdev() == meta.rdev()
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => return Err(e).with_context(|| format!("stat({}) for fixing", target.display())),
+    };
+    if options.read_only {
+        if !same {
+            progress.warn(format!(
+                "{} is not the same kind of file or device as {}",
+                target.display(),
+                orig.display()
+            ));
+        }
+        return Ok(!same);
+    }
+    if same {
+        return if options.preserve_owner {
+            copy_owner(&meta, target)
+        } else {
+            Ok(false)
+        };
+    }
+    progress.set_status(format!("Fixing {}", target.display()));
+    remove_in_the_way(progress, options, target)?;
+    copy_node(options, orig, target).with_context(|| format!("copy {} to fix", orig.display()))?;
+    Ok(true)
+}
+
+pub fn copy_directory(
+    options: &CopyOptions,
+    orig: &Path,
+    target: &Path,
+) -> anyhow::Result<Checksum> {
+    create_directory(options, target)?;
+    copy_attributes(options, orig, true, target)?;
+    directory_checksum(options.checksum, options.links == LinkPolicy::Copy, orig)
+}
+
+/// Copies a file or directory or symlink or special file `orig` to `target` and returns `orig`'s
+/// checksum.
+/// Only the first `limit` bytes of files are copied, if set.
 pub fn copy_path(
-    cache_manager: &dyn CacheManager,
+    cache_manager: &mut dyn CacheManager,
     progress: &Progress,
+    options: &CopyOptions,
     orig: &Path,
     target: &Path,
+    limit: Option<u64>,
 ) -> anyhow::Result<Checksum> {
-    match FileKind::of_path(orig).with_context(|| format!("stat({}) to copy", orig.display()))? {
-        FileKind::Regular | FileKind::Device => copy_file(cache_manager, progress, orig, target),
-        FileKind::Directory => copy_directory(orig, target),
+    if options.read_only {
+        progress.info(format!(
+            "Would copy {} to {}",
+            orig.display(),
+            target.display()
+        ));
+        // nothing was read
+        return Ok(Hasher::new(options.checksum).finish());
+    }
+    match source_kind(options, orig).with_context(|| format!("stat({}) to copy", orig.display()))? {
+        FileKind::Regular | FileKind::Device => {
+            copy_file(cache_manager, progress, options, orig, target, limit)
+        }
+        FileKind::Directory => copy_directory(options, orig, target),
         FileKind::Symlink => {
-            copy_symlink(orig, target)?;
-            symlink_checksum(orig)
+            copy_symlink(options, orig, target)?;
+            symlink_checksum(options.checksum, orig)
+        }
+        FileKind::Fifo | FileKind::CharDevice | FileKind::Socket => {
+            copy_node(options, orig, target)
         }
         FileKind::Other => Err(anyhow!(
             "cannot copy unknown fs path type {}",
@@ -451,17 +2201,43 @@ pub fn copy_path(
 }
 
 /// Returns the checksum of a path, except a device file, because the length to checksum
-/// is not known in advance for device files.
-#[allow(unused)]
+/// is not known in advance for device files. Files are read `block_size` bytes at a time, at
+/// the rate allowed by `throttle`. With `follow_links`, symlinks count as what they point to,
+/// like with `LinkPolicy::Copy`.
 pub fn checksum_path(
     cache_manager: &mut dyn CacheManager,
+    algorithm: ChecksumAlgorithm,
+    block_size: usize,
+    throttle: Option<&Throttle>,
+    metadata: Option<MetadataFields>,
+    follow_links: bool,
     path: &Path,
 ) -> anyhow::Result<Checksum> {
+    let resolved;
+    let path = if follow_links {
+        resolved = std::fs::canonicalize(path)
+            .with_context(|| format!("following symlinks in {}", path.display()))?;
+        resolved.as_path()
+    } else {
+        path
+    };
     match FileKind::of_path(path).with_context(|| format!("stat({}) to copy", path.display()))? {
-        FileKind::Regular => file_checksum(cache_manager, path),
-        FileKind::Directory => directory_checksum(path),
-        FileKind::Symlink => symlink_checksum(path),
+        FileKind::Regular => file_checksum(
+            cache_manager,
+            algorithm,
+            block_size,
+            throttle,
+            metadata,
+            path,
+        ),
+        FileKind::Directory => directory_checksum(algorithm, follow_links, path),
+        FileKind::Symlink => symlink_checksum(algorithm, path),
         FileKind::Device => Err(anyhow!("cannot checksum device file {}", path.display())),
+        FileKind::Fifo | FileKind::CharDevice | FileKind::Socket => {
+            let meta = std::fs::symlink_metadata(path)
+                .with_context(|| format!("stat({}) for checksum", path.display()))?;
+            Ok(node_checksum(algorithm, &meta))
+        }
         FileKind::Other => Err(anyhow!(
             "cannot checksum unknown fs path type {}",
             path.display()
@@ -470,25 +2246,49 @@ pub fn checksum_path(
 }
 
 /// Fixes the copy `target` of `orig` which has checksum `checksum`.
-/// Returns `true` if some fixing was needed or `false` otherwise.
+/// Reports whether some fixing was needed, and for files which bytes differed.
 /// Returns an error if `orig` has changed since it has been checksummed
 /// Sets checksum to `Some` if it was `None`.
+/// If `limit` is set, only the first `limit` bytes of a file are covered by `checksum`. If the
+/// file grew past `limit`, the new bytes are copied, `limit` and `checksum` are updated to cover
+/// them, and fixing is reported as needed.
 pub fn fix_path(
-    cache_manager: &dyn CacheManager,
+    cache_manager: &mut dyn CacheManager,
     progress: &Progress,
+    options: &CopyOptions,
     orig: &Path,
     target: &Path,
     checksum: &mut Option<Checksum>,
-) -> anyhow::Result<bool> {
-    match FileKind::of_path(orig).with_context(|| format!("stat({}) to fix", orig.display()))? {
-        FileKind::Regular | FileKind::Device => {
-            fix_file(cache_manager, progress, orig, target, checksum)
+    limit: &mut Option<u64>,
+) -> anyhow::Result<FixReport> {
+    let report = match source_kind(options, orig)
+        .with_context(|| format!("stat({}) to fix", orig.display()))?
+    {
+        FileKind::Regular | FileKind::Device => fix_file(
+            cache_manager,
+            progress,
+            options,
+            orig,
+            target,
+            checksum,
+            limit,
+        ),
+        FileKind::Directory => {
+            fix_directory(progress, options, orig, target, checksum).map(FixReport::from)
+        }
+        FileKind::Symlink => {
+            fix_symlink(progress, options, orig, target, checksum).map(FixReport::from)
+        }
+        FileKind::Fifo | FileKind::CharDevice | FileKind::Socket => {
+            fix_node(progress, options, orig, target, checksum).map(FixReport::from)
         }
-        FileKind::Directory => fix_directory(progress, orig, target, checksum),
-        FileKind::Symlink => fix_symlink(progress, orig, target, checksum),
         FileKind::Other => Err(anyhow!(
             "cannot fix unknown fs path type {}",
             orig.display()
         )),
+    }?;
+    if report.changed && !options.read_only {
+        log::info!("fixed {} from {}", target.display(), orig.display());
     }
+    Ok(report)
 }
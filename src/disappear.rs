@@ -0,0 +1,171 @@
+//! What to do when the device holding the copy disappears during the copy, as chosen with
+//! `--on-disappear`.
+
+use crate::cache::{CacheManager, Replacement};
+use crate::progress::Progress;
+use crate::udev::{
+    get_udisk_blockdev_for, identify, underlying_device, wait_for_reappearance, Identifier,
+};
+use anyhow::Context;
+use clap::arg_enum;
+use dbus_udisks2::UDisks2;
+use nix::errno::Errno;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long `OnDisappear::Wait` waits for the device to come back.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(3600);
+
+const LONG_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// How many times in a row we recover without any success in between before giving up. EIO is
+/// also what failing media return, and retrying these forever would be pointless.
+const MAX_CONSECUTIVE_RECOVERIES: u32 = 10;
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum OnDisappear {
+        Fail,
+        Wait,
+        Remount,
+    }
+}
+
+/// Whether this error looks like the device it happened on disappeared.
+pub fn is_disappearance(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        let errno = match e.downcast_ref::<std::io::Error>() {
+            Some(io) => io.raw_os_error().map(Errno::from_i32),
+            None => match e.downcast_ref::<nix::Error>() {
+                Some(nix::Error::Sys(errno)) => Some(*errno),
+                _ => None,
+            },
+        };
+        matches!(
+            errno,
+            Some(Errno::EIO) | Some(Errno::ENODEV) | Some(Errno::ENXIO)
+        )
+    })
+}
+
+/// Recovers from the disappearance of the device holding the copy, according to an
+/// `OnDisappear` policy.
+pub struct DisappearHandler {
+    policy: OnDisappear,
+    /// For `OnDisappear::Wait`, how to find the device again.
+    device: Option<(UDisks2, Identifier)>,
+    /// Number of calls to `recover` since the last call to `succeeded`.
+    consecutive: u32,
+}
+
+/// Returns how to find the device holding `path` once it reappears.
+fn identify_path(udisks: &UDisks2, path: &Path) -> anyhow::Result<Identifier> {
+    let dev = underlying_device(path)?;
+    let block = get_udisk_blockdev_for(udisks, &dev)?;
+    identify(udisks, &dev, &block, path)
+}
+
+impl DisappearHandler {
+    /// `target` is the path of the copy. Its device must be present.
+    pub fn new(policy: OnDisappear, target: &Path) -> anyhow::Result<Self> {
+        let device = match policy {
+            OnDisappear::Wait => {
+                let udisks = UDisks2::new().context("Connecting to udisks dbus interface")?;
+                let id = identify_path(&udisks, target).with_context(|| {
+                    format!(
+                        "Identifying the device of {} to wait for it if it disappears",
+                        target.display()
+                    )
+                })?;
+                Some((udisks, id))
+            }
+            OnDisappear::Fail | OnDisappear::Remount => None,
+        };
+        Ok(DisappearHandler {
+            policy,
+            device,
+            consecutive: 0,
+        })
+    }
+
+    /// Notifies that an operation retried after `recover` succeeded.
+    pub fn succeeded(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// Called when copying or fixing below `target` failed with `error`. Returns `error` if the
+    /// copy must be aborted. Otherwise, waits for the device to be usable again, and returns how
+    /// the paths of the copy changed, if they did. The failed operation can then be retried.
+    pub fn recover(
+        &mut self,
+        error: anyhow::Error,
+        cache_manager: &mut dyn CacheManager,
+        progress: &Progress,
+        target: &Path,
+    ) -> anyhow::Result<Option<Replacement>> {
+        if self.policy == OnDisappear::Fail || !is_disappearance(&error) {
+            return Err(error);
+        }
+        if self.consecutive >= MAX_CONSECUTIVE_RECOVERIES {
+            return Err(error.context(format!(
+                "Giving up after recovering {} times in a row from the disappearance of the device",
+                self.consecutive
+            )));
+        }
+        self.consecutive += 1;
+        log::info!(
+            "recovering from {:#}, attempt {} of {}",
+            error,
+            self.consecutive,
+            MAX_CONSECUTIVE_RECOVERIES
+        );
+        progress.warn(format!(
+            "the device holding {} seems to have disappeared: {:#}",
+            target.display(),
+            error
+        ));
+        let new_target = match self.device.as_mut() {
+            None => None,
+            Some((udisks, id)) => {
+                progress.set_status(format!(
+                    "Waiting for the device holding {} to come back",
+                    target.display()
+                ));
+                let new_target =
+                    wait_for_reappearance(udisks, id, target, WAIT_TIMEOUT, LONG_TIMEOUT)
+                        .with_context(|| {
+                            format!(
+                                "waiting for the device holding {} to reappear",
+                                target.display()
+                            )
+                        })?;
+                let path = new_target.as_deref().unwrap_or(target);
+                // the mountpoint may have changed
+                *id = identify_path(udisks, path)
+                    .with_context(|| format!("Identifying the device of {}", path.display()))?;
+                log::info!("identified the device of {} again", path.display());
+                new_target
+            }
+        };
+        let path = new_target.as_deref().unwrap_or(target);
+        cache_manager.permission_check(path).with_context(|| {
+            format!(
+                "Checking permissions for cache management again after the device of {} reappeared",
+                path.display()
+            )
+        })?;
+        Ok(new_target.map(|after| Replacement {
+            before: target.to_path_buf(),
+            after,
+        }))
+    }
+}
+
+#[test]
+fn test_is_disappearance() {
+    let eio = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EIO)).context("writing");
+    assert!(is_disappearance(&eio));
+    let enoent =
+        anyhow::Error::new(std::io::Error::from_raw_os_error(libc::ENOENT)).context("writing");
+    assert!(!is_disappearance(&enoent));
+}
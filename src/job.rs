@@ -0,0 +1,1397 @@
+//! Driving rounds of copying and fixing until the copy is correct, as the `cccp` command does.
+
+use crate::cache::{CacheManager, Replacement};
+use crate::checksum::Checksum;
+use crate::copy::{self, CopyOptions, LinkPolicy};
+use crate::disappear::{DisappearHandler, OnDisappear};
+use crate::progress::{Progress, ProgressEvent};
+use crate::utils::{self, change_prefixes, FileKind};
+use crate::{manifest, xattr};
+use anyhow::Context;
+use clap::arg_enum;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+arg_enum! {
+    /// What to do with a file read back with the same wrong bytes in two rounds in a row.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum OnPersistentMismatch {
+        Refix,
+        Abort,
+        Quarantine,
+    }
+}
+
+/// Copies a file or directory and fixes the copy until it reads back identical to the source,
+/// dropping the cache with a `CacheManager` between rounds, like the `cccp` command.
+///
+/// ```no_run
+/// use cccp::cache::directio::DirectIOCacheManager;
+/// let rounds = cccp::CopyJob::new("/home/me/photos", "/mnt/usb/photos")
+///     .cache_manager(Box::new(DirectIOCacheManager::default()))
+///     .on_progress(|event| eprintln!("{}", event.to_json()))
+///     .run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct CopyJob {
+    /// The entries to copy, with the name of their copy in `dest` if they are copied into it.
+    sources: Vec<(PathBuf, Option<OsString>)>,
+    dest: PathBuf,
+    cache_manager: Option<Box<dyn CacheManager>>,
+    options: CopyOptions,
+    progress: Option<Progress>,
+    max_rounds: Option<u32>,
+    once: bool,
+    keep_going: bool,
+    reference: Option<PathBuf>,
+    resume: bool,
+    resume_state: Option<PathBuf>,
+    /// Percentage of bytes to check, and the seed of the random choice.
+    verify_sample: Option<(f64, Option<u64>)>,
+    post_verify_command: Option<String>,
+    /// Where to write the manifest, and the path of the copy in it.
+    manifest: Option<(PathBuf, PathBuf)>,
+    checksums_in_xattrs: bool,
+    tree_checksum_in_xattr: bool,
+    on_disappear: OnDisappear,
+    on_persistent_mismatch: OnPersistentMismatch,
+    show_remaining: bool,
+    summary: bool,
+    report_cache: bool,
+}
+
+impl CopyJob {
+    /// Prepares copying `source` to `dest`, which must not exist yet or be a previous copy of
+    /// `source` to fix. A cache manager must be set with `cache_manager` before `run`.
+    pub fn new(source: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> CopyJob {
+        CopyJob::with_sources(vec![(source.into(), None)], dest.into())
+    }
+
+    /// Prepares copying each of `sources` into the existing directory `dest`, under the name
+    /// it comes with.
+    pub fn into_directory(
+        sources: impl IntoIterator<Item = (PathBuf, OsString)>,
+        dest: impl Into<PathBuf>,
+    ) -> CopyJob {
+        let sources = sources
+            .into_iter()
+            .map(|(source, name)| (source, Some(name)))
+            .collect();
+        CopyJob::with_sources(sources, dest.into())
+    }
+
+    fn with_sources(sources: Vec<(PathBuf, Option<OsString>)>, dest: PathBuf) -> CopyJob {
+        CopyJob {
+            sources,
+            dest,
+            cache_manager: None,
+            options: CopyOptions::default(),
+            progress: None,
+            max_rounds: None,
+            once: false,
+            keep_going: false,
+            reference: None,
+            resume: false,
+            resume_state: None,
+            verify_sample: None,
+            post_verify_command: None,
+            manifest: None,
+            checksums_in_xattrs: false,
+            tree_checksum_in_xattr: false,
+            on_disappear: OnDisappear::Fail,
+            on_persistent_mismatch: OnPersistentMismatch::Refix,
+            show_remaining: false,
+            summary: true,
+            report_cache: false,
+        }
+    }
+
+    /// Sets how caches are bypassed.
+    pub fn cache_manager(mut self, cache_manager: Box<dyn CacheManager>) -> CopyJob {
+        self.cache_manager = Some(cache_manager);
+        self
+    }
+
+    /// Sets the tunables of copying and fixing. Defaults to `CopyOptions::default()`. With
+    /// `read_only`, nothing is written and `run` fails if something would be.
+    pub fn options(mut self, options: CopyOptions) -> CopyJob {
+        self.options = options;
+        self
+    }
+
+    /// Gives up if the copy is still not correct after this many rounds of fixing. Unlimited by
+    /// default.
+    pub fn max_rounds(mut self, max_rounds: u32) -> CopyJob {
+        self.max_rounds = Some(max_rounds);
+        self
+    }
+
+    /// With `once`, gives up if the first round of fixing was not enough.
+    pub fn once(mut self, once: bool) -> CopyJob {
+        self.once = once;
+        self
+    }
+
+    /// Passes the progress of the copy to `callback`. By default, only warnings are printed.
+    pub fn on_progress(self, callback: impl Fn(&ProgressEvent) + 'static) -> CopyJob {
+        self.progress(Progress::with_callback(callback))
+    }
+
+    /// Reports the progress of the copy with `progress`.
+    pub fn progress(mut self, progress: Progress) -> CopyJob {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// With `keep_going`, entries which cannot be copied are skipped and reported, and `run`
+    /// only fails at the end.
+    pub fn keep_going(mut self, keep_going: bool) -> CopyJob {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Fixes the copy by comparing it to `reference`, a copy of the single source made earlier,
+    /// instead of the source.
+    pub fn reference(mut self, reference: impl Into<PathBuf>) -> CopyJob {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    /// With `resume`, existing regular files of the copy with the size of their source and not
+    /// older than it are assumed to come from an interrupted copy: they are only checked, not
+    /// rewritten.
+    pub fn resume(mut self, resume: bool) -> CopyJob {
+        self.resume = resume;
+        self
+    }
+
+    /// Appends the regular files of the copy to the manifest `path` as they are verified, and
+    /// removes it once the copy is correct. With `resume`, the files it lists are trusted
+    /// without reading them.
+    pub fn resume_state(mut self, path: impl Into<PathBuf>) -> CopyJob {
+        self.resume_state = Some(path.into());
+        self
+    }
+
+    /// After the first copy, only checks a random subset of the entries totalling about
+    /// `percent` of their bytes, chosen from `seed` or randomly, and fails if any of them
+    /// differed instead of fixing the copy until it is correct.
+    pub fn verify_sample(mut self, percent: f64, seed: Option<u64>) -> CopyJob {
+        self.verify_sample = Some((percent, seed));
+        self
+    }
+
+    /// Runs the shell command `command` for each verified entry of the copy, with `{path}` and
+    /// `{checksum}` replaced by its path and checksum. `run` fails at the end if it failed.
+    pub fn post_verify_command(mut self, command: impl Into<String>) -> CopyJob {
+        self.post_verify_command = Some(command.into());
+        self
+    }
+
+    /// Once the copy is correct, writes the checksums of its entries to the manifest `path`,
+    /// below `prefix`.
+    pub fn write_manifest(
+        mut self,
+        path: impl Into<PathBuf>,
+        prefix: impl Into<PathBuf>,
+    ) -> CopyJob {
+        self.manifest = Some((path.into(), prefix.into()));
+        self
+    }
+
+    /// With `store`, stores the checksum of each verified regular file of the copy in an
+    /// extended attribute.
+    pub fn checksums_in_xattrs(mut self, store: bool) -> CopyJob {
+        self.checksums_in_xattrs = store;
+        self
+    }
+
+    /// With `store`, stores the checksum of the whole copy in an extended attribute of its root
+    /// once it is correct.
+    pub fn tree_checksum_in_xattr(mut self, store: bool) -> CopyJob {
+        self.tree_checksum_in_xattr = store;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn on_disappear(mut self, policy: OnDisappear) -> CopyJob {
+        self.on_disappear = policy;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn on_persistent_mismatch(mut self, policy: OnPersistentMismatch) -> CopyJob {
+        self.on_persistent_mismatch = policy;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn show_remaining(mut self, show: bool) -> CopyJob {
+        self.show_remaining = show;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn summary(mut self, summary: bool) -> CopyJob {
+        self.summary = summary;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn report_cache(mut self, report: bool) -> CopyJob {
+        self.report_cache = report;
+        self
+    }
+
+    /// Copies, then fixes the copy until it is correct. Returns the number of rounds of fixing.
+    /// The copy may have moved if the cache manager remounted it.
+    pub fn run(self) -> anyhow::Result<u32> {
+        let mut cache_manager = self
+            .cache_manager
+            .context("CopyJob needs a cache manager")?;
+        let options = &self.options;
+        let mut progress = self.progress.unwrap_or_else(Progress::quiet);
+        let mut target = self.dest;
+        cache_manager.permission_check(&target).with_context(|| {
+            format!(
+                "Checking permissions for cache management with {}",
+                cache_manager.name()
+            )
+        })?;
+        // the fix loop compares the copy to the reference instead of the source
+        let mut to_reference = match (self.reference.as_ref(), self.sources.as_slice()) {
+            (None, _) => None,
+            (Some(reference), [(source, _)]) => Some(change_prefixes(source, reference)),
+            (Some(_), _) => anyhow::bail!("a reference needs a single source"),
+        };
+        let mut on_disappear = DisappearHandler::new(self.on_disappear, &target)?;
+        let resume = if self.resume {
+            let verified = match self.resume_state.as_ref() {
+                Some(path) if path.exists() => manifest::read_manifest(path)
+                    .context("reading --resume-state")?
+                    .into_iter()
+                    .map(|entry| (entry.path.clone(), entry))
+                    .collect(),
+                _ => HashMap::new(),
+            };
+            Some(Resume {
+                root: target.clone(),
+                verified,
+            })
+        } else {
+            None
+        };
+        let mut resume_state = match self.resume_state.as_ref() {
+            Some(path) => Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening --resume-state {}", path.display()))?,
+            ),
+            None => None,
+        };
+        // entries are copied as they are enumerated, so the total grows as we go
+        progress.next_round(0);
+        let mut obligations = Vec::new();
+        // entries skipped with --keep-going
+        let mut failures = Vec::new();
+        for (source, name) in &self.sources {
+            // with several sources, each is copied into DEST
+            let mut copy = match name {
+                Some(name) => target.join(name),
+                None => target.clone(),
+            };
+            let (copied, skipped) = first_copy(
+                &mut *cache_manager,
+                &progress,
+                options,
+                self.keep_going,
+                resume.as_ref(),
+                &mut on_disappear,
+                source,
+                &mut copy,
+            )
+            .context("during initial copy")?;
+            // the device of DEST may have come back elsewhere
+            let root = match name {
+                Some(_) => copy.parent().unwrap(),
+                None => copy.as_path(),
+            };
+            if root != target {
+                let replacement = Replacement {
+                    before: target.clone(),
+                    after: root.to_path_buf(),
+                };
+                apply_replacement(&replacement, &mut target, obligations.iter_mut());
+            }
+            obligations.extend(copied);
+            failures.extend(skipped);
+        }
+        if options.read_only {
+            progress.done();
+            let changes = obligations.iter().filter(|o| o.would_change).count();
+            anyhow::ensure!(
+                changes == 0,
+                "{} of {} entries would be copied or fixed",
+                changes,
+                obligations.len()
+            );
+            ensure_no_failures(&failures)?;
+            return Ok(0);
+        }
+        if let Some((percent, seed)) = self.verify_sample {
+            let seed = match seed {
+                Some(seed) => seed,
+                None => {
+                    let mut seed = [0; 8];
+                    std::fs::File::open("/dev/urandom")
+                        .and_then(|mut f| f.read_exact(&mut seed))
+                        .context("reading /dev/urandom for a --verify-seed")?;
+                    u64::from_ne_bytes(seed)
+                }
+            };
+            obligations = sample_obligations(obligations, percent, seed);
+            let bytes: u64 = obligations.iter().map(|o| o.size).sum();
+            progress.info(format!(
+                "Checking {} entries ({} bytes) sampled with --verify-seed={}:",
+                obligations.len(),
+                bytes,
+                seed
+            ));
+            for o in obligations.iter() {
+                progress.info(format!("  {}", o.source.display()));
+            }
+        }
+        // copies for which --post-verify-command failed
+        let mut post_verify_failures = Vec::new();
+        // copies moved away by --on-persistent-mismatch=quarantine
+        let mut quarantined = Vec::new();
+        // verified entries of the copy, for --write-manifest
+        let mut manifest_entries = Vec::new();
+        // checksum of the verified entries of the copy, for --checksum-tree-root-xattr
+        let mut tree = if self.tree_checksum_in_xattr {
+            Some(xattr::new_tree_checksum(options.checksum))
+        } else {
+            None
+        };
+        // corrupt(&opt.output)?;
+        let mut rounds = 0u32;
+        while !obligations.is_empty() {
+            rounds += 1;
+            log::info!("round {}: {} entries to check", rounds, obligations.len());
+            progress.syncing();
+            if let Some(replacement) = cache_manager
+                .drop_cache(&progress, &target)
+                .with_context(|| format!("Dropping cache below {}", target.display()))?
+            {
+                apply_replacement(&replacement, &mut target, obligations.iter_mut());
+            }
+            let total_size = obligations.iter().map(|o| o.size).sum();
+            if self.show_remaining {
+                progress.show_remaining(total_size);
+            }
+            progress.next_round(total_size);
+            let mut pending: VecDeque<Obligation> = obligations.drain(..).collect();
+            while let Some(mut obligation) = pending.pop_front() {
+                let mut checksum = Some(obligation.checksum);
+                let mut limit = obligation.limit;
+                let orig = match to_reference.as_mut() {
+                    Some(f) => f(&obligation.source),
+                    None => obligation.source.clone(),
+                };
+                let result = match obligation.link_source.as_ref() {
+                    _ if obligation.resumed => Ok(false.into()),
+                    // the content is checked through the first name
+                    Some(link_source) => copy::link_path(&progress, link_source, &obligation.dest)
+                        .map(copy::FixReport::from),
+                    None => copy::fix_path(
+                        &mut *cache_manager,
+                        &progress,
+                        options,
+                        &orig,
+                        &obligation.dest,
+                        &mut checksum,
+                        &mut limit,
+                    ),
+                };
+                match result.context("while fixing copy") {
+                    Ok(report) => {
+                        on_disappear.succeeded();
+                        // the source may have grown
+                        if let Some(limit) = limit {
+                            obligation.size = limit;
+                        }
+                        obligation.limit = limit;
+                        obligation.checksum = checksum.unwrap();
+                        if report.changed {
+                            if let (Some(mismatch), Some(last)) =
+                                (report.mismatch.as_ref(), obligation.last_mismatch.as_ref())
+                            {
+                                if mismatch == last {
+                                    let message = format!(
+                                        "{} read back with the same {} wrong bytes from offset {} in two rounds in a row, the medium is probably failing",
+                                        obligation.dest.display(),
+                                        mismatch.bytes,
+                                        mismatch.offset
+                                    );
+                                    match self.on_persistent_mismatch {
+                                        OnPersistentMismatch::Refix => progress.warn(message),
+                                        OnPersistentMismatch::Abort => anyhow::bail!(message),
+                                        OnPersistentMismatch::Quarantine => {
+                                            let moved = quarantine(&target, &obligation.dest)?;
+                                            progress.warn(format!(
+                                                "{}, moved it to {}",
+                                                message,
+                                                moved.display()
+                                            ));
+                                            quarantined.push(moved);
+                                            continue;
+                                        }
+                                    }
+                                }
+                            }
+                            obligation.last_mismatch = report.mismatch;
+                            obligations.push(obligation);
+                        } else {
+                            progress.verified(obligation.size);
+                            if let Some(file) = resume_state.as_mut() {
+                                if obligation.link_source.is_none()
+                                    && FileKind::of_path(&obligation.dest)? == FileKind::Regular
+                                {
+                                    let line = manifest::format_entry(&manifest::Entry {
+                                        path: obligation.dest.strip_prefix(&target)?.to_path_buf(),
+                                        algorithm: options.checksum,
+                                        checksum: obligation.checksum,
+                                        size: Some(obligation.size),
+                                    })?;
+                                    file.write_all(&line).context("writing to --resume-state")?;
+                                }
+                            }
+                            if self.checksums_in_xattrs
+                                && FileKind::of_path(&obligation.dest)? == FileKind::Regular
+                            {
+                                xattr::store_checksum(
+                                    &obligation.dest,
+                                    options.checksum,
+                                    obligation.checksum,
+                                )
+                                .with_context(|| {
+                                    format!(
+                                        "storing the checksum of {} in an extended attribute",
+                                        obligation.dest.display()
+                                    )
+                                })?;
+                            }
+                            if let Some(tree) = tree.as_mut() {
+                                let relative = obligation.dest.strip_prefix(&target)?;
+                                xattr::add_to_tree(
+                                    tree,
+                                    options.checksum,
+                                    relative,
+                                    obligation.checksum,
+                                );
+                            }
+                            if self.manifest.is_some() {
+                                let size = match FileKind::of_path(&obligation.dest)? {
+                                    FileKind::Regular => {
+                                        Some(match obligation.link_source.as_ref() {
+                                            Some(first) => std::fs::metadata(first)?.len(),
+                                            None => obligation.size,
+                                        })
+                                    }
+                                    _ => None,
+                                };
+                                manifest_entries.push(manifest::Entry {
+                                    path: obligation.dest.strip_prefix(&target)?.to_path_buf(),
+                                    algorithm: options.checksum,
+                                    checksum: obligation.checksum,
+                                    size,
+                                });
+                            }
+                            if let Some(command) = self.post_verify_command.as_ref() {
+                                if let Err(e) = run_post_verify_command(
+                                    command,
+                                    &obligation.dest,
+                                    obligation.checksum,
+                                ) {
+                                    progress.warn(format!("{:#}", e));
+                                    post_verify_failures.push(obligation.dest.clone());
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let replacement = match on_disappear.recover(
+                            e,
+                            &mut *cache_manager,
+                            &progress,
+                            &target,
+                        ) {
+                            Ok(replacement) => replacement,
+                            Err(e) => {
+                                skip_failure(
+                                    self.keep_going,
+                                    &progress,
+                                    &mut failures,
+                                    &obligation.dest,
+                                    e,
+                                )?;
+                                continue;
+                            }
+                        };
+                        // retry it
+                        pending.push_front(obligation);
+                        if let Some(replacement) = replacement {
+                            apply_replacement(
+                                &replacement,
+                                &mut target,
+                                obligations.iter_mut().chain(pending.iter_mut()),
+                            );
+                        }
+                    }
+                }
+            }
+            if self.verify_sample.is_some() && !obligations.is_empty() {
+                let differing: Vec<_> = obligations.iter().map(|o| o.dest.display()).collect();
+                anyhow::bail!(
+                    "Sampled files differed from the source and were fixed, other files of the copy may be wrong too: {:?}",
+                    differing
+                );
+            }
+            if self.once && !obligations.is_empty() {
+                anyhow::bail!("Still files to fix: {:?}", &obligations);
+            }
+            if let Some(max) = self.max_rounds {
+                if rounds >= max && !obligations.is_empty() {
+                    let differing: Vec<_> = obligations.iter().map(|o| o.dest.display()).collect();
+                    anyhow::bail!(
+                        "Still files to fix after {} rounds: {:?}",
+                        rounds,
+                        differing
+                    );
+                }
+            }
+        }
+        // the skipped entries are missing from the manifest and the tree checksum
+        if failures.is_empty() {
+            if let Some((path, prefix)) = self.manifest.as_ref() {
+                manifest::write_manifest(path, prefix, manifest_entries)?;
+            }
+            if let Some(tree) = tree {
+                xattr::store_tree_checksum(&target, options.checksum, tree).with_context(|| {
+                    format!(
+                        "storing the checksum of the tree below {} in an extended attribute",
+                        target.display()
+                    )
+                })?;
+            }
+        }
+        if quarantined.is_empty()
+            && post_verify_failures.is_empty()
+            && failures.is_empty()
+            && self.summary
+        {
+            progress.finish_with_summary();
+        } else {
+            progress.done();
+        }
+        ensure_no_failures(&failures)?;
+        anyhow::ensure!(
+            quarantined.is_empty(),
+            "The rest of the copy is correct, but {} files kept being read back wrong and were quarantined: {:?}",
+            quarantined.len(),
+            quarantined
+        );
+        anyhow::ensure!(
+            post_verify_failures.is_empty(),
+            "The copy is correct, but --post-verify-command failed for {} entries: {:?}",
+            post_verify_failures.len(),
+            post_verify_failures
+        );
+        if let Some(path) = self.resume_state.as_ref() {
+            // the copy is complete
+            std::fs::remove_file(path)
+                .with_context(|| format!("removing --resume-state {}", path.display()))?;
+        }
+        if self.report_cache {
+            for line in cache_manager.report(&target).with_context(|| {
+                format!("Reporting on cache management below {}", target.display())
+            })? {
+                eprintln!("{}", line);
+            }
+        }
+        Ok(rounds)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+struct Obligation {
+    source: PathBuf,
+    dest: PathBuf,
+    checksum: Checksum,
+    size: u64,
+    /// With `append_tolerant`, the length of the source covered by `checksum`.
+    limit: Option<u64>,
+    /// When `source` is a hard link to an entry copied earlier, the copy of that entry, which
+    /// `dest` must be a hard link to.
+    link_source: Option<PathBuf>,
+    /// The bytes which differed when the copy was last fixed.
+    last_mismatch: Option<copy::Mismatch>,
+    /// Verified by a previous run according to --resume-state, so it is not read again.
+    resumed: bool,
+    /// Whether the first copy wrote to `dest`, or would have with --dry-run.
+    would_change: bool,
+}
+
+/// What --resume trusts from a previous run.
+struct Resume {
+    /// The root of the copy when the run started.
+    root: PathBuf,
+    /// Regular files verified by a previous run, by path relative to `root`, as read from
+    /// --resume-state.
+    verified: HashMap<PathBuf, manifest::Entry>,
+}
+
+/// With --resume, returns the obligation for the existing copy `dest` of `source` if it looks
+/// like it was written by an interrupted copy: a regular file with the size of `source` and not
+/// older than it. Only `source` is read, unless a previous run already verified the copy.
+fn resume_entry(
+    progress: &Progress,
+    options: &CopyOptions,
+    resume: &Resume,
+    source: &Path,
+    dest: &Path,
+) -> anyhow::Result<Option<Obligation>> {
+    let orig = std::fs::symlink_metadata(source)
+        .with_context(|| format!("stat({}) to resume its copy", source.display()))?;
+    let copy = std::fs::symlink_metadata(dest)
+        .with_context(|| format!("stat({}) to resume the copy", dest.display()))?;
+    if !orig.is_file()
+        || !copy.is_file()
+        || copy.len() != orig.len()
+        || (copy.mtime(), copy.mtime_nsec()) < (orig.mtime(), orig.mtime_nsec())
+    {
+        return Ok(None);
+    }
+    let verified = dest
+        .strip_prefix(&resume.root)
+        .ok()
+        .and_then(|relative| resume.verified.get(relative));
+    let (checksum, resumed) = match verified {
+        Some(entry) if entry.algorithm == options.checksum && entry.size == Some(orig.len()) => {
+            (entry.checksum, true)
+        }
+        _ => {
+            progress.set_status(format!("Resuming {}", dest.display()));
+            let checksum =
+                copy::source_checksum(progress, options, options.throttle.as_ref(), source, None)
+                    .with_context(|| format!("computing the checksum of {}", source.display()))?;
+            (checksum, false)
+        }
+    };
+    progress.do_bytes(orig.len());
+    Ok(Some(Obligation {
+        source: source.to_path_buf(),
+        dest: dest.to_path_buf(),
+        checksum,
+        size: orig.len(),
+        limit: None,
+        link_source: None,
+        last_mismatch: None,
+        resumed,
+        would_change: false,
+    }))
+}
+
+/// Copies `source` to `dest`, or fixes `dest` if it already exists, and returns the
+/// corresponding obligation.
+fn copy_entry(
+    cache_manager: &mut dyn CacheManager,
+    progress: &Progress,
+    options: &CopyOptions,
+    resume: Option<&Resume>,
+    source: PathBuf,
+    dest: PathBuf,
+    size: u64,
+) -> anyhow::Result<Obligation> {
+    let mut limit =
+        if options.append_tolerant && copy::source_kind(options, &source)? == FileKind::Regular {
+            // the source may have grown since it was enumerated
+            Some(size)
+        } else {
+            None
+        };
+    let exists = utils::exists(&dest)
+        .with_context(|| format!("checking if a copy {} already exists", dest.display()))?;
+    if let (true, Some(resume)) = (exists, resume) {
+        if let Some(obligation) = resume_entry(progress, options, resume, &source, &dest)? {
+            return Ok(obligation);
+        }
+    }
+    let (checksum, would_change) = if exists {
+        let mut checksum = None;
+        let report = copy::fix_path(
+            cache_manager,
+            progress,
+            options,
+            &source,
+            &dest,
+            &mut checksum,
+            &mut limit,
+        )
+        .with_context(|| {
+            format!(
+                "fixing existing copy {} of {}",
+                dest.display(),
+                source.display()
+            )
+        })?;
+        if options.read_only && report.changed {
+            progress.info(format!("Would fix {}", dest.display()));
+        }
+        (checksum.unwrap(), report.changed)
+    } else {
+        let checksum = copy::copy_path(cache_manager, progress, options, &source, &dest, limit)
+            .with_context(|| format!("copying {} to {}", source.display(), dest.display()))?;
+        (checksum, true)
+    };
+    Ok(Obligation {
+        source,
+        dest,
+        checksum,
+        size: limit.unwrap_or(size),
+        limit,
+        link_source: None,
+        last_mismatch: None,
+        resumed: false,
+        would_change,
+    })
+}
+
+/// With --dry-run, returns whether `dest` would be made a hard link to `link_source`, and says
+/// so.
+fn plan_link(progress: &Progress, link_source: &Path, dest: &Path) -> bool {
+    let linked = match (
+        std::fs::symlink_metadata(link_source),
+        std::fs::symlink_metadata(dest),
+    ) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    };
+    if !linked {
+        progress.info(format!(
+            "Would link {} to {}",
+            dest.display(),
+            link_source.display()
+        ));
+    }
+    !linked
+}
+
+/// Whether `first_copy` should copy this entry, and descend into it if it is a directory.
+fn should_copy(entry: &walkdir::DirEntry, options: &CopyOptions, target: &Path) -> bool {
+    if entry.depth() == 0 {
+        // never prune the root
+        return true;
+    }
+    if entry.path() == target {
+        // do not copy the copy, if it is below the source
+        return false;
+    }
+    if options
+        .exclude
+        .as_ref()
+        .map_or(false, |exclude| exclude.matches(entry.path()))
+    {
+        return false;
+    }
+    !(entry.file_type().is_dir()
+        && options
+            .exclude_if_present
+            .iter()
+            .any(|marker| std::fs::symlink_metadata(entry.path().join(marker)).is_ok()))
+}
+
+/// Returns the size of this entry if it is a regular file excluded by its size.
+fn size_excluded(entry: &walkdir::DirEntry, options: &CopyOptions) -> Option<u64> {
+    if entry.depth() == 0 || !entry.file_type().is_file() {
+        return None;
+    }
+    let size = entry.metadata().ok()?.len();
+    if options.exclude_larger_than.map_or(false, |max| size > max)
+        || options.exclude_smaller_than.map_or(false, |min| size < min)
+    {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+/// Updates the paths of the copy after they changed, for example because it was remounted
+/// elsewhere.
+fn apply_replacement<'a>(
+    replacement: &Replacement,
+    target: &mut PathBuf,
+    obligations: impl Iterator<Item = &'a mut Obligation>,
+) {
+    let mut f = change_prefixes(&replacement.before, &replacement.after);
+    for o in obligations {
+        o.dest = f(&o.dest);
+        o.link_source = o.link_source.as_deref().map(&mut f);
+    }
+    *target = f(target);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn first_copy(
+    cache_manager: &mut dyn CacheManager,
+    progress: &Progress,
+    options: &CopyOptions,
+    keep_going: bool,
+    resume: Option<&Resume>,
+    on_disappear: &mut DisappearHandler,
+    orig: &Path,
+    target: &mut PathBuf,
+) -> anyhow::Result<(Vec<Obligation>, Vec<Failure>)> {
+    let follow_links = options.links == LinkPolicy::Copy;
+    let meta = if follow_links {
+        std::fs::metadata(orig).map_err(|e| dangling_symlink_error(e.into(), orig))
+    } else {
+        std::fs::symlink_metadata(orig).map_err(anyhow::Error::from)
+    }
+    .with_context(|| format!("stat({}) to enumerate obligations", orig.display()))?;
+    anyhow::ensure!(
+        !(options.links == LinkPolicy::Skip && FileKind::of_metadata(&meta) == FileKind::Symlink),
+        "{} is a symlink, and --links={} skips symlinks",
+        orig.display(),
+        options.links
+    );
+    let initial_target = target.clone();
+    if options.preallocate_dirs
+        && !options.read_only
+        && FileKind::of_metadata(&meta) == FileKind::Directory
+    {
+        progress.set_status("Creating directories");
+        let mut dirs = Vec::new();
+        for entry in walkdir::WalkDir::new(orig)
+            .follow_links(follow_links)
+            .into_iter()
+            .filter_entry(|entry| should_copy(entry, options, &initial_target))
+        {
+            let entry = entry.with_context(|| format!("iterating in {}", orig.display()))?;
+            if entry.file_type().is_dir() {
+                dirs.push((entry.depth(), entry.into_path()));
+            }
+        }
+        // parents first
+        dirs.sort_by_key(|&(depth, _)| depth);
+        let mut to_target = change_prefixes(orig, target);
+        for (_, dir) in dirs {
+            copy::create_directory(options, &to_target(&dir))?;
+        }
+    }
+    // walkdir always dereferences its arguments if it is a symlink, so we special case it
+    let entries: Box<dyn Iterator<Item = anyhow::Result<SourceEntry>>> =
+        match FileKind::of_metadata(&meta) {
+            FileKind::Directory if options.files.is_some() => Box::new(listed_entries(
+                progress,
+                options,
+                orig,
+                options.files.as_deref().unwrap_or_default(),
+            )),
+            FileKind::Directory => Box::new(
+                walkdir::WalkDir::new(orig)
+                    // this also detects symlink loops
+                    .follow_links(follow_links)
+                    .into_iter()
+                    .filter_entry(|entry| {
+                        if options.links == LinkPolicy::Skip && entry.file_type().is_symlink() {
+                            progress.info(format!("Skipping symlink {}", entry.path().display()));
+                            return false;
+                        }
+                        if let Some(size) = size_excluded(entry, options) {
+                            progress.info(format!(
+                                "Skipping {} of {} bytes",
+                                entry.path().display(),
+                                size
+                            ));
+                            return false;
+                        }
+                        should_copy(entry, options, &initial_target)
+                    })
+                    .map(|entry| {
+                        let entry = entry
+                            .map_err(|e| match (e.path(), e.io_error()) {
+                                (Some(path), Some(io))
+                                    if follow_links
+                                        && io.kind() == std::io::ErrorKind::NotFound =>
+                                {
+                                    let path = path.to_path_buf();
+                                    dangling_symlink_error(e.into(), &path)
+                                }
+                                _ => e.into(),
+                            })
+                            .with_context(|| format!("iterating in {}", orig.display()))?;
+                        let meta = entry.metadata().with_context(|| {
+                            format!("stat({}) to get size", entry.path().display())
+                        })?;
+                        let size = utils::copy_size(&meta);
+                        Ok((entry.into_path(), size, hard_link_key(&meta)))
+                    }),
+            ),
+            kind => {
+                let size = match (options.source_range, kind) {
+                    (Some(range), _) => range.len,
+                    // the size of a block device is not in its metadata
+                    (None, FileKind::Device) => utils::device_size(orig)?,
+                    (None, _) => utils::copy_size(&meta),
+                };
+                Box::new(std::iter::once(Ok((orig.to_path_buf(), size, None))))
+            }
+        };
+    let mut res: Vec<Obligation> = Vec::new();
+    // index in `res` of the regular files with several names seen so far, by (device, inode)
+    let mut links: HashMap<InodeKey, usize> = HashMap::new();
+    let mut failures = Vec::new();
+    'entries: for entry in entries {
+        let (source, size, key) = entry?;
+        let linked = key.and_then(|key| links.get(&key).copied());
+        if linked.is_none() {
+            progress.add_total(size);
+        }
+        let obligation = loop {
+            let dest = change_prefixes(orig, target)(&source);
+            let result = match linked.map(|i| &res[i]) {
+                Some(first) => if options.read_only {
+                    Ok(plan_link(progress, &first.dest, &dest))
+                } else {
+                    copy::link_path(progress, &first.dest, &dest)
+                }
+                .with_context(|| format!("copying hard link {}", source.display()))
+                .map(|would_change| Obligation {
+                    source: source.clone(),
+                    dest,
+                    checksum: first.checksum,
+                    size: 0,
+                    limit: None,
+                    link_source: Some(first.dest.clone()),
+                    last_mismatch: None,
+                    resumed: false,
+                    would_change,
+                }),
+                None => copy_entry(
+                    &mut *cache_manager,
+                    progress,
+                    options,
+                    resume,
+                    source.clone(),
+                    dest,
+                    size,
+                ),
+            };
+            match result {
+                Ok(obligation) => {
+                    on_disappear.succeeded();
+                    break obligation;
+                }
+                Err(e) => match on_disappear.recover(e, cache_manager, progress, target) {
+                    Ok(Some(replacement)) => {
+                        apply_replacement(&replacement, target, res.iter_mut())
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        skip_failure(keep_going, progress, &mut failures, &source, e)?;
+                        continue 'entries;
+                    }
+                },
+            }
+        };
+        if let (Some(key), None) = (key, linked) {
+            links.insert(key, res.len());
+        }
+        res.push(obligation);
+    }
+    Ok((res, failures))
+}
+
+/// An entry skipped with --keep-going, and why.
+type Failure = (PathBuf, anyhow::Error);
+
+/// Returns `error`, which happened on the entry `path`, unless --keep-going was passed: then
+/// reports it and adds it to `failures`.
+fn skip_failure(
+    keep_going: bool,
+    progress: &Progress,
+    failures: &mut Vec<Failure>,
+    path: &Path,
+    error: anyhow::Error,
+) -> anyhow::Result<()> {
+    if !keep_going {
+        return Err(error);
+    }
+    progress.warn(format!("skipping {}: {:#}", path.display(), error));
+    failures.push((path.to_path_buf(), error));
+    Ok(())
+}
+
+/// Returns an error listing the entries skipped with --keep-going, if any.
+fn ensure_no_failures(failures: &[Failure]) -> anyhow::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut message = format!(
+        "{} entries could not be copied and were skipped with --keep-going:",
+        failures.len()
+    );
+    for (path, error) in failures {
+        message.push_str(&format!("\n  {}: {:#}", path.display(), error));
+    }
+    Err(anyhow::anyhow!(message))
+}
+
+/// Enumerates the root `orig` and the entries of `files`, relative to `orig`, each after its
+/// parent directories, for --files-from.
+fn listed_entries<'a>(
+    progress: &'a Progress,
+    options: &'a CopyOptions,
+    orig: &'a Path,
+    files: &[PathBuf],
+) -> impl Iterator<Item = anyhow::Result<SourceEntry>> + 'a {
+    let mut seen = std::collections::HashSet::new();
+    let mut relative = Vec::new();
+    for file in files {
+        let mut ancestors: Vec<&Path> = file.ancestors().collect();
+        // the root, then parents first
+        ancestors.reverse();
+        for path in ancestors {
+            if seen.insert(path) {
+                relative.push(path.to_path_buf());
+            }
+        }
+    }
+    if relative.is_empty() {
+        relative.push(PathBuf::new());
+    }
+    let follow_links = options.links == LinkPolicy::Copy;
+    relative.into_iter().filter_map(move |relative| {
+        let path = if relative.as_os_str().is_empty() {
+            orig.to_path_buf()
+        } else {
+            orig.join(&relative)
+        };
+        let meta = if follow_links {
+            std::fs::metadata(&path).map_err(|e| dangling_symlink_error(e.into(), &path))
+        } else {
+            std::fs::symlink_metadata(&path).map_err(anyhow::Error::from)
+        }
+        .with_context(|| format!("stat({}) listed by --files-from", path.display()));
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(e) => return Some(Err(e)),
+        };
+        if options.links == LinkPolicy::Skip && meta.file_type().is_symlink() {
+            progress.info(format!("Skipping symlink {}", path.display()));
+            return None;
+        }
+        let size = utils::copy_size(&meta);
+        Some(Ok((path, size, hard_link_key(&meta))))
+    })
+}
+
+/// Device and inode number of a file.
+type InodeKey = (u64, u64);
+
+/// An entry enumerated by `first_copy`: its path, its size, and its `InodeKey` if it is a
+/// regular file with several names.
+type SourceEntry = (PathBuf, u64, Option<InodeKey>);
+
+/// Turns `error`, which happened when following `path` with --links=copy, into an error telling
+/// that `path` is a dangling symlink, if it is one: there is nothing to copy.
+fn dangling_symlink_error(error: anyhow::Error, path: &Path) -> anyhow::Error {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => error.context(format!(
+            "{} is a dangling symlink, and --links=copy copies what symlinks point to",
+            path.display()
+        )),
+        _ => error,
+    }
+}
+
+/// Identifies regular files with several names, which are copied as hard links.
+fn hard_link_key(meta: &std::fs::Metadata) -> Option<InodeKey> {
+    if meta.is_file() && meta.nlink() > 1 {
+        Some((meta.dev(), meta.ino()))
+    } else {
+        None
+    }
+}
+
+/// Keeps a random subset of `obligations`, chosen from `seed`, totalling about `percent` of
+/// their bytes. Empty entries like directories are cheap to check, so they are always kept.
+fn sample_obligations(
+    mut obligations: Vec<Obligation>,
+    percent: f64,
+    seed: u64,
+) -> Vec<Obligation> {
+    // the order of the walk depends on the file system, but the sample must only depend on the
+    // seed
+    obligations.sort_by(|a, b| a.source.cmp(&b.source));
+    let mut rng = utils::SplitMix64::new(seed);
+    for i in (1..obligations.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        obligations.swap(i, j);
+    }
+    let total: u64 = obligations.iter().map(|o| o.size).sum();
+    let budget = (total as f64 * percent / 100.) as u64;
+    let mut taken = 0;
+    let mut res: Vec<Obligation> = obligations
+        .into_iter()
+        .filter(|o| {
+            if o.size == 0 {
+                true
+            } else if taken < budget {
+                taken += o.size;
+                true
+            } else {
+                false
+            }
+        })
+        .collect();
+    res.sort_by(|a, b| a.source.cmp(&b.source));
+    res
+}
+
+#[test]
+fn test_sample_obligations() {
+    let obligations: Vec<Obligation> = (0..1000u64)
+        .map(|i| Obligation {
+            source: PathBuf::from(format!("/src/{:04}", i)),
+            dest: PathBuf::from(format!("/dest/{:04}", i)),
+            checksum: "0000000000000000".parse().unwrap(),
+            size: if i % 10 == 0 { 0 } else { 1000 },
+            limit: None,
+            link_source: None,
+            last_mismatch: None,
+            resumed: false,
+            would_change: true,
+        })
+        .collect();
+    let mut reversed = obligations.clone();
+    reversed.reverse();
+    let sample = sample_obligations(obligations.clone(), 10., 42);
+    assert_eq!(sample, sample_obligations(reversed, 10., 42));
+    assert_ne!(sample, sample_obligations(obligations.clone(), 10., 43));
+    let bytes: u64 = sample.iter().map(|o| o.size).sum();
+    assert_eq!(bytes, 90_000);
+    assert_eq!(sample.iter().filter(|o| o.size == 0).count(), 100);
+    assert_eq!(
+        sample_obligations(obligations.clone(), 100., 1),
+        obligations
+    );
+}
+
+/// Turns the `{path}` and `{checksum}` placeholders of `--post-verify-command` into the
+/// positional parameters of `sh -c`, so that they are never interpreted by the shell.
+fn post_verify_script(command: &str) -> String {
+    command
+        .replace("{path}", "\"$1\"")
+        .replace("{checksum}", "\"$2\"")
+}
+
+/// Runs `--post-verify-command` for the verified copy `path`, whose checksum is `checksum`.
+fn run_post_verify_command(command: &str, path: &Path, checksum: Checksum) -> anyhow::Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(post_verify_script(command))
+        .arg("sh")
+        .arg(path)
+        .arg(checksum.to_string())
+        .status()
+        .with_context(|| format!("running sh -c {:?}", command))?;
+    anyhow::ensure!(
+        status.success(),
+        "{:?} for {} failed with {}",
+        command,
+        path.display(),
+        status
+    );
+    Ok(())
+}
+
+#[test]
+fn test_post_verify_script() {
+    assert_eq!(
+        post_verify_script("register {path} --sum={checksum}"),
+        r#"register "$1" --sum="$2""#
+    );
+    assert_eq!(post_verify_script("true"), "true");
+}
+
+/// Name of the directory next to DEST where `OnPersistentMismatch::Quarantine` moves bad copies.
+const QUARANTINE_DIR: &str = ".cccp-quarantine";
+
+/// Moves the copy `dest` below the root of the copy `target` to the quarantine directory, keeping
+/// its path relative to `target`, and returns where it was moved. `dest` is a regular file, as
+/// only those are read back with wrong bytes.
+fn quarantine(target: &Path, dest: &Path) -> anyhow::Result<PathBuf> {
+    let mut moved = target.parent().unwrap_or(target).join(QUARANTINE_DIR);
+    match dest.strip_prefix(target) {
+        Ok(relative) if relative != Path::new("") => moved.push(relative),
+        _ => moved.push(dest.file_name().context("copy without a file name")?),
+    }
+    if let Some(parent) = moved.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating quarantine directory {}", parent.display()))?;
+    }
+    match std::fs::rename(dest, &moved) {
+        // DEST is a mountpoint, so its parent is on another filesystem
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            std::fs::copy(dest, &moved)
+                .and_then(|_| std::fs::remove_file(dest))
+                .with_context(|| {
+                    format!(
+                        "copying {} to quarantine as {} and removing it",
+                        dest.display(),
+                        moved.display()
+                    )
+                })?;
+        }
+        res => res.with_context(|| {
+            format!(
+                "moving {} to quarantine as {}",
+                dest.display(),
+                moved.display()
+            )
+        })?,
+    }
+    Ok(moved)
+}
+
+/// Checks that `dest` is an identical copy of `source` without writing to it, reading it
+/// without cache as `fix_path` does with `options.read_only` set, and reports each entry with
+/// the first divergence found in it. Returns an error if any entry is missing or differs.
+pub fn verify_copy(
+    cache_manager: &mut dyn CacheManager,
+    mut progress: Progress,
+    mut options: CopyOptions,
+    source: &Path,
+    dest: &Path,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        options.vhd_footer.is_none(),
+        "verifying a copy to a VHD image is not supported"
+    );
+    options.read_only = true;
+    let entries = verified_entries(&progress, &options, source, dest)?;
+    progress.syncing();
+    let dest = match cache_manager
+        .drop_cache(&progress, dest)
+        .with_context(|| format!("Dropping cache below {}", dest.display()))?
+    {
+        Some(replacement) => change_prefixes(&replacement.before, &replacement.after)(dest),
+        None => dest.to_path_buf(),
+    };
+    progress.next_round(entries.iter().map(|(_, size)| size).sum());
+    let mut failures = 0u64;
+    for (entry, _) in &entries {
+        let copy = change_prefixes(source, &dest)(entry);
+        progress.set_status(format!("Verifying {}", copy.display()));
+        if !utils::exists(&copy)? {
+            failures += 1;
+            progress.warn(format!("{}: FAILED, missing", copy.display()));
+            continue;
+        }
+        match copy::fix_path(
+            cache_manager,
+            &progress,
+            &options,
+            entry,
+            &copy,
+            &mut None,
+            &mut None,
+        ) {
+            Ok(report) if !report.changed => progress.info(format!("{}: OK", copy.display())),
+            Ok(report) => {
+                failures += 1;
+                match report.mismatch {
+                    Some(mismatch) => progress.warn(format!(
+                        "{}: FAILED, differs from {} at offset {}",
+                        copy.display(),
+                        entry.display(),
+                        mismatch.offset
+                    )),
+                    None => progress.warn(format!(
+                        "{}: FAILED, differs from {}",
+                        copy.display(),
+                        entry.display()
+                    )),
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                progress.warn(format!("{}: FAILED, {:#}", copy.display(), e));
+            }
+        }
+    }
+    progress.done();
+    anyhow::ensure!(
+        failures == 0,
+        "{} of {} entries of {} differ from {}",
+        failures,
+        entries.len(),
+        dest.display(),
+        source.display()
+    );
+    Ok(())
+}
+
+/// Lists the entries of `source` which `first_copy` would copy to `dest`, parents first, with
+/// their size.
+fn verified_entries(
+    progress: &Progress,
+    options: &CopyOptions,
+    source: &Path,
+    dest: &Path,
+) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let follow_links = options.links == LinkPolicy::Copy;
+    let meta = if follow_links {
+        std::fs::metadata(source).map_err(|e| dangling_symlink_error(e.into(), source))
+    } else {
+        std::fs::symlink_metadata(source).map_err(anyhow::Error::from)
+    }
+    .with_context(|| format!("stat({}) to enumerate entries", source.display()))?;
+    if FileKind::of_metadata(&meta) != FileKind::Directory {
+        // walkdir would dereference a symlink
+        return Ok(vec![(source.to_path_buf(), utils::copy_size(&meta))]);
+    }
+    let mut res = Vec::new();
+    for entry in walkdir::WalkDir::new(source)
+        .follow_links(follow_links)
+        .into_iter()
+        .filter_entry(|entry| {
+            if options.links == LinkPolicy::Skip && entry.file_type().is_symlink() {
+                return false;
+            }
+            if let Some(size) = size_excluded(entry, options) {
+                progress.info(format!(
+                    "Skipping {} of {} bytes",
+                    entry.path().display(),
+                    size
+                ));
+                return false;
+            }
+            should_copy(entry, options, dest)
+        })
+    {
+        let entry = entry.with_context(|| format!("iterating in {}", source.display()))?;
+        let meta = entry
+            .metadata()
+            .with_context(|| format!("stat({}) to get size", entry.path().display()))?;
+        res.push((entry.into_path(), utils::copy_size(&meta)));
+    }
+    Ok(res)
+}
@@ -0,0 +1,40 @@
+//! Copies files and checks that the copy reads back correctly, bypassing the caches between the
+//! copy and the check. This is the library behind the `cccp` command.
+//!
+//! `CopyJob` copies a tree until it is correct, and `verify_copy` checks an existing copy. The
+//! building blocks they use are public too: a `CacheManager` bypasses caches, `copy_path` and
+//! `fix_path` copy and fix single entries, and `Progress` reports what happens. The cache
+//! managers are in `cache`.
+
+pub mod cache;
+
+// used by the cccp command, not part of the API
+#[doc(hidden)]
+pub mod checksum;
+#[doc(hidden)]
+pub mod copy;
+#[doc(hidden)]
+pub mod disappear;
+#[doc(hidden)]
+pub mod job;
+#[doc(hidden)]
+pub mod manifest;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod throttle;
+#[doc(hidden)]
+pub mod udev;
+#[doc(hidden)]
+pub mod utils;
+#[doc(hidden)]
+pub mod vhd;
+#[doc(hidden)]
+pub mod xattr;
+
+pub use crate::cache::{CacheManager, Replacement};
+pub use crate::checksum::{Checksum, ChecksumAlgorithm};
+pub use crate::copy::{copy_path, fix_path, CopyOptions, FixReport};
+pub use crate::job::{verify_copy, CopyJob};
+pub use crate::progress::{Progress, ProgressEvent};
+pub use crate::utils::FileKind;
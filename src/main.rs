@@ -1,80 +1,72 @@
-mod cache;
-mod checksum;
-mod copy;
-mod progress;
-mod udev;
-mod utils;
-
-use crate::cache::{CacheManager, Replacement};
-use crate::progress::Progress;
-use crate::utils::{change_prefixes, FileKind};
 use anyhow::Context;
-use checksum::Checksum;
+use cccp::cache::{self, CacheManager};
+use cccp::checksum::{self, ChecksumAlgorithm};
+use cccp::copy::{self, CopyOptions, DeletePolicy, LinkPolicy, Preserve};
+use cccp::disappear::OnDisappear;
+use cccp::job::OnPersistentMismatch;
+use cccp::progress::{self, Progress, ProgressFormat, ProgressLayout};
+use cccp::throttle::Throttle;
+use cccp::utils::{self, change_prefixes, ByteRange, ChmodSpec, FileKind};
+use cccp::vhd::{DestFormat, VhdFooter};
+use cccp::{manifest, xattr, CopyJob};
 use clap::arg_enum;
+use std::ffi::OsString;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-struct Obligation {
-    source: PathBuf,
-    dest: PathBuf,
-    checksum: Checksum,
-    size: u64,
-}
-
-fn first_copy(
-    cache_manager: &dyn CacheManager,
-    progress: &mut Progress,
-    orig: &Path,
-    target: &PathBuf,
-) -> anyhow::Result<Vec<Obligation>> {
-    let mut orig_paths = vec![];
-    let meta = std::fs::symlink_metadata(orig)
-        .with_context(|| format!("stat({}) to enumerate obligations", orig.display()))?;
-    // walkdir always dereferences its arguments if it is a symlink, so we special case it
-    match FileKind::of_metadata(&meta) {
-        FileKind::Directory => {
-            for entry in walkdir::WalkDir::new(orig) {
-                let entry = entry.with_context(|| format!("iterating in {}", orig.display()))?;
-                let meta = entry
-                    .metadata()
-                    .with_context(|| format!("stat({}) to get size", entry.path().display()))?;
-                orig_paths.push((entry.into_path(), utils::copy_size(&meta)));
-            }
+/// Reads the paths listed in the file `path` of --files-from, `-` for stdin, separated by newlines
+/// or with `null` by null bytes. They must be relative and stay below SOURCE.
+fn read_files_from(path: &Path, null: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_end(&mut content)
+            .context("reading --files-from from stdin")?;
+    } else {
+        content = std::fs::read(path)
+            .with_context(|| format!("reading --files-from {}", path.display()))?;
+    }
+    let separator = if null { b'\0' } else { b'\n' };
+    let mut files = Vec::new();
+    for name in content
+        .split(|&b| b == separator)
+        .filter(|name| !name.is_empty())
+    {
+        let file = Path::new(std::ffi::OsStr::from_bytes(name));
+        anyhow::ensure!(
+            file.is_relative()
+                && file
+                    .components()
+                    .all(|c| c != std::path::Component::ParentDir),
+            "{} listed by --files-from is not below SOURCE",
+            file.display()
+        );
+        // without `.` components, so that parents are only created once
+        let file: PathBuf = file.components().collect();
+        if !file.as_os_str().is_empty() {
+            files.push(file);
         }
-        _ => orig_paths.push((orig.to_path_buf(), utils::copy_size(&meta))),
     }
-    let total_size = orig_paths.iter().map(|&(_, size)| size).sum();
-    progress.next_round(total_size);
-    let mut to_new_paths = utils::change_prefixes(orig, target);
-    let mut res = Vec::new();
-    for (source, size) in orig_paths {
-        let dest = to_new_paths(&source);
-        let checksum = if utils::exists(&dest)
-            .with_context(|| format!("checking if a copy {} already exists", dest.display()))?
-        {
-            let mut checksum = None;
-            let _changed = copy::fix_path(cache_manager, progress, &source, &dest, &mut checksum)
-                .with_context(|| {
-                format!(
-                    "fixing existing copy {} of {}",
-                    dest.display(),
-                    source.display()
-                )
-            })?;
-            checksum.unwrap()
-        } else {
-            copy::copy_path(cache_manager, progress, &source, &dest)
-                .with_context(|| format!("copying {} to {}", source.display(), dest.display()))?
-        };
-        res.push(Obligation {
-            source,
-            dest,
-            checksum,
-            size,
-        });
+    Ok(files)
+}
+
+/// Returns the number of bytes of all the entries of `source`.
+fn tree_size(source: &Path, follow_links: bool) -> anyhow::Result<u64> {
+    if FileKind::of_path(source)? == FileKind::Device {
+        return utils::device_size(source);
+    }
+    let mut total = 0;
+    for entry in walkdir::WalkDir::new(source).follow_links(follow_links) {
+        let entry = entry.with_context(|| format!("iterating in {}", source.display()))?;
+        let meta = entry
+            .metadata()
+            .with_context(|| format!("stat({}) to get size", entry.path().display()))?;
+        total += utils::copy_size(&meta);
     }
-    Ok(res)
+    Ok(total)
 }
 
 arg_enum! {
@@ -84,24 +76,397 @@ arg_enum! {
         DirectIO,
         Umount,
         UsbReset,
+        Fuse,
+        BlkFlush,
+        Fadvise,
+        Auto,
+    }
+}
+
+/// Modes tried in this order by --mode=auto, from the most to the least thorough.
+const AUTO_MODES: [Mode; 4] = [Mode::Umount, Mode::UsbReset, Mode::DirectIO, Mode::Vm];
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum LogFormat {
+        Human,
+        Json,
     }
 }
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "cccp")]
+enum Command {
+    /// Checks that DEST is an identical copy of SOURCE without writing anything, reading it
+    /// without cache, and reports the first difference in each file. Options such as --mode,
+    /// --checksum or --links must come before `verify`.
+    Verify {
+        #[structopt(name = "SOURCE", parse(from_os_str))]
+        source: PathBuf,
+        #[structopt(name = "DEST", parse(from_os_str))]
+        dest: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cccp", setting = clap::AppSettings::SubcommandsNegateReqs)]
 struct Opt {
-    /// File or directory to copy
-    #[structopt(name = "SOURCE", parse(from_os_str))]
-    input: PathBuf,
-    /// Destination. Can be a block device if SOURCE is a regular file.
-    #[structopt(name = "DEST", parse(from_os_str))]
-    output: PathBuf,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// File or directory to copy. With --verify-only or --verify-manifest, the copy to verify.
+    #[structopt(name = "SOURCE", parse(from_os_str), required = true)]
+    input: Option<PathBuf>,
+    /// Destination. When it is an existing directory, SOURCE is copied into it, like with cp,
+    /// unless SOURCE ends with a slash: then DEST becomes the copy of the directory SOURCE.
+    /// Can be a block device if SOURCE is a regular file.
+    #[structopt(
+        name = "DEST",
+        parse(from_os_str),
+        required_unless_one = &["verify-only", "verify-manifest", "list-modes"]
+    )]
+    output: Option<PathBuf>,
+    /// Make DEST the copy of SOURCE even if it is an existing directory, like `cp -T`. This is
+    /// the same as a trailing slash after SOURCE.
+    #[structopt(short = "T", long, conflicts_with = "MORE")]
+    no_target_directory: bool,
+    /// More paths, like `cp SOURCE... DIRECTORY`: all the paths but the last are copied into the
+    /// last one, which must be an existing directory.
+    #[structopt(name = "MORE", parse(from_os_str))]
+    more: Vec<PathBuf>,
     /// Only attempt to fix files once, and bail out if it is not enough
     #[structopt(short = "1", long)]
     once: bool,
-    /// Method used to prevent re-reading from cache when checking files.
+    /// Bail out if the copy is still not correct after this many rounds of fixing
+    #[structopt(long, value_name = "N")]
+    max_rounds: Option<u32>,
+    /// What to do with a file read back with the same wrong bytes in two rounds in a row:
+    /// `refix` it again, `abort` the copy, or `quarantine` it by moving it to a `.cccp-quarantine`
+    /// directory next to DEST and continue with the rest of the copy.
+    #[structopt(possible_values = &OnPersistentMismatch::variants(), case_insensitive = true, default_value="refix", long)]
+    on_persistent_mismatch: OnPersistentMismatch,
+    /// Print a line with the throughput at this interval, like `30s`, `5m` or `1h`, for logs
+    /// where the progress bars are not visible.
+    #[structopt(long, parse(try_from_str = utils::parse_duration))]
+    rate_report_interval: Option<std::time::Duration>,
+    /// Print whether checksums are hardware accelerated, and details about how caches were
+    /// bypassed after the copy, and log what is copied, fixed, removed and remounted. Repeat it
+    /// to also log checksums and offsets. `RUST_LOG` can refine what is logged, with the syntax
+    /// of `env_logger`.
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u64,
+    /// Do not draw progress bars, only print warnings, errors and requested reports. This is
+    /// the default when stderr is not a terminal, as when run from cron or systemd.
+    #[structopt(short, long)]
+    quiet: bool,
+    /// What the progress bar shows while checking the copy: the bytes checked during the
+    /// current round with `rounds`, or with `remaining` the bytes verified for good, in a single
+    /// bar for the whole check.
+    #[structopt(possible_values = &ProgressLayout::variants(), case_insensitive = true, default_value="rounds", long)]
+    progress: ProgressLayout,
+    /// How to report progress: `bars` for progress bars, or `json` for one JSON object per line
+    /// and event (`round`, `bytes`, `status`, `syncing`, `verified`, `info`, `warning`, `done`) on
+    /// stderr or --progress-fd, for other programs to display.
+    #[structopt(possible_values = &ProgressFormat::variants(), case_insensitive = true, default_value="bars", long)]
+    progress_format: ProgressFormat,
+    /// With --progress-format=json, write the events to this already open file descriptor
+    /// instead of stderr.
+    #[structopt(long, value_name = "FD")]
+    progress_fd: Option<std::os::unix::io::RawFd>,
+    /// I/O scheduling class to run with, `idle` or `best-effort[:level]` with a level from 0
+    /// (highest priority) to 7, so that background copies do not slow down other programs.
+    #[structopt(long, alias = "ionice")]
+    ioprio: Option<utils::IoPriority>,
+    /// Method used to prevent re-reading from cache when checking files. `auto` uses the first
+    /// of umount, usbreset, directio and vm which can work for DEST.
     #[structopt(possible_values = &Mode::variants(), case_insensitive = true, default_value="directio", short, long)]
     mode: Mode,
+    /// Also checksum the copy when rereading it, and warn if the checksum would have missed
+    /// differences found by comparing bytes.
+    #[structopt(long)]
+    double_read: bool,
+    /// Do not reread regions of the copy where the source only contains zeros. This makes
+    /// checking sparse images much faster, but corruption in these regions goes unnoticed.
+    /// New copies of regular files also keep the holes of the source, found with
+    /// lseek(SEEK_HOLE), instead of writing zeros. When imaging a block device, this works for
+    /// devices which report their unmapped regions as holes.
+    #[structopt(long)]
+    sparse: bool,
+    /// Format of the error report printed on failure. `json` prints the error chain, the mode and
+    /// the paths as a single JSON object on stderr.
+    #[structopt(possible_values = &LogFormat::variants(), case_insensitive = true, default_value="human", long)]
+    log_format: LogFormat,
+    /// Hash function used to detect that the source changed during the copy. `crc64` is the
+    /// fastest, `sha256` and `blake3` also detect changes of regular files crafted to collide.
+    /// Directories and the tree of --checksum-tree-root-xattr combine the hashes of their entries
+    /// with XOR, which can still be forged. With `none`, copies are only checked by comparing
+    /// bytes, so there is no hash collision window, but changes of the source go unnoticed and no
+    /// checksum is available for a manifest.
+    #[structopt(possible_values = &ChecksumAlgorithm::variants(), case_insensitive = true, default_value="crc64", long, visible_alias = "checksum-algorithm")]
+    checksum: ChecksumAlgorithm,
+    /// Compute checksums on a separate thread while checking copies, so that reading the
+    /// devices is not stalled by hashing.
+    #[structopt(long)]
+    checksum_parallel_files: bool,
+    /// Split each block read from a file in chunks of 256KiB hashed in parallel on all cpus, for
+    /// fast devices where hashing is the bottleneck. Only `--checksum=crc64` can be split, and
+    /// only blocks of at least 512KiB are, see --block-size. Checksums are the same as without
+    /// this option.
+    #[structopt(long)]
+    checksum_parallel_chunks: bool,
+    /// Container format of DEST when SOURCE is a single file. `vhd` appends the footer of a
+    /// fixed size VHD image after the data, which is generated rather than copied.
+    #[structopt(possible_values = &DestFormat::variants(), case_insensitive = true, default_value="raw", long)]
+    dest_format: DestFormat,
+    /// Check the copy against this independent copy of SOURCE after the first copy, instead of
+    /// rereading SOURCE every round. Useful when SOURCE is slow to read, like tapes or network
+    /// shares. Its checksum must still match the one of SOURCE.
+    #[structopt(long, parse(from_os_str))]
+    reference: Option<PathBuf>,
+    /// Do not copy directories containing a file with this name, like `.nobackup`. Can be
+    /// repeated.
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    exclude_if_present: Vec<OsString>,
+    /// Do not copy the entries of SOURCE whose path relative to SOURCE matches this glob, like
+    /// `**/.cache` or `*.o`, nor what is below them, and do not remove their copies from an
+    /// existing DEST. Can be repeated.
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Do not copy regular files larger than this size, like `4G`.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = utils::parse_size))]
+    exclude_larger_than: Option<u64>,
+    /// Do not copy regular files smaller than this size, like `1M`.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = utils::parse_size))]
+    exclude_smaller_than: Option<u64>,
+    /// When a copy differs from the source, save the differing bytes of both to this directory,
+    /// at most 1MiB per file and round.
+    #[structopt(long, parse(from_os_str))]
+    checksum_on_mismatch_dump: Option<PathBuf>,
+    /// What to do when the device of DEST disappears during the copy: `fail`, `wait` for the
+    /// same device or file system to come back, or `remount` it with the cache management mode
+    /// and continue.
+    #[structopt(possible_values = &OnDisappear::variants(), case_insensitive = true, default_value="fail", long)]
+    on_disappear: OnDisappear,
+    /// After the first copy, only check a random subset of the files totalling about this
+    /// percentage of bytes, and fail if any of them differs from the source instead of fixing
+    /// the copy until it is correct.
+    #[structopt(long)]
+    verify_sample: Option<f64>,
+    /// Seed of the random choice of --verify-sample, to reproduce a previous spot check.
+    /// Random by default.
+    #[structopt(long)]
+    verify_seed: Option<u64>,
+    /// Accept that regular files of SOURCE are appended to during the copy, like growing logs:
+    /// only the length seen when a file is first copied must match its checksum, and what is
+    /// appended later is copied and checked in the next rounds.
+    #[structopt(long)]
+    checksum_resume_tolerant: bool,
+    /// Also cover the permissions of regular files by their checksum, as well as their owner and
+    /// modification time when they are in --preserve, so that a copy whose attributes drifted is
+    /// fixed. The copy gets the permissions of SOURCE. --verify-only and --verify-manifest need
+    /// the same --checksum-include-metadata and --preserve as the copy.
+    #[structopt(long)]
+    checksum_include_metadata: bool,
+    /// What to do with symlinks in SOURCE: `preserve` copies them as symlinks, `copy` copies
+    /// what they point to instead, and `skip` does not copy them.
+    #[structopt(possible_values = &LinkPolicy::variants(), case_insensitive = true, default_value="preserve", long)]
+    links: LinkPolicy,
+    /// Same as --links=copy, like `cp -L`: copy what symlinks in SOURCE point to, as regular
+    /// files or directories. Overrides --links.
+    #[structopt(short = "L", long)]
+    follow_symlinks: bool,
+    /// What to do with entries of an existing DEST which are not in SOURCE, or are in the way
+    /// of the copy, like a directory where SOURCE has a file, and with the end of files of DEST
+    /// longer than in SOURCE: `always` removes them, `never` keeps them, and `ask` asks on the
+    /// terminal for each of them.
+    #[structopt(possible_values = &DeletePolicy::variants(), case_insensitive = true, default_value="always", long)]
+    delete: DeletePolicy,
+    /// Shell command to run for each entry of the copy once it is verified. `{path}` and
+    /// `{checksum}` are replaced by the path of the copy and its checksum, already quoted.
+    /// Failures are reported at the end.
+    #[structopt(long)]
+    post_verify_command: Option<String>,
+    /// Write the copy of SOURCE, a single file, at this offset in bytes in DEST, for example
+    /// past a bootloader in a block device. With --mode=directio, it must be a multiple of the
+    /// logical block size.
+    #[structopt(long, default_value = "0")]
+    dest_offset: u64,
+    /// Clone the block device SOURCE to the block device DEST, which must be at least as large.
+    /// All the bytes of SOURCE are copied, then read back through the cache management mode.
+    #[structopt(long)]
+    whole_device: bool,
+    /// Only copy and check the bytes `start` to `start+len` of SOURCE, a single file, written
+    /// `start:len`. The checksum only covers this range. Together with --dest-offset, this
+    /// copies a partition of a disk image to a partition of a disk.
+    #[structopt(long)]
+    source_range: Option<ByteRange>,
+    /// Give the files and directories created by the copy these permissions instead of those
+    /// of SOURCE, like rsync's --chmod: comma separated octal or symbolic modes, like
+    /// `a+r,u+w`, which only apply to directories when prefixed by `D` and to other files when
+    /// prefixed by `F`, like `D755,F644`. Existing copies being fixed are not changed.
+    #[structopt(long)]
+    chmod: Option<ChmodSpec>,
+    /// Attributes of the source to give to the copy, separated by commas. `timestamps` copies
+    /// the access and modification times of files and symlinks, and fixes copies whose
+    /// modification time differs. `owner` copies the owner and group of all entries, and is
+    /// ignored with a warning when not running as root. `xattr` copies the extended attributes
+    /// of all entries, except the ones cccp stores itself.
+    #[structopt(long, possible_values = &Preserve::variants(), case_insensitive = true, use_delimiter = true)]
+    preserve: Vec<Preserve>,
+    /// Keep the files of SOURCE in the page cache after reading them. By default, their cache
+    /// is dropped with posix_fadvise(POSIX_FADV_DONTNEED) once they were read, so that copying
+    /// does not evict other data from the cache.
+    #[structopt(long)]
+    no_fadvise_dontneed_source: bool,
+    /// Do not change the access time of the files of SOURCE by reading them. They are opened
+    /// with O_NOATIME when owned by the user running cccp, and otherwise their access time is
+    /// restored after reading them, which changes their ctime.
+    #[structopt(long)]
+    atime_preserve: bool,
+    /// Before copying, write a marker file next to DEST, drop the cache, and check that it reads
+    /// back the same, to detect dying media which mount fine but silently discard writes.
+    #[structopt(long)]
+    target_readonly_check: bool,
+    /// When copying a file fails, for example because a read of SOURCE fails, move what was
+    /// written of its copy to `<name>.partial` in DEST to salvage it.
+    #[structopt(long)]
+    keep_partial_on_error: bool,
+    /// When an entry cannot be copied or fixed, for example because of bad sectors of SOURCE,
+    /// skip it, copy the rest, and list the skipped entries at the end with a non zero exit
+    /// status. By default, the first error aborts the copy.
+    #[structopt(long)]
+    keep_going: bool,
+    /// Resume an interrupted copy to DEST: regular files which already exist in DEST with the
+    /// size of their source, and are not older than it, are not compared to SOURCE right away.
+    /// Only SOURCE is read, and they are checked in the next round like the rest of the copy.
+    #[structopt(long)]
+    resume: bool,
+    /// Only report what would be copied, fixed or removed, without writing anything to DEST
+    /// nor dropping caches. Existing copies are compared to SOURCE, possibly through caches.
+    /// Fails if anything would be changed.
+    #[structopt(long)]
+    dry_run: bool,
+    /// With --resume, record the regular files of the copy which are verified in FILE, and do
+    /// not read them again when resuming with the same FILE. FILE is removed when the copy
+    /// completes.
+    #[structopt(long, value_name = "FILE", parse(from_os_str), requires = "resume")]
+    resume_state: Option<PathBuf>,
+    /// Do not copy anything, but write a file of this size next to DEST, like `64M`, drop the
+    /// cache, read it back, and print how many blocks differed and roughly how many rounds
+    /// copying SOURCE would take at this error rate.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = utils::parse_size))]
+    probe_reliability: Option<u64>,
+    /// Read each region of the copy this many times when checking it, and only compare the
+    /// last read. Some cheap USB controllers serve the first read of just written data from
+    /// their own memory, even after the cache of the kernel was dropped.
+    #[structopt(long, default_value = "1")]
+    verify_reread: u32,
+    /// Retry a read of SOURCE or DEST which fails, for example with EIO, this many times before
+    /// giving up. Errors tell the offset of the failed read, to locate bad sectors.
+    #[structopt(long, value_name = "N", default_value = "0")]
+    retry_block: u32,
+    /// When checking the copy, ask the kernel to read this much of it, like `8M`, ahead of what
+    /// is being compared, so that slow rotational drives keep reading while cccp compares.
+    /// Reads with --mode=directio bypass the page cache, so it cannot be used with it.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = utils::parse_size))]
+    readahead: Option<u64>,
+    /// Size of the buffer used to read and write files, like `512K` or `4M`. Larger buffers cut
+    /// the syscall overhead of fast or spinning disks. With --mode=directio, it must be a
+    /// multiple of 512 bytes.
+    #[structopt(long, default_value = "32K", parse(try_from_str = utils::parse_size))]
+    block_size: u64,
+    /// Limit the throughput of copying and checking to this many bytes per second, like `10M`,
+    /// for example to keep the machine responsive when the copy shares a bus with its disk.
+    #[structopt(long, parse(try_from_str = utils::parse_size))]
+    bwlimit: Option<u64>,
+    /// Limit the throughput of checking the copy to this many bytes per second, instead of
+    /// --bwlimit, on devices where reading back just written data is especially slow.
+    #[structopt(long, parse(try_from_str = utils::parse_size))]
+    verify_bwlimit: Option<u64>,
+    /// Create all directories of the copy in a first pass, shallowest first, before copying
+    /// any file, instead of creating each one when it is reached.
+    #[structopt(long)]
+    preallocate_dirs: bool,
+    /// Only copy the entries of SOURCE listed in this file, `-` for stdin, one path relative to
+    /// SOURCE per line, and the directories containing them. Listed directories are copied
+    /// without their content.
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        conflicts_with_all = &["exclude", "exclude-if-present", "exclude-larger-than", "exclude-smaller-than", "preallocate-dirs"]
+    )]
+    files_from: Option<PathBuf>,
+    /// Paths in the file of --files-from are separated by null bytes instead of newlines, for
+    /// names containing newlines.
+    #[structopt(long, requires = "files-from")]
+    null: bool,
+    /// With --mode=vm, drop the page cache of all file systems instead of only the one of the
+    /// copy. This needs root privileges and slows down the whole system, but also drops
+    /// metadata caches like directory entries.
+    #[structopt(long)]
+    global_drop_caches: bool,
+    /// Once a regular file of the copy is verified, store its checksum in its
+    /// `user.cccp.checksum` extended attribute, for later checks with --verify-only.
+    #[structopt(long)]
+    checksum_store_in_xattr: bool,
+    /// Once the whole copy is verified, store the checksum of the tree in the
+    /// `user.cccp.tree` extended attribute of DEST, for later checks with --verify-only.
+    #[structopt(long)]
+    checksum_tree_root_xattr: bool,
+    /// Do not copy anything, but tell which --mode can work for DEST, or SOURCE if DEST is
+    /// omitted, and why.
+    #[structopt(long)]
+    list_modes: bool,
+    /// Do not copy anything, but check that SOURCE and the files below it still have the
+    /// checksums stored by --checksum-store-in-xattr and --checksum-tree-root-xattr.
+    #[structopt(long, conflicts_with = "DEST")]
+    verify_only: bool,
+    /// Do not copy anything, but check that the entries below SOURCE listed in this manifest
+    /// still have the listed checksums, and report each of them. Lines of the manifest are
+    /// `<algorithm>:<checksum>[:<size>]  <path relative to SOURCE>`.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["DEST", "verify-only"])]
+    verify_manifest: Option<PathBuf>,
+    /// Once the copy is correct, write to this file a manifest of the checksums of all its
+    /// entries, relative to DEST, to check it later with --verify-manifest.
+    #[structopt(long, parse(from_os_str))]
+    write_manifest: Option<PathBuf>,
+    /// Write the paths of --write-manifest relative to this directory instead of DEST, for the
+    /// tool which will check it from there. It must contain the copy, or the source to list the
+    /// paths of the files it was copied from.
+    #[structopt(long, parse(from_os_str), requires = "write-manifest")]
+    manifest_relative_to: Option<PathBuf>,
+    /// With --mode=umount, remount the destination file system read-write if it is mounted
+    /// read-only, instead of failing.
+    #[structopt(long)]
+    remount_rw: bool,
+    /// With --mode=usbreset, how long to wait for the device to come back after each reset,
+    /// like `90s` or `5m`.
+    #[structopt(long, default_value = "60s", parse(try_from_str = utils::parse_duration))]
+    device_timeout: std::time::Duration,
+}
+
+/// Returns the name of the copy of `source` in `target` if it is copied into it like with cp,
+/// because `target` is an existing directory. It is not when `input`, the path given for
+/// `source`, ends with a slash, or with `no_target_directory`: `target` is the copy itself.
+fn name_in_target(
+    input: &Path,
+    source: &Path,
+    target: &Path,
+    no_target_directory: bool,
+) -> anyhow::Result<Option<OsString>> {
+    let input = input.as_os_str().as_bytes();
+    let content = input.ends_with(b"/") || input.ends_with(b"/.") || input == b".";
+    if no_target_directory || content || FileKind::of_path(target).ok() != Some(FileKind::Directory)
+    {
+        return Ok(None);
+    }
+    let name = source.file_name().with_context(|| {
+        format!(
+            "{} has no name to copy it into {}",
+            source.display(),
+            target.display()
+        )
+    })?;
+    Ok(Some(name.to_os_string()))
 }
 
 /// Attempts to canonicalizes the input path, but allows the last component of the path to be a broken symlink
@@ -116,6 +481,12 @@ fn canonicalize(path: &Path, must_exist: bool) -> anyhow::Result<PathBuf> {
     // canonicalize the parent only
     let canon = match (path.parent(), path.file_name()) {
         (Some(p), Some(f)) => {
+            // the parent of a relative path of a single component is empty
+            let p = if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            };
             let mut p2 = p
                 .canonicalize()
                 .with_context(|| format!("Canonicalizing parent directory {}", p.display()))?;
@@ -145,6 +516,10 @@ fn test_canonicalize() {
     p.push("doesnotexist!");
     assert!(p2.is_absolute());
     assert_eq!(p, p2);
+    assert_eq!(
+        canonicalize(&PathBuf::from("doesnotexist!"), false).unwrap(),
+        p2
+    );
     assert_eq!(
         canonicalize(&PathBuf::from("/"), true).unwrap(),
         PathBuf::from("/")
@@ -153,20 +528,551 @@ fn test_canonicalize() {
     assert!(canonicalize(&PathBuf::from("/doesnotexist!"), true).is_err());
 }
 
+/// Formats a failure of `run` as a single line JSON object.
+fn error_to_json(error: &anyhow::Error, opt: &Opt) -> String {
+    let chain: Vec<String> = error
+        .chain()
+        .map(|e| utils::json_string(&e.to_string()))
+        .collect();
+    let (source, dest) = match opt.command.as_ref() {
+        Some(Command::Verify { source, dest }) => (Some(source), Some(dest)),
+        None => (opt.input.as_ref(), opt.output.as_ref()),
+    };
+    let json_path = |path: Option<&PathBuf>| {
+        path.map_or_else(
+            || "null".to_string(),
+            |path| utils::json_string(&path.to_string_lossy()),
+        )
+    };
+    format!(
+        r#"{{"error":{},"chain":[{}],"mode":{},"source":{},"dest":{}}}"#,
+        utils::json_string(&error.to_string()),
+        chain.join(","),
+        utils::json_string(&opt.mode.to_string()),
+        json_path(source),
+        json_path(dest),
+    )
+}
+
+/// Makes the paths of options absolute, as `run` changes the current directory to `/` before
+/// opening them.
+fn resolve_paths(opt: &mut Opt) -> anyhow::Result<()> {
+    if let Some(path) = opt.write_manifest.as_mut() {
+        *path = canonicalize(path, false)
+            .with_context(|| format!("Canonicalizing --write-manifest {}", path.display()))?;
+    }
+    if let Some(path) = opt.manifest_relative_to.as_mut() {
+        *path = canonicalize(path, true)
+            .with_context(|| format!("Canonicalizing --manifest-relative-to {}", path.display()))?;
+    }
+    if let Some(path) = opt.verify_manifest.as_mut() {
+        *path = canonicalize(path, true)
+            .with_context(|| format!("Canonicalizing --verify-manifest {}", path.display()))?;
+    }
+    if let Some(path) = opt.resume_state.as_mut() {
+        *path = canonicalize(path, false)
+            .with_context(|| format!("Canonicalizing --resume-state {}", path.display()))?;
+    }
+    if let Some(path) = opt.files_from.as_mut() {
+        // `-` is stdin
+        if path != Path::new("-") {
+            *path = canonicalize(path, true)
+                .with_context(|| format!("Canonicalizing --files-from {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
-    let mut cache_manager = match opt.mode {
-        Mode::Vm => Box::new(cache::vm::PageCacheManager::default()) as Box<dyn CacheManager>,
+    let mut opt = Opt::from_args();
+    progress::init_logger(opt.verbose)?;
+    if opt.follow_symlinks {
+        opt.links = LinkPolicy::Copy;
+    }
+    let res = resolve_paths(&mut opt)
+        .and_then(|()| choose_mode(&mut opt))
+        .and_then(|choice| run(&opt, choice));
+    if let (Err(e), LogFormat::Json) = (&res, opt.log_format) {
+        eprintln!("{}", error_to_json(e, &opt));
+        std::process::exit(1);
+    }
+    res
+}
+
+/// Creates the `Progress` chosen by --progress-format.
+fn new_progress(opt: &Opt) -> anyhow::Result<Progress> {
+    let progress = match (opt.progress_format, opt.progress_fd) {
+        (ProgressFormat::Bars, _)
+            if opt.quiet || !nix::unistd::isatty(libc::STDERR_FILENO).unwrap_or(false) =>
+        {
+            Progress::quiet()
+        }
+        (ProgressFormat::Bars, _) => Progress::new(),
+        (ProgressFormat::Json, None) => Progress::json(Box::new(std::io::stderr())),
+        (ProgressFormat::Json, Some(fd)) => {
+            nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD)
+                .with_context(|| format!("--progress-fd {} is not an open file descriptor", fd))?;
+            // nothing else uses this file descriptor
+            let file = unsafe { std::fs::File::from_raw_fd(fd) };
+            Progress::json(Box::new(file))
+        }
+    };
+    Ok(progress)
+}
+
+fn new_cache_manager(mode: Mode, opt: &Opt) -> anyhow::Result<Box<dyn CacheManager>> {
+    Ok(match mode {
+        Mode::Vm => Box::new(cache::vm::PageCacheManager::new(opt.global_drop_caches)),
         Mode::DirectIO => Box::new(cache::directio::DirectIOCacheManager::default()),
-        Mode::Umount => Box::new(cache::umount::UmountCacheManager::default()),
-        Mode::UsbReset => Box::new(cache::usbreset::UsbResetCacheManager::default()),
+        Mode::Umount => Box::new(cache::umount::UmountCacheManager::new(opt.remount_rw)),
+        Mode::UsbReset => Box::new(cache::usbreset::UsbResetCacheManager::new(
+            opt.device_timeout,
+        )),
+        Mode::Fuse => Box::new(cache::fuse::FuseCacheManager::default()),
+        Mode::BlkFlush => Box::new(cache::blkflush::BlkFlushCacheManager::default()),
+        Mode::Fadvise => Box::new(cache::fadvise::FadviseCacheManager::default()),
+        Mode::Auto => anyhow::bail!("--mode=auto must be replaced by a mode with choose_mode"),
+    })
+}
+
+/// Replaces --mode=auto by the first of `AUTO_MODES` whose permission check passes for DEST.
+/// Returns which one it chose, and why the modes before it were skipped, to report it.
+fn choose_mode(opt: &mut Opt) -> anyhow::Result<Option<String>> {
+    if !matches!(opt.mode, Mode::Auto) || opt.list_modes {
+        return Ok(None);
+    }
+    let path = match opt.command.as_ref() {
+        Some(Command::Verify { dest, .. }) => dest,
+        None => opt
+            .output
+            .as_ref()
+            .or(opt.input.as_ref())
+            .context("SOURCE is required")?,
     };
-    let source_ = canonicalize(&opt.input, true)
-        .with_context(|| format!("Canonicalizing input path {}", opt.input.display()))?;
+    let path = canonicalize(path, false)
+        .with_context(|| format!("Canonicalizing path {}", path.display()))?;
+    let mut failures: Vec<String> = Vec::new();
+    for &mode in AUTO_MODES.iter() {
+        match new_cache_manager(mode, opt)?.permission_check(&path) {
+            Ok(()) => {
+                let mut choice =
+                    format!("--mode=auto chose --mode={} for {}", mode, path.display());
+                for failure in &failures {
+                    choice.push_str("\nskipped ");
+                    choice.push_str(failure);
+                }
+                opt.mode = mode;
+                return Ok(Some(choice));
+            }
+            Err(e) => failures.push(format!("--mode={}: {:#}", mode, e)),
+        }
+    }
+    anyhow::bail!(
+        "--mode=auto found no usable mode for {}:\n{}",
+        path.display(),
+        failures.join("\n")
+    )
+}
+
+/// Runs `cccp verify SOURCE DEST`.
+fn verify(
+    cache_manager: &mut dyn CacheManager,
+    progress: Progress,
+    options: CopyOptions,
+    source: &Path,
+    dest: &Path,
+) -> anyhow::Result<()> {
+    let source = canonicalize(source, true)
+        .with_context(|| format!("Canonicalizing input path {}", source.display()))?;
+    let dest = canonicalize(dest, true)
+        .with_context(|| format!("Canonicalizing output path {}", dest.display()))?;
+    std::env::set_current_dir("/").context("chdir(/)")?;
+    cache_manager.permission_check(&dest).with_context(|| {
+        format!(
+            "Checking permissions for cache management with {}",
+            cache_manager.name()
+        )
+    })?;
+    cccp::verify_copy(cache_manager, progress, options, &source, &dest)
+}
+
+/// Prints which cache management modes can work for DEST, or SOURCE if there is no DEST.
+fn list_modes(opt: &Opt) -> anyhow::Result<()> {
+    let path = opt
+        .output
+        .as_ref()
+        .or(opt.input.as_ref())
+        .context("SOURCE is required")?;
+    let path = canonicalize(path, false)
+        .with_context(|| format!("Canonicalizing path {}", path.display()))?;
+    for name in Mode::variants().iter() {
+        let mode: Mode = name.parse().map_err(anyhow::Error::msg)?;
+        if let Mode::Auto = mode {
+            continue;
+        }
+        let capabilities = new_cache_manager(mode, opt)?
+            .probe(&path)
+            .with_context(|| format!("Probing --mode={} for {}", mode, path.display()))?;
+        println!(
+            "{}: {}",
+            mode,
+            if capabilities.usable() {
+                "usable"
+            } else {
+                "unusable"
+            }
+        );
+        for line in capabilities.problems.iter().chain(&capabilities.notes) {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Runs cccp as `opt` says. `mode_choice` is what `choose_mode` chose for --mode=auto.
+fn run(opt: &Opt, mode_choice: Option<String>) -> anyhow::Result<()> {
+    if let Some(priority) = opt.ioprio {
+        // before spawning any thread, so that they inherit it
+        utils::set_io_priority(priority).context("Setting the I/O scheduling class")?;
+    }
+    if opt.list_modes {
+        return list_modes(opt);
+    }
+    let block_size = opt.block_size as usize;
+    anyhow::ensure!(
+        opt.block_size >= 512,
+        "--block-size={} is less than 512 bytes",
+        opt.block_size
+    );
+    anyhow::ensure!(opt.readahead != Some(0), "--readahead must not be zero");
+    anyhow::ensure!(
+        !opt.checksum_parallel_chunks || opt.checksum == ChecksumAlgorithm::Crc64,
+        "--checksum-parallel-chunks only works with --checksum=crc64"
+    );
+    checksum::set_parallel_chunks(opt.checksum_parallel_chunks);
+    if let Mode::DirectIO = opt.mode {
+        anyhow::ensure!(
+            opt.readahead.is_none(),
+            "--readahead cannot be used with --mode={}, which bypasses the page cache",
+            opt.mode
+        );
+        anyhow::ensure!(
+            opt.block_size % 512 == 0,
+            "--block-size={} must be a multiple of 512 bytes with --mode={}",
+            opt.block_size,
+            opt.mode
+        );
+    }
+    anyhow::ensure!(opt.bwlimit != Some(0), "--bwlimit must not be zero");
+    anyhow::ensure!(opt.max_rounds != Some(0), "--max-rounds must not be zero");
+    anyhow::ensure!(
+        opt.probe_reliability != Some(0),
+        "--probe-reliability must not be zero"
+    );
+    anyhow::ensure!(
+        opt.progress_fd.is_none() || opt.progress_format == ProgressFormat::Json,
+        "--progress-fd requires --progress-format=json"
+    );
+    anyhow::ensure!(
+        opt.verify_bwlimit != Some(0),
+        "--verify-bwlimit must not be zero"
+    );
+    // for --verify-only and --verify-manifest, which do not copy
+    let verify_throttle = opt.verify_bwlimit.or(opt.bwlimit).map(Throttle::new);
+    let verify_metadata = if opt.checksum_include_metadata {
+        Some(copy::MetadataFields {
+            owner: opt.preserve.contains(&Preserve::Owner),
+            mtime: opt.preserve.contains(&Preserve::Timestamps),
+        })
+    } else {
+        None
+    };
+    let mut cache_manager = new_cache_manager(opt.mode, opt)?;
+    let mut progress = new_progress(opt)?;
+    if let (Some(choice), false) = (mode_choice, opt.quiet) {
+        progress.info(choice);
+    }
+    if let Some(Command::Verify { source, dest }) = opt.command.as_ref() {
+        let options = CopyOptions {
+            checksum: opt.checksum,
+            links: opt.links,
+            block_size,
+            verify_reads: opt.verify_reread,
+            retry_block: opt.retry_block,
+            readahead: opt.readahead,
+            preserve_atime: opt.atime_preserve,
+            drop_source_cache: !opt.no_fadvise_dontneed_source,
+            verify_throttle,
+            ..CopyOptions::default()
+        };
+        return verify(&mut *cache_manager, progress, options, source, dest);
+    }
+    let input = opt.input.as_ref().context("SOURCE is required")?;
+    let source_ = canonicalize(input, true)
+        .with_context(|| format!("Canonicalizing input path {}", input.display()))?;
     let source = &source_;
-    let target_ = canonicalize(&opt.output, false)
-        .with_context(|| format!("Canonicalizing output path {}", opt.output.display()))?;
-    let target = &target_;
+    if opt.verify_only {
+        std::env::set_current_dir("/").context("chdir(/)")?;
+        cache_manager.permission_check(source).with_context(|| {
+            format!(
+                "Checking permissions for cache management mode --mode={}",
+                opt.mode
+            )
+        })?;
+        return xattr::verify_tree(
+            &mut *cache_manager,
+            progress,
+            source,
+            block_size,
+            verify_throttle.as_ref(),
+            verify_metadata,
+        );
+    }
+    if let Some(manifest) = opt.verify_manifest.as_ref() {
+        std::env::set_current_dir("/").context("chdir(/)")?;
+        cache_manager.permission_check(source).with_context(|| {
+            format!(
+                "Checking permissions for cache management mode --mode={}",
+                opt.mode
+            )
+        })?;
+        return manifest::verify_manifest(
+            &mut *cache_manager,
+            progress,
+            manifest,
+            source,
+            block_size,
+            verify_throttle.as_ref(),
+            verify_metadata,
+            opt.links == LinkPolicy::Copy,
+        );
+    }
+    let mut inputs = vec![input];
+    let mut output = opt.output.as_ref().context("DEST is required")?;
+    if let Some((last, more)) = opt.more.split_last() {
+        inputs.push(output);
+        inputs.extend(more);
+        output = last;
+    }
+    let mut target = canonicalize(output, false)
+        .with_context(|| format!("Canonicalizing output path {}", output.display()))?;
+    let mut sources = vec![source_.clone()];
+    for input in &inputs[1..] {
+        sources.push(
+            canonicalize(input, true)
+                .with_context(|| format!("Canonicalizing input path {}", input.display()))?,
+        );
+    }
+    // for each source, the name of its copy in DEST if it is copied into it
+    let mut names = Vec::new();
+    for (input, source) in inputs.iter().zip(&sources) {
+        // for example when DEST is a symlink to SOURCE: the copy would be copied into itself
+        anyhow::ensure!(
+            &target != source,
+            "source {} and destination {} resolve to the same path {}",
+            input.display(),
+            output.display(),
+            source.display()
+        );
+        let name = name_in_target(input, source, &target, opt.no_target_directory)?;
+        if let Some(name) = name.as_ref() {
+            anyhow::ensure!(
+                &target.join(name) != source,
+                "{} would be copied onto itself into {}",
+                input.display(),
+                output.display()
+            );
+        }
+        names.push(name);
+    }
+    if let [name] = names.as_mut_slice() {
+        // the copy of the single source is the target
+        if let Some(name) = name.take() {
+            target.push(name);
+        }
+    }
+    if sources.len() > 1 {
+        anyhow::ensure!(
+            FileKind::of_path(&target).ok() == Some(FileKind::Directory),
+            "{} paths were given, so the last one {} must be an existing directory to copy the others into",
+            sources.len() + 1,
+            output.display()
+        );
+        anyhow::ensure!(
+            opt.reference.is_none()
+                && opt.source_range.is_none()
+                && opt.dest_offset == 0
+                && opt.dest_format != DestFormat::Vhd
+                && !opt.whole_device
+                && !opt.checksum_tree_root_xattr
+                && opt.probe_reliability.is_none()
+                && opt.files_from.is_none(),
+            "--reference, --source-range, --dest-offset, --dest-format=vhd, --whole-device, --checksum-tree-root-xattr, --probe-reliability and --files-from need a single SOURCE"
+        );
+        let mut seen = std::collections::HashSet::new();
+        for (input, name) in inputs.iter().zip(&names) {
+            // DEST would be made identical to each of them in turn
+            let name = name.as_ref().with_context(|| {
+                format!(
+                    "with several sources, {} cannot end with a slash to copy its content",
+                    input.display()
+                )
+            })?;
+            anyhow::ensure!(
+                seen.insert(name),
+                "several sources are named {:?}, their copies would overwrite each other",
+                name
+            );
+        }
+    }
+    let reference_ = match opt.reference.as_ref() {
+        Some(r) => Some(
+            canonicalize(r, true)
+                .with_context(|| format!("Canonicalizing reference path {}", r.display()))?,
+        ),
+        None => None,
+    };
+    let mismatch_dump = match opt.checksum_on_mismatch_dump.as_ref() {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).with_context(|| {
+                format!("Creating directory {} to save mismatches", dir.display())
+            })?;
+            Some(
+                dir.canonicalize()
+                    .with_context(|| format!("Canonicalizing {}", dir.display()))?,
+            )
+        }
+        None => None,
+    };
+    let vhd_footer = match opt.dest_format {
+        DestFormat::Raw | DestFormat::Img => None,
+        DestFormat::Vhd => {
+            anyhow::ensure!(
+                matches!(
+                    FileKind::of_path(source)?,
+                    FileKind::Regular | FileKind::Device
+                ),
+                "--dest-format={} needs SOURCE {} to be a single file or block device",
+                opt.dest_format,
+                source.display()
+            );
+            anyhow::ensure!(
+                !matches!(FileKind::of_path(&target), Ok(FileKind::Device)),
+                "--dest-format={} writes an image file, DEST {} cannot be a block device",
+                opt.dest_format,
+                target.display()
+            );
+            let meta = std::fs::metadata(source)
+                .with_context(|| format!("stat({}) for its VHD footer", source.display()))?;
+            Some(VhdFooter::new(&meta))
+        }
+    };
+    if opt.dest_offset != 0 {
+        anyhow::ensure!(
+            matches!(
+                FileKind::of_path(source)?,
+                FileKind::Regular | FileKind::Device
+            ),
+            "--dest-offset needs SOURCE {} to be a single file or block device",
+            source.display()
+        );
+        anyhow::ensure!(
+            vhd_footer.is_none(),
+            "--dest-offset cannot be used with --dest-format={}",
+            opt.dest_format
+        );
+        if let Mode::DirectIO = opt.mode {
+            let alignment = match FileKind::of_path(&target) {
+                Ok(FileKind::Device) => {
+                    let device = std::fs::File::open(&target).with_context(|| {
+                        format!("opening destination block device {}", target.display())
+                    })?;
+                    utils::block_sizes(&device)
+                        .with_context(|| {
+                            format!("getting the block sizes of {}", target.display())
+                        })?
+                        .0
+                }
+                // the logical block size of the underlying device is at most a page
+                _ => 4096,
+            };
+            anyhow::ensure!(
+                opt.dest_offset % alignment == 0,
+                "--dest-offset={} must be a multiple of {} bytes with --mode={}",
+                opt.dest_offset,
+                alignment,
+                opt.mode
+            );
+        }
+    }
+    if let Some(range) = opt.source_range {
+        let source_size = match FileKind::of_path(source)? {
+            FileKind::Regular => std::fs::metadata(source)
+                .with_context(|| format!("stat({}) to get its size", source.display()))?
+                .len(),
+            FileKind::Device => utils::device_size(source)?,
+            _ => anyhow::bail!(
+                "--source-range needs SOURCE {} to be a single file or block device",
+                source.display()
+            ),
+        };
+        anyhow::ensure!(
+            range.start + range.len <= source_size,
+            "--source-range={}:{} ends past the end of SOURCE {}, which has {} bytes",
+            range.start,
+            range.len,
+            source.display(),
+            source_size
+        );
+        anyhow::ensure!(
+            !opt.checksum_resume_tolerant,
+            "--source-range cannot be used with --checksum-resume-tolerant"
+        );
+    }
+    if matches!(FileKind::of_path(&target), Ok(FileKind::Device)) {
+        let size = utils::device_size(&target)?;
+        // card readers without a card, for example
+        anyhow::ensure!(
+            size != 0,
+            "destination block device {} reports 0 bytes, is a medium inserted?",
+            target.display()
+        );
+        let source_size = match FileKind::of_path(source)? {
+            FileKind::Regular => Some(
+                std::fs::metadata(source)
+                    .with_context(|| format!("stat({}) to get its size", source.display()))?
+                    .len(),
+            ),
+            FileKind::Device => Some(utils::device_size(source)?),
+            _ => None,
+        };
+        if let Some(source_size) = source_size {
+            // fail now rather than with ENOSPC or EIO in the middle of the copy
+            let needed = opt.source_range.map_or(source_size, |range| range.len) + opt.dest_offset;
+            anyhow::ensure!(
+                size >= needed,
+                "SOURCE {} needs {} ({} bytes), but DEST {} is only {} ({} bytes)",
+                source.display(),
+                indicatif::HumanBytes(needed),
+                needed,
+                target.display(),
+                indicatif::HumanBytes(size),
+                size
+            );
+        }
+    }
+    if opt.whole_device {
+        anyhow::ensure!(
+            FileKind::of_path(source)? == FileKind::Device,
+            "--whole-device needs SOURCE {} to be a block device",
+            source.display()
+        );
+        anyhow::ensure!(
+            matches!(FileKind::of_path(&target), Ok(FileKind::Device)),
+            "--whole-device needs DEST {} to be an existing block device",
+            target.display()
+        );
+    }
     if target.is_absolute() && source.is_absolute() {
         // this prevents trying to unmount .
         std::env::set_current_dir("/").context("chdir(/)")?;
@@ -177,39 +1083,301 @@ fn main() -> anyhow::Result<()> {
             opt.mode
         )
     })?;
-    let mut progress = Progress::new();
-    let mut obligations = first_copy(&*cache_manager, &mut progress, source, target)
-        .context("during initial copy")?;
-    // corrupt(&opt.output)?;
-    while !obligations.is_empty() {
-        progress.syncing();
-        if let Some(Replacement { before, after }) = cache_manager
-            .drop_cache(&target)
-            .with_context(|| format!("Dropping cache below {}", target.display()))?
-        {
-            let mut f = change_prefixes(before.as_path(), after.as_path());
-            for o in obligations.iter_mut() {
-                o.dest = f(o.dest.as_path());
-            }
+    anyhow::ensure!(
+        !(opt.checksum_resume_tolerant && vhd_footer.is_some()),
+        "--checksum-resume-tolerant cannot be used with --dest-format={}, the footer would be overwritten",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        !(opt.checksum_store_in_xattr && opt.checksum == ChecksumAlgorithm::None),
+        "--checksum-store-in-xattr cannot be used with --checksum=none"
+    );
+    anyhow::ensure!(
+        !(opt.checksum_store_in_xattr && vhd_footer.is_some()),
+        "--checksum-store-in-xattr cannot be used with --dest-format={}, the checksum would not cover the footer",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        !(opt.checksum_tree_root_xattr && opt.checksum == ChecksumAlgorithm::None),
+        "--checksum-tree-root-xattr cannot be used with --checksum=none"
+    );
+    anyhow::ensure!(
+        !(opt.checksum_tree_root_xattr && vhd_footer.is_some()),
+        "--checksum-tree-root-xattr cannot be used with --dest-format={}, the checksum would not cover the footer",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        !opt.checksum_tree_root_xattr || opt.exclude_if_present.is_empty(),
+        "--checksum-tree-root-xattr cannot be used with --exclude-if-present, the checksums of directories would cover excluded entries"
+    );
+    anyhow::ensure!(
+        !opt.checksum_tree_root_xattr
+            || (opt.exclude_larger_than.is_none() && opt.exclude_smaller_than.is_none()),
+        "--checksum-tree-root-xattr cannot be used with --exclude-larger-than or --exclude-smaller-than, the checksums of directories would cover excluded entries"
+    );
+    anyhow::ensure!(
+        !opt.checksum_tree_root_xattr || opt.exclude.is_empty(),
+        "--checksum-tree-root-xattr cannot be used with --exclude, the checksums of directories would cover excluded entries"
+    );
+    let exclude = if opt.exclude.is_empty() {
+        None
+    } else {
+        let mut patterns = globset::GlobSetBuilder::new();
+        for pattern in &opt.exclude {
+            patterns.add(
+                globset::Glob::new(pattern)
+                    .with_context(|| format!("invalid --exclude={}", pattern))?,
+            );
         }
-        let total_size = obligations.iter().map(|o| o.size).sum();
-        progress.next_round(total_size);
-        obligations.retain(|obligation| {
-            let mut checksum = Some(obligation.checksum);
-            copy::fix_path(
-                &*cache_manager,
-                &progress,
-                &obligation.source,
-                &obligation.dest,
-                &mut checksum,
-            )
-            .context("while fixing copy")
-            .unwrap()
-        });
-        if opt.once && !obligations.is_empty() {
-            anyhow::bail!("Still files to fix: {:?}", &obligations);
+        // the fix loop compares the copy to the reference instead of the source
+        let roots = sources.iter().chain(reference_.as_ref()).cloned().collect();
+        Some(copy::Exclude {
+            roots,
+            patterns: patterns.build().context("compiling --exclude patterns")?,
+        })
+    };
+    let files = match opt.files_from.as_ref() {
+        None => None,
+        Some(path) => {
+            anyhow::ensure!(
+                !opt.checksum_tree_root_xattr,
+                "--checksum-tree-root-xattr cannot be used with --files-from, the checksums of directories would cover entries which are not copied"
+            );
+            anyhow::ensure!(
+                sources
+                    .iter()
+                    .all(|source| FileKind::of_path(source).ok() == Some(FileKind::Directory)),
+                "--files-from needs SOURCE to be a directory"
+            );
+            Some(read_files_from(path, opt.null)?)
         }
+    };
+    anyhow::ensure!(
+        !(opt.write_manifest.is_some() && (vhd_footer.is_some() || opt.dest_offset != 0)),
+        "--write-manifest lists the checksums of whole files, it cannot be used with --dest-format={} or --dest-offset",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        opt.write_manifest.is_none() || opt.checksum != ChecksumAlgorithm::None,
+        "--write-manifest cannot be used with --checksum=none"
+    );
+    // path of the copy in --write-manifest
+    let manifest_prefix = match opt.manifest_relative_to.as_ref() {
+        None => PathBuf::new(),
+        Some(dir) => target
+            .strip_prefix(dir)
+            .ok()
+            .or_else(|| match sources.as_slice() {
+                [source] => source.strip_prefix(dir).ok(),
+                _ => None,
+            })
+            .with_context(|| {
+                format!(
+                    "--manifest-relative-to {} contains neither DEST {} nor a single SOURCE",
+                    dir.display(),
+                    target.display()
+                )
+            })?
+            .to_path_buf(),
+    };
+    anyhow::ensure!(
+        !(opt.write_manifest.is_some() && opt.verify_sample.is_some()),
+        "--write-manifest needs the whole copy to be checked, it cannot be used with --verify-sample"
+    );
+    anyhow::ensure!(
+        !(opt.checksum_tree_root_xattr && opt.verify_sample.is_some()),
+        "--checksum-tree-root-xattr needs the whole copy to be checked, it cannot be used with --verify-sample"
+    );
+    anyhow::ensure!(
+        !(opt.resume
+            && (vhd_footer.is_some()
+                || opt.dest_offset != 0
+                || opt.source_range.is_some()
+                || opt.checksum_resume_tolerant
+                || opt.checksum_include_metadata)),
+        "--resume compares the size of whole files, it cannot be used with --dest-format={}, --dest-offset, --source-range, --checksum-resume-tolerant or --checksum-include-metadata",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        opt.resume_state.is_none() || opt.checksum != ChecksumAlgorithm::None,
+        "--resume-state cannot be used with --checksum=none"
+    );
+    anyhow::ensure!(
+        !(opt.dry_run
+            && (vhd_footer.is_some()
+                || opt.target_readonly_check
+                || opt.probe_reliability.is_some()
+                || opt.resume_state.is_some()
+                || opt.write_manifest.is_some()
+                || opt.checksum_on_mismatch_dump.is_some())),
+        "--dry-run writes nothing, it cannot be used with --dest-format={}, --target-readonly-check, --probe-reliability, --resume-state, --write-manifest or --checksum-on-mismatch-dump",
+        opt.dest_format
+    );
+    anyhow::ensure!(
+        opt.delete != DeletePolicy::Ask || nix::unistd::isatty(libc::STDIN_FILENO).unwrap_or(false),
+        "--delete=ask needs a terminal to ask on"
+    );
+    anyhow::ensure!(opt.verify_reread != 0, "--verify-reread must be at least 1");
+    anyhow::ensure!(
+        !(opt.double_read && opt.checksum == ChecksumAlgorithm::None),
+        "--double-read compares checksums, so it cannot be used with --checksum=none"
+    );
+    let mut preserve_owner = opt.preserve.contains(&Preserve::Owner);
+    if preserve_owner && !nix::unistd::geteuid().is_root() {
+        progress.warn("--preserve=owner needs root, the owner of copies is not preserved");
+        preserve_owner = false;
     }
-    progress.done();
-    Ok(())
+    anyhow::ensure!(
+        !(opt.checksum_include_metadata && opt.checksum == ChecksumAlgorithm::None),
+        "--checksum-include-metadata cannot be used with --checksum=none"
+    );
+    anyhow::ensure!(
+        !(opt.checksum_include_metadata && opt.checksum_resume_tolerant),
+        "--checksum-include-metadata cannot be used with --checksum-resume-tolerant, appending to a file changes its modification time"
+    );
+    let options = CopyOptions {
+        double_read: opt.double_read,
+        sparse: opt.sparse,
+        checksum: opt.checksum,
+        parallel_hashing: opt.checksum_parallel_files,
+        vhd_footer,
+        exclude_if_present: opt.exclude_if_present.clone(),
+        exclude,
+        exclude_larger_than: opt.exclude_larger_than,
+        exclude_smaller_than: opt.exclude_smaller_than,
+        mismatch_dump,
+        preallocate_dirs: opt.preallocate_dirs,
+        files,
+        append_tolerant: opt.checksum_resume_tolerant,
+        links: opt.links,
+        delete: opt.delete,
+        dest_offset: opt.dest_offset,
+        source_range: opt.source_range,
+        chmod: opt.chmod.clone(),
+        verify_reads: opt.verify_reread,
+        retry_block: opt.retry_block,
+        readahead: opt.readahead,
+        block_size,
+        preserve_atime: opt.atime_preserve,
+        keep_partial: opt.keep_partial_on_error,
+        read_only: opt.dry_run,
+        checksum_metadata: verify_metadata.map(|fields| copy::MetadataFields {
+            owner: preserve_owner,
+            ..fields
+        }),
+        throttle: opt.bwlimit.map(Throttle::new),
+        verify_throttle: opt.verify_bwlimit.map(Throttle::new),
+        drop_source_cache: !opt.no_fadvise_dontneed_source,
+        preserve_timestamps: opt.preserve.contains(&Preserve::Timestamps),
+        preserve_owner,
+        preserve_xattrs: opt.preserve.contains(&Preserve::Xattr),
+    };
+    if let Some(percent) = opt.verify_sample {
+        anyhow::ensure!(
+            (0. ..=100.).contains(&percent),
+            "--verify-sample={} is not a percentage",
+            percent
+        );
+    }
+    if opt.target_readonly_check {
+        // the nearest directory which will hold the copy
+        let dir = match FileKind::of_path(&target) {
+            Ok(FileKind::Directory) => target.clone(),
+            Ok(FileKind::Device) => anyhow::bail!(
+                "--target-readonly-check needs DEST on a file system, {} is a block device",
+                target.display()
+            ),
+            _ => target.parent().unwrap_or(&target).to_path_buf(),
+        };
+        if let Some(replacement) = copy::check_writes_persist(&mut *cache_manager, &progress, &dir)
+            .context("Checking that DEST persists writes")?
+        {
+            target = change_prefixes(&replacement.before, &replacement.after)(&target);
+        }
+    }
+    if let Some(size) = opt.probe_reliability {
+        let dir = match FileKind::of_path(&target) {
+            Ok(FileKind::Directory) => target.clone(),
+            Ok(FileKind::Device) => anyhow::bail!(
+                "--probe-reliability needs DEST on a file system, {} is a block device",
+                target.display()
+            ),
+            _ => target.parent().unwrap_or(&target).to_path_buf(),
+        };
+        let res =
+            copy::probe_reliability(&mut *cache_manager, &mut progress, &dir, size, block_size)
+                .context("Probing the reliability of DEST")
+                .and_then(|(_, sampled, bad)| {
+                    let source_size = tree_size(source, opt.links == LinkPolicy::Copy)?;
+                    let blocks = (source_size + block_size as u64 - 1) / block_size as u64;
+                    progress.info(format!(
+                        "{} of {} blocks of {} bytes written next to {} read back wrong ({:.3}%).",
+                        bad,
+                        sampled,
+                        block_size,
+                        target.display(),
+                        bad as f64 * 100. / sampled.max(1) as f64
+                    ));
+                    progress.info(match utils::expected_rounds(sampled, bad, blocks) {
+                        Some(rounds) => format!(
+                            "Copying the {} bytes of {} would take about {} rounds.",
+                            source_size,
+                            source.display(),
+                            rounds
+                        ),
+                        None => format!(
+                            "Copying {} would probably never finish, the medium is not usable.",
+                            source.display()
+                        ),
+                    });
+                    Ok(())
+                });
+        progress.done();
+        return res;
+    }
+    if opt.verbose > 0 {
+        eprintln!("{}", checksum::acceleration_report(opt.checksum));
+    }
+    if let Some(interval) = opt.rate_report_interval {
+        progress.set_rate_report_interval(interval);
+    }
+    let job = if let [None] = names.as_slice() {
+        CopyJob::new(source_.clone(), target)
+    } else {
+        // each source has a name in DEST
+        CopyJob::into_directory(sources.into_iter().zip(names.into_iter().flatten()), target)
+    };
+    let mut job = job
+        .cache_manager(cache_manager)
+        .options(options)
+        .progress(progress)
+        .once(opt.once)
+        .keep_going(opt.keep_going)
+        .resume(opt.resume)
+        .checksums_in_xattrs(opt.checksum_store_in_xattr)
+        .tree_checksum_in_xattr(opt.checksum_tree_root_xattr)
+        .on_disappear(opt.on_disappear)
+        .on_persistent_mismatch(opt.on_persistent_mismatch)
+        .show_remaining(opt.progress == ProgressLayout::Remaining)
+        .summary(!opt.quiet || opt.progress_format == ProgressFormat::Json)
+        .report_cache(opt.verbose > 0);
+    if let Some(max) = opt.max_rounds {
+        job = job.max_rounds(max);
+    }
+    if let Some(reference) = reference_ {
+        job = job.reference(reference);
+    }
+    if let Some(path) = opt.resume_state.as_ref() {
+        job = job.resume_state(path.clone());
+    }
+    if let Some(percent) = opt.verify_sample {
+        job = job.verify_sample(percent, opt.verify_seed);
+    }
+    if let Some(command) = opt.post_verify_command.as_ref() {
+        job = job.post_verify_command(command.clone());
+    }
+    if let Some(path) = opt.write_manifest.as_ref() {
+        job = job.write_manifest(path.clone(), manifest_prefix);
+    }
+    job.run().map(drop)
 }
@@ -0,0 +1,250 @@
+//! Manifests listing the checksums of the entries of a tree, written with `--write-manifest`
+//! and checked with `--verify-manifest`.
+//!
+//! A manifest has one line per entry, `<algorithm>:<hexadecimal checksum>  <path>`, like
+//! `crc64:00000000deadbeef  dir/file`, where the path is relative to the root of the tree, the
+//! root itself being `.`. The checksum of a regular file may be followed by its size in bytes,
+//! as in `crc64:00000000deadbeef:1024  dir/file`. Paths containing a newline cannot be listed.
+//! Checksums of directories, symlinks and special files are computed as by `cccp` itself, so
+//! other tools can only check the regular files.
+
+use crate::cache::CacheManager;
+use crate::checksum::{Checksum, ChecksumAlgorithm};
+use crate::copy::MetadataFields;
+use crate::progress::Progress;
+use crate::throttle::Throttle;
+use crate::utils::{change_prefixes, FileKind};
+use crate::xattr::parse_value;
+use anyhow::Context;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Separates the checksum from the path in a line of a manifest.
+const SEPARATOR: &[u8] = b"  ";
+
+/// One line of a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative to the root of the tree, empty for the root itself.
+    pub path: PathBuf,
+    pub algorithm: ChecksumAlgorithm,
+    pub checksum: Checksum,
+    /// Size in bytes, for regular files.
+    pub size: Option<u64>,
+}
+
+/// Formats `entry` as a line of a manifest, with the final newline.
+pub fn format_entry(entry: &Entry) -> anyhow::Result<Vec<u8>> {
+    let path = entry.path.as_os_str().as_bytes();
+    anyhow::ensure!(
+        !path.contains(&b'\n'),
+        "path {} contains a newline",
+        entry.path.display()
+    );
+    let mut line = format!(
+        "{}:{}",
+        entry.algorithm.to_string().to_lowercase(),
+        entry.checksum
+    )
+    .into_bytes();
+    if let Some(size) = entry.size {
+        line.extend_from_slice(format!(":{}", size).as_bytes());
+    }
+    line.extend_from_slice(SEPARATOR);
+    line.extend_from_slice(if path.is_empty() { b"." } else { path });
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Writes a manifest listing `entries` to `path`, sorted by path. The paths of `entries` are
+/// relative to the root of the tree, and `prefix` is the path of this root in the manifest.
+pub fn write_manifest(path: &Path, prefix: &Path, mut entries: Vec<Entry>) -> anyhow::Result<()> {
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut content = Vec::new();
+    for mut entry in entries {
+        // joining an empty path would add a trailing slash
+        entry.path = if entry.path.as_os_str().is_empty() {
+            prefix.to_path_buf()
+        } else {
+            prefix.join(&entry.path)
+        };
+        content.extend(format_entry(&entry)?);
+    }
+    std::fs::write(path, content).with_context(|| format!("writing manifest {}", path.display()))
+}
+
+/// Parses a line of a manifest, without the final newline.
+pub fn parse_entry(line: &[u8]) -> anyhow::Result<Entry> {
+    let i = line
+        .windows(SEPARATOR.len())
+        .position(|w| w == SEPARATOR)
+        .context("no two spaces between the checksum and the path")?;
+    let mut value = &line[..i];
+    let mut size = None;
+    if value.iter().filter(|&&b| b == b':').count() == 2 {
+        let colon = value.iter().rposition(|&b| b == b':').unwrap();
+        let digits = std::str::from_utf8(&value[colon + 1..]).context("size is not utf8")?;
+        size = Some(
+            digits
+                .parse()
+                .with_context(|| format!("invalid size {:?}", digits))?,
+        );
+        value = &value[..colon];
+    }
+    let (algorithm, checksum) = parse_value(value)?;
+    let path = Path::new(OsStr::from_bytes(&line[i + SEPARATOR.len()..]));
+    anyhow::ensure!(!path.as_os_str().is_empty(), "empty path");
+    anyhow::ensure!(
+        path.is_relative()
+            && path
+                .components()
+                .all(|c| c != std::path::Component::ParentDir),
+        "path {} is not below the root of the tree",
+        path.display()
+    );
+    let path = if path == Path::new(".") {
+        PathBuf::new()
+    } else {
+        path.to_path_buf()
+    };
+    Ok(Entry {
+        path,
+        algorithm,
+        checksum,
+        size,
+    })
+}
+
+/// Reads all the entries of the manifest at `path`.
+pub fn read_manifest(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let content =
+        std::fs::read(path).with_context(|| format!("reading manifest {}", path.display()))?;
+    content
+        .split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            parse_entry(line)
+                .with_context(|| format!("parsing line {} of manifest {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Checks that the entries below `root` listed in the manifest at `manifest` still have the
+/// listed checksums and sizes, reading them without cache `block_size` bytes at a time at the rate
+/// allowed by `throttle`, and reports each of them. `metadata` must be what the checksums of the
+/// manifest covered, and `follow_links` whether the copy followed symlinks, as with
+/// `LinkPolicy::Copy`. Returns an error if any is missing or does not match.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_manifest(
+    cache_manager: &mut dyn CacheManager,
+    mut progress: Progress,
+    manifest: &Path,
+    root: &Path,
+    block_size: usize,
+    throttle: Option<&Throttle>,
+    metadata: Option<MetadataFields>,
+    follow_links: bool,
+) -> anyhow::Result<()> {
+    let entries = read_manifest(manifest)?;
+    progress.syncing();
+    let root = match cache_manager
+        .drop_cache(&progress, root)
+        .with_context(|| format!("Dropping cache below {}", root.display()))?
+    {
+        Some(replacement) => change_prefixes(&replacement.before, &replacement.after)(root),
+        None => root.to_path_buf(),
+    };
+    progress.next_round(0);
+    let mut failures = 0u64;
+    for entry in &entries {
+        let path = root.join(&entry.path);
+        progress.set_status(format!("Verifying {}", path.display()));
+        if let Some(size) = entry.size {
+            let meta = if follow_links {
+                std::fs::metadata(&path)
+            } else {
+                std::fs::symlink_metadata(&path)
+            };
+            let actual = match meta {
+                Ok(meta) if FileKind::of_metadata(&meta) == FileKind::Regular => Some(meta.len()),
+                _ => None,
+            };
+            if actual != Some(size) {
+                failures += 1;
+                progress.warn(format!(
+                    "{}: FAILED, not a regular file of {} bytes",
+                    path.display(),
+                    size
+                ));
+                continue;
+            }
+        }
+        match crate::copy::checksum_path(
+            cache_manager,
+            entry.algorithm,
+            block_size,
+            throttle,
+            metadata,
+            follow_links,
+            &path,
+        ) {
+            Ok(actual) if actual == entry.checksum => {
+                progress.info(format!("{}: OK", path.display()));
+            }
+            Ok(actual) => {
+                failures += 1;
+                progress.warn(format!(
+                    "{}: FAILED, checksum {} instead of {}",
+                    path.display(),
+                    actual,
+                    entry.checksum
+                ));
+            }
+            Err(e) => {
+                failures += 1;
+                progress.warn(format!("{}: FAILED, {:#}", path.display(), e));
+            }
+        }
+    }
+    progress.done();
+    anyhow::ensure!(
+        failures == 0,
+        "{} of {} entries of {} do not match",
+        failures,
+        entries.len(),
+        manifest.display()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_manifest_entry() {
+    assert_eq!(
+        parse_entry(b"crc64:00000000deadbeef  dir/some file").unwrap(),
+        Entry {
+            path: PathBuf::from("dir/some file"),
+            algorithm: ChecksumAlgorithm::Crc64,
+            checksum: "00000000deadbeef".parse().unwrap(),
+            size: None,
+        }
+    );
+    let sized = parse_entry(b"crc64:00000000deadbeef:1024  dir/file").unwrap();
+    assert_eq!(sized.size, Some(1024));
+    assert_eq!(
+        format_entry(&sized).unwrap(),
+        b"crc64:00000000deadbeef:1024  dir/file\n"
+    );
+    let root = parse_entry(b"crc64:00000000deadbeef  .").unwrap();
+    assert_eq!(format_entry(&root).unwrap(), b"crc64:00000000deadbeef  .\n");
+    assert!(parse_entry(b"crc64:00000000deadbeef:big  dir/file").is_err());
+    assert_eq!(
+        parse_entry(b"crc64:00000000deadbeef  .").unwrap().path,
+        PathBuf::new()
+    );
+    assert!(parse_entry(b"crc64:00000000deadbeef  /etc/passwd").is_err());
+    assert!(parse_entry(b"crc64:00000000deadbeef  dir/../../etc/passwd").is_err());
+    assert!(parse_entry(b"crc64:00000000deadbeef dir/file").is_err());
+    assert!(parse_entry(b"crc64:00000000deadbeef  ").is_err());
+}
@@ -1,6 +1,188 @@
+use crate::utils::json_string;
 use anyhow::Context;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::sync::Arc;
+use clap::arg_enum;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two `ProgressEvent::Bytes`.
+const BYTES_EVENT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The round bar of the `Progress` drawing bars, if any, above which log lines are printed so
+/// that they are not overwritten.
+static LOG_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Filters log records like `env_logger`, and prints them with `LOG_BAR`.
+struct ProgressLogger(env_logger::Logger);
+
+impl log::Log for ProgressLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+        match LOG_BAR.lock().expect("poisoned LOG_BAR").as_ref() {
+            Some(b) => b.println(line),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs what cccp does on stderr, for `--verbose`: once `verbosity` 1 what is copied, fixed,
+/// removed or remounted, from 2 also checksums and offsets. `RUST_LOG` can refine this with the
+/// syntax of `env_logger`. Call this at most once.
+pub fn init_logger(verbosity: u64) -> anyhow::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(match verbosity {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(ProgressLogger(logger))).context("installing the logger")
+}
+
+/// State of the periodic throughput lines of `--rate-report-interval`.
+struct RateReport {
+    interval: Duration,
+    start: Instant,
+    /// When the previous line was printed, and how many bytes of the round were done then.
+    last: Cell<(Instant, u64)>,
+}
+
+arg_enum! {
+    /// What the bar of bytes shows after the first copy: `Rounds` shows the bytes checked during
+    /// the current round, `Remaining` shows a single bar for the whole check, which only
+    /// advances when an entry is verified for good.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ProgressLayout {
+        Rounds,
+        Remaining,
+    }
+}
+
+arg_enum! {
+    /// How progress is reported: `Bars` draws progress bars on the terminal, `Json` writes one
+    /// JSON object per line for each event, for other programs to display.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ProgressFormat {
+        Bars,
+        Json,
+    }
+}
+
+/// What happened, as reported to the callback of `Progress::with_callback`, and written as JSON
+/// with `ProgressFormat::Json`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent<'a> {
+    /// A round started, which will process `total` bytes, or more if they are added later.
+    Round {
+        round: u64,
+        total: u64,
+    },
+    /// `done` bytes of the `total` of the current round were processed. Sent at most every
+    /// 200ms, and when the round ends.
+    Bytes {
+        done: u64,
+        total: u64,
+    },
+    /// What is being done, usually naming the path being copied or fixed.
+    Status(&'a str),
+    /// The round ended and the cache manager is asked to drop the cache.
+    Syncing,
+    /// `done` bytes of the `total` of the copy are verified for good, with
+    /// `Progress::show_remaining`.
+    Verified {
+        done: u64,
+        total: u64,
+    },
+    Info(&'a str),
+    Warning(&'a str),
+    /// The whole run succeeded: `bytes` were processed in all `rounds`.
+    Summary {
+        bytes: u64,
+        rounds: u64,
+        elapsed: Duration,
+    },
+    Done,
+}
+
+impl ProgressEvent<'_> {
+    /// Formats the event as a single line JSON object, like
+    /// `{"event":"bytes","done":1024,"total":4096}`.
+    pub fn to_json(&self) -> String {
+        let (name, fields) = match self {
+            ProgressEvent::Round { round, total } => {
+                ("round", format!(",\"round\":{},\"total\":{}", round, total))
+            }
+            ProgressEvent::Bytes { done, total } => {
+                ("bytes", format!(",\"done\":{},\"total\":{}", done, total))
+            }
+            ProgressEvent::Status(msg) => ("status", format!(",\"message\":{}", json_string(msg))),
+            ProgressEvent::Syncing => ("syncing", String::new()),
+            ProgressEvent::Verified { done, total } => (
+                "verified",
+                format!(",\"done\":{},\"total\":{}", done, total),
+            ),
+            ProgressEvent::Info(msg) => ("info", format!(",\"message\":{}", json_string(msg))),
+            ProgressEvent::Warning(msg) => {
+                ("warning", format!(",\"message\":{}", json_string(msg)))
+            }
+            ProgressEvent::Summary {
+                bytes,
+                rounds,
+                elapsed,
+            } => (
+                "summary",
+                format!(
+                    ",\"bytes\":{},\"rounds\":{},\"elapsed\":{:.3},\"rate\":{}",
+                    bytes,
+                    rounds,
+                    elapsed.as_secs_f64(),
+                    average_rate(*bytes, *elapsed)
+                ),
+            ),
+            ProgressEvent::Done => ("done", String::new()),
+        };
+        format!("{{\"event\":\"{}\"{}}}", name, fields)
+    }
+}
+
+/// Bytes per second of processing `bytes` in `elapsed`.
+fn average_rate(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(1e-3)) as u64
+}
+
+/// Type of the callback of `Progress::with_callback`.
+type Callback = Box<dyn Fn(&ProgressEvent)>;
+
+/// Where `Progress` reports to.
+enum Backend {
+    /// Progress bars drawn by the `MultiProgress`.
+    Bars,
+    /// No progress bars, for logs: only messages are printed, on stderr. The progress bars are
+    /// still used to count bytes, but are hidden.
+    Quiet,
+    /// Events of `ProgressFormat::Json`, written to this output. The progress bars are still
+    /// used to count bytes, but are hidden.
+    Json(RefCell<Box<dyn Write>>),
+    /// Events passed to this callback. The progress bars are still used to count bytes, but are
+    /// hidden.
+    Callback(Callback),
+}
 
 /// This struct allows to display a progress bar and status information during
 /// operation. It leaves nothing once `done` is called.
@@ -12,6 +194,27 @@ pub struct Progress {
     /// The progress bar for bytes processed during a round. Only filled between
     /// `next_round` and `syncing`.
     bytes_bar: Option<ProgressBar>,
+    /// The progress bar of `show_remaining`. When filled, `bytes_bar` is hidden.
+    remaining_bar: Option<ProgressBar>,
+    rate_report: Option<RateReport>,
+    backend: Backend,
+    /// When the instance was created, for `finish_with_summary`.
+    start: Instant,
+    /// Bytes processed in all rounds so far.
+    total_bytes: Cell<u64>,
+    /// When the last `ProgressEvent::Bytes` was sent.
+    last_bytes_event: Cell<Instant>,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+/// Formats a duration of `secs` seconds as `hh:mm:ss`.
+fn format_elapsed(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
 }
 
 impl Progress {
@@ -22,6 +225,120 @@ impl Progress {
             multi,
             bytes_bar: None,
             round_bar: None,
+            remaining_bar: None,
+            rate_report: None,
+            backend: Backend::Bars,
+            start: Instant::now(),
+            total_bytes: Cell::new(0),
+            last_bytes_event: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Creates an instance which draws no progress bars and only prints messages, for
+    /// non interactive use.
+    pub fn quiet() -> Progress {
+        Progress {
+            backend: Backend::Quiet,
+            ..Progress::new()
+        }
+    }
+
+    /// Creates an instance which writes the events of `ProgressFormat::Json` to `out` instead of
+    /// drawing progress bars.
+    pub fn json(out: Box<dyn Write>) -> Progress {
+        Progress {
+            backend: Backend::Json(RefCell::new(out)),
+            ..Progress::new()
+        }
+    }
+
+    /// Creates an instance which passes events to `callback` instead of drawing progress bars.
+    pub fn with_callback(callback: impl Fn(&ProgressEvent) + 'static) -> Progress {
+        Progress {
+            backend: Backend::Callback(Box::new(callback)),
+            ..Progress::new()
+        }
+    }
+
+    /// Whether events are reported instead of displayed.
+    fn has_events(&self) -> bool {
+        matches!(self.backend, Backend::Json(_) | Backend::Callback(_))
+    }
+
+    /// Reports `event`, if events are reported. Does nothing with progress bars.
+    fn event(&self, event: ProgressEvent) {
+        match &self.backend {
+            Backend::Json(out) => {
+                let mut out = out.borrow_mut();
+                // the copy goes on if nobody listens anymore
+                let _ = writeln!(out, "{}", event.to_json());
+                let _ = out.flush();
+            }
+            Backend::Callback(callback) => callback(&event),
+            Backend::Bars | Backend::Quiet => (),
+        }
+    }
+
+    /// Reports the progress of the current round, unless it was less than
+    /// `BYTES_EVENT_INTERVAL` ago and `force` is false.
+    fn bytes_event(&self, force: bool) {
+        if let (true, Some(b)) = (self.has_events(), self.bytes_bar.as_ref()) {
+            let now = Instant::now();
+            if !force && now - self.last_bytes_event.get() < BYTES_EVENT_INTERVAL {
+                return;
+            }
+            self.last_bytes_event.set(now);
+            self.event(ProgressEvent::Bytes {
+                done: b.position(),
+                total: b.length(),
+            });
+        }
+    }
+
+    /// Displays the bar `b`, or only keeps it to count without progress bars.
+    fn add_bar(&self, b: ProgressBar) -> ProgressBar {
+        match self.backend {
+            Backend::Bars => self.multi.add(b),
+            Backend::Quiet | Backend::Json(_) | Backend::Callback(_) => {
+                b.set_draw_target(ProgressDrawTarget::hidden());
+                b
+            }
+        }
+    }
+
+    /// Prints a line with the throughput every `interval` while bytes are processed, which
+    /// stays in non interactive logs, unlike the progress bars.
+    pub fn set_rate_report_interval(&mut self, interval: Duration) {
+        let now = Instant::now();
+        self.rate_report = Some(RateReport {
+            interval,
+            start: now,
+            last: Cell::new((now, 0)),
+        });
+    }
+
+    /// Shows a bar of the `total` bytes of the copy which remain to be verified, across all
+    /// the following rounds, instead of a bar per round. Does nothing after the first call.
+    pub fn show_remaining(&mut self, total: u64) {
+        if self.remaining_bar.is_some() {
+            return;
+        }
+        let b = ProgressBar::new(total);
+        b.set_style(ProgressStyle::default_bar()
+                      .template("[{elapsed_precise}] [{bar:40.green/blue}] {bytes}/{total_bytes} verified ({eta_precise})")
+                      .progress_chars("#>-"));
+        b.set_draw_delta(std::cmp::min(1_000_000, total / 100));
+        self.remaining_bar = Some(self.add_bar(b));
+    }
+
+    /// Notifies that `n` bytes of the copy are verified for good, for `show_remaining`.
+    pub fn verified(&self, n: u64) {
+        if let Some(b) = self.remaining_bar.as_ref() {
+            b.inc(n);
+            self.event(ProgressEvent::Verified {
+                done: b.position(),
+                total: b.length(),
+            });
         }
     }
 
@@ -30,14 +347,48 @@ impl Progress {
         if let Some(b) = self.round_bar.as_ref() {
             b.set_message(msg.as_ref())
         }
+        if !msg.as_ref().is_empty() {
+            self.event(ProgressEvent::Status(msg.as_ref()));
+        }
+    }
+
+    /// Returns the progress bar showing the status, if any. Unlike `Progress`, it can be sent to
+    /// other threads.
+    pub fn status_bar(&self) -> Option<ProgressBar> {
+        self.round_bar.clone()
+    }
+
+    /// Displays a warning above the progress bars, which stays visible after `done`.
+    pub fn warn(&self, msg: impl AsRef<str>) {
+        match (&self.backend, self.round_bar.as_ref()) {
+            _ if self.has_events() => self.event(ProgressEvent::Warning(msg.as_ref())),
+            (Backend::Bars, Some(b)) if !b.is_hidden() => {
+                b.println(format!("Warning: {}", msg.as_ref()))
+            }
+            _ => eprintln!("Warning: {}", msg.as_ref()),
+        }
+    }
+
+    /// Displays an informative message above the progress bars, which stays visible after
+    /// `done`.
+    pub fn info(&self, msg: impl AsRef<str>) {
+        match (&self.backend, self.round_bar.as_ref()) {
+            _ if self.has_events() => self.event(ProgressEvent::Info(msg.as_ref())),
+            (Backend::Bars, Some(b)) if !b.is_hidden() => b.println(msg.as_ref()),
+            _ => eprintln!("{}", msg.as_ref()),
+        }
     }
 
     /// Call this when copy is finished and the CacheManager is asked to drop cache.
     pub fn syncing(&mut self) {
+        self.bytes_event(true);
         if let Some(b) = self.bytes_bar.as_ref() {
             b.finish_and_clear()
         }
-        self.set_status("Syncing");
+        if let Some(b) = self.round_bar.as_ref() {
+            b.set_message("Syncing")
+        }
+        self.event(ProgressEvent::Syncing);
     }
 
     /// Starts a round, given then total number of bytes to copy.
@@ -50,19 +401,37 @@ impl Progress {
             );
             let b = ProgressBar::new_spinner();
             b.set_style(ProgressStyle::default_spinner().template("{spinner} Round {pos}. {msg}"));
-            self.round_bar = Some(self.multi.add(b));
-            // this must be done after the bar is added to the MultiProgress
-            if let Some(b) = self.round_bar.as_ref() {
-                b.enable_steady_tick(200)
+            self.round_bar = Some(self.add_bar(b));
+            if let Backend::Bars = self.backend {
+                // this must be done after the bar is added to the MultiProgress
+                if let Some(b) = self.round_bar.as_ref() {
+                    b.enable_steady_tick(200);
+                    *LOG_BAR.lock().expect("poisoned LOG_BAR") = Some(b.clone());
+                }
+                let multi = self.multi.clone();
+                std::thread::spawn(move || multi.join().context("joining progress bar").unwrap());
             }
-            let multi = self.multi.clone();
-            std::thread::spawn(move || multi.join().context("joining progress bar").unwrap());
         }
         self.set_status("");
         if let Some(b) = self.round_bar.as_ref() {
-            b.inc(1)
+            b.inc(1);
+            self.event(ProgressEvent::Round {
+                round: b.position(),
+                total: total_size,
+            });
+        }
+        if let Some(report) = self.rate_report.as_ref() {
+            let (last_time, _) = report.last.get();
+            report.last.set((last_time, 0));
         }
-        self.bytes_bar = Some(self.multi.add({
+        if self.remaining_bar.is_some() {
+            // still counted, for `report_rate`
+            let b = ProgressBar::hidden();
+            b.set_length(total_size);
+            self.bytes_bar = Some(b);
+            return;
+        }
+        self.bytes_bar = Some(self.add_bar({
             let b = ProgressBar::new(total_size);
             b.set_style(ProgressStyle::default_bar()
                           .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes}, {bytes_per_sec} ({eta_precise})")
@@ -72,6 +441,17 @@ impl Progress {
         }));
     }
 
+    /// Adds `n` bytes to the total of the current round, when it was not known in advance.
+    pub fn add_total(&self, n: u64) {
+        let b = self
+            .bytes_bar
+            .as_ref()
+            .expect("called add_total() before next_round()");
+        let total = b.length() + n;
+        b.set_length(total);
+        b.set_draw_delta(std::cmp::min(1_000_000, total / 100));
+    }
+
     /// Notifies that `n` bytes were copied.
     pub fn do_bytes(&self, n: u64) {
         let b = self
@@ -79,15 +459,99 @@ impl Progress {
             .as_ref()
             .expect("called do_bytes() before next_round()");
         b.inc(n);
+        self.total_bytes.set(self.total_bytes.get() + n);
+        self.bytes_event(false);
+        self.report_rate();
+    }
+
+    /// Prints the throughput line of `set_rate_report_interval` if it is due.
+    fn report_rate(&self) {
+        let (report, bytes_bar, round_bar) = match (
+            self.rate_report.as_ref(),
+            self.bytes_bar.as_ref(),
+            self.round_bar.as_ref(),
+        ) {
+            (Some(r), Some(b), Some(round)) => (r, b, round),
+            _ => return,
+        };
+        let now = Instant::now();
+        let (last_time, last_bytes) = report.last.get();
+        let since = now - last_time;
+        if since < report.interval {
+            return;
+        }
+        let done = bytes_bar.position();
+        report.last.set((now, done));
+        let rate = (done.saturating_sub(last_bytes) as f64 / since.as_secs_f64()) as u64;
+        self.info(format!(
+            "[{}] copied {} of {} ({}/s), round {}",
+            format_elapsed((now - report.start).as_secs()),
+            HumanBytes(done),
+            HumanBytes(bytes_bar.length()),
+            HumanBytes(rate),
+            round_bar.position()
+        ));
+    }
+
+    /// Like `done`, but first prints a line summarizing the whole run: the bytes processed in
+    /// all rounds, the number of rounds, the elapsed time and the average throughput.
+    pub fn finish_with_summary(self) {
+        let elapsed = self.start.elapsed();
+        let bytes = self.total_bytes.get();
+        let rounds = self.round_bar.as_ref().map_or(0, |b| b.position());
+        if self.has_events() {
+            self.event(ProgressEvent::Summary {
+                bytes,
+                rounds,
+                elapsed,
+            });
+        } else {
+            self.info(format!(
+                "Copied and checked {} in {} rounds, {} elapsed ({}/s)",
+                HumanBytes(bytes),
+                rounds,
+                format_elapsed(elapsed.as_secs()),
+                HumanBytes(average_rate(bytes, elapsed))
+            ));
+        }
+        self.done()
     }
 
     /// Clears the progress bar. Must be called, otherwise the process will not terminate.
     pub fn done(self) {
+        self.event(ProgressEvent::Done);
         if let Some(b) = self.bytes_bar.as_ref() {
             b.finish_and_clear()
         }
+        if let Some(b) = self.remaining_bar.as_ref() {
+            b.finish_and_clear()
+        }
         if let Some(b) = self.round_bar.as_ref() {
+            LOG_BAR.lock().expect("poisoned LOG_BAR").take();
             b.finish_and_clear()
         }
     }
 }
+
+#[test]
+fn test_progress_event_json() {
+    assert_eq!(
+        ProgressEvent::Bytes {
+            done: 1024,
+            total: 4096
+        }
+        .to_json(),
+        r#"{"event":"bytes","done":1024,"total":4096}"#
+    );
+    assert_eq!(
+        ProgressEvent::Status("Fixing \"a\"").to_json(),
+        r#"{"event":"status","message":"Fixing \"a\""}"#
+    );
+    assert_eq!(ProgressEvent::Done.to_json(), r#"{"event":"done"}"#);
+}
+
+#[test]
+fn test_format_elapsed() {
+    assert_eq!(format_elapsed(0), "00:00:00");
+    assert_eq!(format_elapsed(3725), "01:02:05");
+}
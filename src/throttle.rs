@@ -0,0 +1,59 @@
+//! Limit of the throughput of copying and checking, as set with `--bwlimit`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting the rate at which bytes are copied or checked. It is used by
+/// reference, so that several threads respect a single limit.
+#[derive(Debug)]
+pub struct Throttle {
+    /// In bytes per second.
+    rate: f64,
+    /// How many bytes can be transferred without waiting, which is negative after a burst, and
+    /// when it was computed.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    /// `rate` is in bytes per second, and must not be zero.
+    pub fn new(rate: u64) -> Throttle {
+        let rate = rate as f64;
+        Throttle {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Accounts for `n` bytes which were just transferred, and sleeps as long as needed to keep
+    /// the rate below the limit. All the time since the previous call counts, including the
+    /// time spent hashing.
+    pub fn transferred(&self, n: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (available, last) = *state;
+            let now = Instant::now();
+            // bursts last at most one second
+            let available = (available + now.duration_since(last).as_secs_f64() * self.rate)
+                .min(self.rate)
+                - n as f64;
+            *state = (available, now);
+            if available < 0. {
+                Duration::from_secs_f64(-available / self.rate)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+#[test]
+fn test_throttle() {
+    let throttle = Throttle::new(10_000);
+    let start = Instant::now();
+    // the bucket starts full
+    throttle.transferred(10_000);
+    assert!(start.elapsed() < Duration::from_millis(50));
+    throttle.transferred(1_000);
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
@@ -1,14 +1,22 @@
 use crate::utils::FileKind;
-use crate::utils::{get_unique, Unique};
+use crate::utils::{change_prefixes, get_mountpoint_in, get_unique, Unique};
 use anyhow::Context;
 use dbus_udisks2::{Block, Drive, MountError, UDisks2};
-use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use udev::Device;
 
+/// First delay between two polls of `poll_until`.
+const FIRST_POLL_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest delay between two polls of `poll_until`.
+const MAX_POLL_DELAY: Duration = Duration::from_secs(5);
+
 /// Returns the device number of the device bearing the specified path.
 /// Either this path, or its parent must exist.
 fn underlying_device_number(path: &Path) -> anyhow::Result<u64> {
@@ -82,26 +90,70 @@ pub fn get_udisk_blockdev_by_uuid(udisks: &UDisks2, uuid: &str) -> Unique<Block>
     )
 }
 
-/// Returns a UDisks2 block device by drive dbus path and size
-pub fn get_udisk_blockdev_by_drive_and_size(
+/// How to tell a block device from the other block devices of its drive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockKey {
+    /// A partition, by partition uuid.
+    PartitionUuid(String),
+    /// A partition without uuid, by partition number.
+    PartitionNumber(u32),
+    /// Anything else, like the whole drive, by size. Using the size is pretty hacky, sorry
+    Size(u64),
+}
+
+impl BlockKey {
+    /// The most precise key of `block` which udisks knows.
+    pub fn of(block: &Block) -> BlockKey {
+        match &block.partition {
+            Some(p) if !p.uuid.is_empty() => BlockKey::PartitionUuid(p.uuid.clone()),
+            Some(p) => BlockKey::PartitionNumber(p.number),
+            None => BlockKey::Size(block.size),
+        }
+    }
+
+    /// Whether `block` has this key.
+    pub fn matches(&self, block: &Block) -> bool {
+        match (self, &block.partition) {
+            (BlockKey::PartitionUuid(uuid), Some(p)) => &p.uuid == uuid,
+            (BlockKey::PartitionNumber(number), Some(p)) => p.number == *number,
+            (BlockKey::Size(size), _) => block.size == *size,
+            (_, None) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for BlockKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockKey::PartitionUuid(uuid) => write!(f, "partition uuid {}", uuid),
+            BlockKey::PartitionNumber(number) => write!(f, "partition number {}", number),
+            BlockKey::Size(size) => write!(f, "size {}", size),
+        }
+    }
+}
+
+/// Returns a UDisks2 block device by drive dbus path and key
+pub fn get_udisk_blockdev_by_drive_and_key(
     udisks: &UDisks2,
     drive: &str,
-    size: u64,
+    key: &BlockKey,
 ) -> Unique<Block> {
     get_unique(
         udisks
             .get_blocks()
-            .filter(|b| b.drive == drive && b.size == size),
+            .filter(|b| b.drive == drive && key.matches(b)),
     )
 }
 
 /// Like Udisks2.mount, but does not fail if the fs is already mounted.
+/// `options` are passed to udisks as mount options, for example `Some("rw")`.
 pub fn ensure_mounted(
     udisks: &mut UDisks2,
     block: &Block,
+    options: Option<&str>,
     timeout: std::time::Duration,
 ) -> anyhow::Result<PathBuf> {
-    match udisks.mount(block, /* interactive */ true, None, None, timeout) {
+    match udisks.mount(block, /* interactive */ true, None, options, timeout) {
         Err(MountError::DBUS(d)) => {
             if d.name() == Some("org.freedesktop.UDisks2.Error.AlreadyMounted") {
                 udisks
@@ -128,6 +180,177 @@ pub fn ensure_mounted(
     }
 }
 
+/// Enough info to find what we are copying to after its device disappeared, for example after
+/// a usb reset.
+pub enum Identifier {
+    /// A block device, by drive dbus path and key among the block devices of the drive.
+    BlockDevice(String, BlockKey),
+    /// A file system, by uuid. There is also the mountpoint, but it's only to piggy back the info.
+    Fs(String, PathBuf),
+}
+
+/// Returns how to find `block` again after its device disappeared. `block` is the UDisks2 block
+/// device of `dev`, which is the underlying device of `path`.
+pub fn identify(
+    udisks: &UDisks2,
+    dev: &Device,
+    block: &Block,
+    path: &Path,
+) -> anyhow::Result<Identifier> {
+    let id = match FileKind::of_path(path) {
+        Ok(FileKind::Device) => {
+            let key = BlockKey::of(block);
+            let b = get_udisk_blockdev_by_drive_and_key(udisks, &block.drive, &key);
+            match b {
+                Unique::Zero => {
+                    anyhow::bail!("{} disappeared", block.preferred_device.display())
+                }
+                Unique::Several => {
+                    anyhow::bail!("Several partitions on {} have the {}", block.drive, key)
+                }
+                Unique::One(x) => {
+                    anyhow::ensure!(
+                        x.path == block.path,
+                        "{} changed path to {}",
+                        block.path,
+                        x.path
+                    );
+                    Identifier::BlockDevice(block.drive.clone(), key)
+                }
+            }
+        }
+        _ => {
+            anyhow::ensure!(
+                block.has_fs(),
+                "UDisks knows about no file system on block device {}, corresponding to sysfs {} and path {}",
+                block.preferred_device.display(),
+                dev.syspath().display(),
+                path.display()
+            );
+            let mountpoint = match get_mountpoint_in(block, path) {
+                None => anyhow::bail!(
+                "File system on block device {}, corresponding to sysfs {}, does not looks like it bears {}: mount points {:?}",
+                block.preferred_device.display(),
+                dev.syspath().display(),
+                path.display(),
+                &block.mount_points
+            ),
+            Some(x) => x.to_path_buf()
+            };
+            let uuid = match block.id_uuid.clone() {
+                None => anyhow::bail!(
+                    "Attempting to write to a filesystem {} without uuid",
+                    block.preferred_device.display()
+                ),
+                Some(x) => x,
+            };
+            match get_udisk_blockdev_by_uuid(udisks, &uuid) {
+                Unique::Zero => anyhow::bail!("FS with UUID {} disappeared", uuid),
+                Unique::Several => anyhow::bail!("Several fs with UUID {}", uuid),
+                Unique::One(x) => {
+                    anyhow::ensure!(
+                        x.path == block.path,
+                        "{} changed path to {}",
+                        block.path,
+                        x.path
+                    );
+                    Identifier::Fs(uuid, mountpoint)
+                }
+            }
+        }
+    };
+    Ok(id)
+}
+
+/// Calls `attempt` until it returns something, first after `FIRST_POLL_DELAY` then doubling the
+/// delay up to `MAX_POLL_DELAY`, for at most `wait` in total. `attempt` is called at least once,
+/// even if `wait` is zero. `what` is what is waited for, for the error message.
+fn poll_until<T>(
+    wait: Duration,
+    what: impl FnOnce() -> String,
+    mut attempt: impl FnMut() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let mut delay = FIRST_POLL_DELAY;
+    loop {
+        std::thread::sleep(delay.min(wait.saturating_sub(start.elapsed())));
+        if let Some(x) = attempt()? {
+            return Ok(x);
+        }
+        if start.elapsed() >= wait {
+            anyhow::bail!(
+                "Timeout reached after waiting {:.1}s for {} to appear",
+                start.elapsed().as_secs_f64(),
+                what()
+            );
+        }
+        delay = (delay * 2).min(MAX_POLL_DELAY);
+    }
+}
+
+/// Waits up to `wait` for the device identified by `id` to reappear, and mounts it if it bears a
+/// file system, with this `timeout`. Returns the new path of `path`, if it changed.
+pub fn wait_for_reappearance(
+    udisks: &mut UDisks2,
+    id: &Identifier,
+    path: &Path,
+    wait: Duration,
+    timeout: Duration,
+) -> anyhow::Result<Option<PathBuf>> {
+    let new_path = match id {
+        Identifier::Fs(uuid, mountpoint) => {
+            let block = poll_until(
+                wait,
+                || format!("fs with uuid {}", uuid),
+                || {
+                    udisks.update().context("Updating Udisks2")?;
+                    match get_udisk_blockdev_by_uuid(udisks, uuid) {
+                        Unique::Zero => Ok(None),
+                        Unique::Several => anyhow::bail!("Several FS with uuid {}", uuid),
+                        Unique::One(x) => Ok(Some(x)),
+                    }
+                },
+            )?;
+            // we need to remount the fs
+            let remounted_path = ensure_mounted(udisks, &block, None, timeout)
+                .with_context(|| format!("Remounting {}", &block.preferred_device.display()))?;
+            if path.starts_with(&remounted_path) {
+                None
+            } else {
+                let mut f = change_prefixes(mountpoint.as_path(), remounted_path.as_path());
+                Some(f(path))
+            }
+        }
+        Identifier::BlockDevice(drive, key) => {
+            let block = poll_until(
+                wait,
+                || format!("block device on drive {} with {}", drive, key),
+                || {
+                    udisks.update().context("Updating Udisks2")?;
+                    match get_udisk_blockdev_by_drive_and_key(udisks, drive, key) {
+                        Unique::Zero => Ok(None),
+                        Unique::Several => {
+                            anyhow::bail!("Several block devices on drive {} with {}", drive, key)
+                        }
+                        Unique::One(x) => Ok(Some(x)),
+                    }
+                },
+            )?;
+            if block.symlinks.iter().any(|x| x.as_path() == path) || path == block.device {
+                // the current path to the device file is still valid
+                None
+            } else {
+                Some(block.device)
+            }
+        }
+    };
+    match new_path.as_ref() {
+        Some(new_path) => log::info!("{} reappeared as {}", path.display(), new_path.display()),
+        None => log::info!("{} reappeared", path.display()),
+    }
+    Ok(new_path)
+}
+
 pub fn udisk_drives_for(udisks: &UDisks2, fs: &Block) -> anyhow::Result<Vec<Drive>> {
     let drive = match udisks.get_drive(&fs.drive) {
         None => anyhow::bail!("Could not find drive for {}", fs.device.display()),
@@ -166,37 +389,22 @@ pub fn usb_hub_for(dev: &Device) -> anyhow::Result<Device> {
 // defined in include/uapi/linux/usbdevice_fs.h
 nix::ioctl_none!(usbreset, b'U', 20);
 
-fn leftpad(s: &[u8]) -> anyhow::Result<OsString> {
-    let mut res = [b'0'; 3];
-    let len = s.len();
-    anyhow::ensure!(
-        len <= 3,
-        "more than 3 digits: {}",
-        String::from_utf8_lossy(s)
-    );
-    res[(3 - len)..].copy_from_slice(s);
-    Ok(OsStr::from_bytes(&res).into())
+/// Pads a bus or device number with zeros to 3 digits, like the kernel's `%03d` in the names
+/// of `/dev/bus/usb/BBB/DDD`. Longer numbers are left as they are.
+fn leftpad(s: &[u8]) -> OsString {
+    let mut res = vec![b'0'; 3_usize.saturating_sub(s.len())];
+    res.extend_from_slice(s);
+    OsString::from_vec(res)
 }
 
 #[test]
 fn test_leftpad() {
-    assert_eq!(
-        leftpad(b"").map_err(|_| ()),
-        Ok(OsStr::from_bytes(b"000").into())
-    );
-    assert_eq!(
-        leftpad(b"1").map_err(|_| ()),
-        Ok(OsStr::from_bytes(b"001").into())
-    );
-    assert_eq!(
-        leftpad(b"12").map_err(|_| ()),
-        Ok(OsStr::from_bytes(b"012").into())
-    );
-    assert_eq!(
-        leftpad(b"123").map_err(|_| ()),
-        Ok(OsStr::from_bytes(b"123").into())
-    );
-    assert!(leftpad(b"1234").is_err());
+    assert_eq!(leftpad(b""), "000");
+    assert_eq!(leftpad(b"1"), "001");
+    assert_eq!(leftpad(b"12"), "012");
+    assert_eq!(leftpad(b"123"), "123");
+    assert_eq!(leftpad(b"1234"), "1234");
+    assert_eq!(leftpad(b"12345"), "12345");
 }
 
 /// Resets a usb device, source: https://marc.info/?l=linux-usb-users&m=116827193506484
@@ -207,8 +415,8 @@ pub fn reset_usb_hub(dev: &Device, dryrun: bool) -> anyhow::Result<()> {
         _ => anyhow::bail!("Device {} is missing busnum or devnum attribute"),
     };
     let mut buspath = PathBuf::from("/dev/bus/usb");
-    buspath.push(leftpad(busnum.as_bytes()).context("bus number")?);
-    buspath.push(leftpad(devnum.as_bytes()).context("dev number")?);
+    buspath.push(leftpad(busnum.as_bytes()));
+    buspath.push(leftpad(devnum.as_bytes()));
     let file = std::fs::OpenOptions::new()
         .write(true)
         .open(&buspath)
@@ -221,3 +429,124 @@ pub fn reset_usb_hub(dev: &Device, dryrun: bool) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// Returns the scsi device (like `2:0:0:0`) above `dev`, and the scsi host (like `host2`) it is
+/// attached to.
+fn scsi_device_for(dev: &Device) -> anyhow::Result<(Device, Device)> {
+    let mut device = None;
+    let mut dev = dev.clone();
+    while let Some(p) = dev.parent() {
+        match p.devtype().map(OsStrExt::as_bytes) {
+            Some(b"scsi_device") if device.is_none() => device = Some(p.clone()),
+            Some(b"scsi_host") => {
+                if let Some(device) = device {
+                    return Ok((device, p));
+                }
+            }
+            _ => (),
+        }
+        dev = p;
+    }
+    anyhow::bail!("{} is not on a scsi host", dev.syspath().display());
+}
+
+/// Opens the sysfs attribute `name` of `dev` for writing.
+fn open_attribute(dev: &Device, name: &str) -> anyhow::Result<std::fs::File> {
+    let path = dev.syspath().join(name);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Opening {} for writing", path.display()))
+}
+
+/// Resets a scsi device by removing it from its host, and rescanning the host so that it is
+/// detected again. If dryrun is true, only performs permission checks.
+fn reset_scsi_device(device: &Device, host: &Device, dryrun: bool) -> anyhow::Result<()> {
+    let mut delete = open_attribute(device, "delete")?;
+    let mut scan = open_attribute(host, "scan")?;
+    if !dryrun {
+        delete
+            .write_all(b"1")
+            .with_context(|| format!("Deleting scsi device {}", device.syspath().display()))?;
+        scan.write_all(b"- - -")
+            .with_context(|| format!("Rescanning scsi host {}", host.syspath().display()))?;
+    }
+    Ok(())
+}
+
+/// How the device bearing a drive can be reset, depending on how it is plugged in.
+pub enum ResetTarget {
+    /// The usb hub, from `usb_hub_for`.
+    Usb(Device),
+    /// The scsi device (eSATA, SAS, ...) and its host, from `scsi_device_for`.
+    Scsi(Device, Device),
+}
+
+impl ResetTarget {
+    /// Finds how to reset the device `dev`. Usb is preferred, as usb drives are also scsi devices
+    /// with usb-storage.
+    pub fn for_device(dev: &Device) -> anyhow::Result<ResetTarget> {
+        match usb_hub_for(dev) {
+            Ok(hub) => Ok(ResetTarget::Usb(hub)),
+            Err(usb) => match scsi_device_for(dev) {
+                Ok((device, host)) => Ok(ResetTarget::Scsi(device, host)),
+                Err(scsi) => Err(scsi.context(usb)),
+            },
+        }
+    }
+
+    /// Resets the device. If dryrun is true, only performs permission checks.
+    pub fn reset(&self, dryrun: bool) -> anyhow::Result<()> {
+        match self {
+            ResetTarget::Usb(hub) => reset_usb_hub(hub, dryrun),
+            ResetTarget::Scsi(device, host) => reset_scsi_device(device, host, dryrun),
+        }
+    }
+}
+
+impl std::fmt::Display for ResetTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetTarget::Usb(hub) => write!(f, "usb hub {}", hub.syspath().display()),
+            ResetTarget::Scsi(device, _) => {
+                write!(f, "scsi device {}", device.syspath().display())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_poll_until() {
+    let mut calls = 0;
+    let found = poll_until(
+        Duration::from_secs(10),
+        || "nothing".into(),
+        || {
+            calls += 1;
+            Ok(if calls == 3 { Some(calls) } else { None })
+        },
+    );
+    assert_eq!(found.unwrap(), 3);
+    let start = Instant::now();
+    let timeout = poll_until(
+        Duration::from_millis(300),
+        || "nothing".into(),
+        || Ok(None::<()>),
+    );
+    assert!(timeout
+        .unwrap_err()
+        .to_string()
+        .contains("for nothing to appear"));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    // a zero timeout still tries once
+    let mut calls = 0;
+    let found = poll_until(
+        Duration::from_secs(0),
+        || "nothing".into(),
+        || {
+            calls += 1;
+            Ok(Some(calls))
+        },
+    );
+    assert_eq!(found.unwrap(), 1);
+}
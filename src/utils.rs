@@ -1,6 +1,9 @@
 use anyhow::Context;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::FileTypeExt;
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -14,6 +17,12 @@ pub enum FileKind {
     /// A block device
     // does someone really need to copy a file to a character device ?
     Device,
+    /// A named pipe, recreated with mknod
+    Fifo,
+    /// A character device, recreated with mknod
+    CharDevice,
+    /// A unix socket, recreated with mknod
+    Socket,
     /// Something else that we cannot handle.
     Other,
 }
@@ -21,7 +30,12 @@ pub enum FileKind {
 impl FileKind {
     /// Gets the kind of the file which has this metadata. No syscall is issued.
     pub fn of_metadata(metadata: &std::fs::Metadata) -> FileKind {
-        let t = metadata.file_type();
+        Self::of_file_type(metadata.file_type())
+    }
+
+    /// Gets the kind of the file which has this file type, as returned for example by
+    /// `DirEntry::file_type`. No syscall is issued.
+    pub fn of_file_type(t: std::fs::FileType) -> FileKind {
         if t.is_file() {
             FileKind::Regular
         } else if t.is_dir() {
@@ -30,6 +44,12 @@ impl FileKind {
             FileKind::Symlink
         } else if t.is_block_device() {
             FileKind::Device
+        } else if t.is_fifo() {
+            FileKind::Fifo
+        } else if t.is_char_device() {
+            FileKind::CharDevice
+        } else if t.is_socket() {
+            FileKind::Socket
         } else {
             FileKind::Other
         }
@@ -51,6 +71,35 @@ impl FileKind {
     }
 }
 
+/// Turns `error`, which happened when creating `path`, into an `anyhow::Error`. When the file
+/// system was full, explains whether it ran out of space or of inodes, which is common when
+/// copying many small files to FAT or ext file systems.
+pub fn no_space_error(error: std::io::Error, path: &Path) -> anyhow::Error {
+    use nix::errno::Errno;
+    let what = match error.raw_os_error().map(Errno::from_i32) {
+        Some(Errno::EDQUOT) => "the disk quota is exceeded",
+        Some(Errno::ENOSPC) => {
+            let parent = path.parent().unwrap_or(path);
+            match nix::sys::statvfs::statvfs(parent) {
+                // file systems without inodes, like FAT, report f_files = 0
+                Ok(stat) if stat.files() != 0 && stat.files_available() == 0 => {
+                    "the file system is out of inodes"
+                }
+                Ok(stat) if stat.blocks_available() != 0 && stat.files() == 0 => {
+                    "the directory or file system cannot hold more entries"
+                }
+                _ => "the file system is out of space",
+            }
+        }
+        _ => return error.into(),
+    };
+    anyhow::Error::new(error).context(format!(
+        "{} when creating {}: free some and run cccp again, what was already copied is checked instead of copied again",
+        what,
+        path.display()
+    ))
+}
+
 /// Returns without this file exists, without following symlinks
 pub fn exists(path: &Path) -> anyhow::Result<bool> {
     match std::fs::symlink_metadata(path) {
@@ -113,7 +162,12 @@ pub fn get_mountpoint_in<'a, 'b>(
 /// This is 0 for symlinks and directories.
 pub fn copy_size(meta: &std::fs::Metadata) -> u64 {
     match FileKind::of_metadata(meta) {
-        FileKind::Symlink | FileKind::Directory | FileKind::Other => 0,
+        FileKind::Symlink
+        | FileKind::Directory
+        | FileKind::Fifo
+        | FileKind::CharDevice
+        | FileKind::Socket
+        | FileKind::Other => 0,
         FileKind::Regular | FileKind::Device => meta.size(),
     }
 }
@@ -143,6 +197,570 @@ where
     }
 }
 
+// BLKSSZGET and BLKPBSZGET from linux/fs.h
+nix::ioctl_read_bad!(blksszget, 0x1268, libc::c_int);
+nix::ioctl_read_bad!(blkpbszget, 0x127b, libc::c_uint);
+
+/// Returns the logical and physical block sizes of a block device, in bytes.
+pub fn block_sizes(device: &std::fs::File) -> anyhow::Result<(u64, u64)> {
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    unsafe { blksszget(device.as_raw_fd(), &mut logical) }
+        .context("ioctl(BLKSSZGET) to get the logical block size")?;
+    unsafe { blkpbszget(device.as_raw_fd(), &mut physical) }
+        .context("ioctl(BLKPBSZGET) to get the physical block size")?;
+    Ok((logical as u64, physical as u64))
+}
+
+// BLKGETSIZE64 from linux/fs.h
+nix::ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+/// Returns the size of a block device, in bytes. `stat` reports 0 for them.
+pub fn block_device_size(device: &std::fs::File) -> anyhow::Result<u64> {
+    let mut size: u64 = 0;
+    unsafe { blkgetsize64(device.as_raw_fd(), &mut size) }
+        .context("ioctl(BLKGETSIZE64) to get the size of the device")?;
+    Ok(size)
+}
+
+/// Returns the size in bytes of the block device at `path`.
+pub fn device_size(path: &Path) -> anyhow::Result<u64> {
+    let device = std::fs::File::open(path)
+        .with_context(|| format!("opening block device {}", path.display()))?;
+    block_device_size(&device).with_context(|| format!("getting the size of {}", path.display()))
+}
+
+// BLKFLSBUF from linux/fs.h
+nix::ioctl_none_bad!(blkflsbuf, 0x1261);
+
+/// Writes back and drops the buffer cache of a block device. Requires root privileges.
+pub fn flush_block_device_buffers(device: &std::fs::File) -> anyhow::Result<()> {
+    unsafe { blkflsbuf(device.as_raw_fd()) }.context("ioctl(BLKFLSBUF)")?;
+    Ok(())
+}
+
+/// Drops the clean pages of `file` from the page cache.
+pub fn drop_page_cache(file: &std::fs::File) -> anyhow::Result<()> {
+    nix::fcntl::posix_fadvise(
+        file.as_raw_fd(),
+        0, /* from offset 0 */
+        0, /* full file */
+        nix::fcntl::PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    )
+    .context("posix_fadvise(DONTNEED)")?;
+    Ok(())
+}
+
+/// Drops the clean pages of all regular files below `path` (included) from the page cache,
+/// without following symlinks. Dirty pages are kept, so the file system should be synced first.
+pub fn drop_page_cache_below(path: &Path) -> anyhow::Result<()> {
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry.with_context(|| format!("iterating in {}", path.display()))?;
+        if entry.file_type().is_file() {
+            let f = std::fs::File::open(entry.path())
+                .with_context(|| format!("open {} to drop cache", entry.path().display()))?;
+            drop_page_cache(&f).with_context(|| {
+                format!("dropping the page cache of {}", entry.path().display())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the extended attribute `name` of `path` to `value`, following symlinks.
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    let res = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    nix::errno::Errno::result(res)
+        .with_context(|| format!("setxattr({}, {})", path.display(), name))?;
+    Ok(())
+}
+
+/// Calls `f`, a syscall like getxattr filling a buffer with a value and returning its length,
+/// first without a buffer to get the size of the value, then with a buffer of this size.
+/// Returns `None` when the syscall fails with ENODATA.
+fn read_xattr_value(
+    mut f: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t,
+) -> nix::Result<Option<Vec<u8>>> {
+    use nix::errno::Errno;
+    loop {
+        // first query the size
+        let size = match Errno::result(f(std::ptr::null_mut(), 0)) {
+            Err(nix::Error::Sys(Errno::ENODATA)) => return Ok(None),
+            res => res?,
+        };
+        let mut value = vec![0u8; size as usize];
+        match Errno::result(f(value.as_mut_ptr() as *mut libc::c_void, value.len())) {
+            // the value grew in between
+            Err(nix::Error::Sys(Errno::ERANGE)) => continue,
+            Err(nix::Error::Sys(Errno::ENODATA)) => return Ok(None),
+            res => {
+                value.truncate(res? as usize);
+                return Ok(Some(value));
+            }
+        }
+    }
+}
+
+/// Returns the value of the extended attribute `name` of `path`, following symlinks, or `None`
+/// if it is not set.
+pub fn get_xattr(path: &Path, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let c_name = CString::new(name)?;
+    read_xattr(path, &c_name, true)
+}
+
+/// Returns the value of the extended attribute `name` of `path`, or `None` if it is not set.
+/// Symlinks are followed if `follow`.
+pub fn read_xattr(
+    path: &Path,
+    name: &std::ffi::CStr,
+    follow: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let getxattr = if follow {
+        libc::getxattr
+    } else {
+        libc::lgetxattr
+    };
+    read_xattr_value(|buf, len| unsafe { getxattr(c_path.as_ptr(), name.as_ptr(), buf, len) })
+        .with_context(|| format!("getxattr({}, {:?})", path.display(), name))
+}
+
+/// Returns the names of the extended attributes of `path`, following symlinks if `follow`.
+/// File systems without extended attributes have none.
+pub fn list_xattrs(path: &Path, follow: bool) -> anyhow::Result<Vec<CString>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let listxattr = if follow {
+        libc::listxattr
+    } else {
+        libc::llistxattr
+    };
+    let list = match read_xattr_value(|buf, len| unsafe {
+        listxattr(c_path.as_ptr(), buf as *mut libc::c_char, len)
+    }) {
+        Err(nix::Error::Sys(nix::errno::Errno::ENOTSUP)) => None,
+        res => res.with_context(|| format!("listxattr({})", path.display()))?,
+    };
+    // names are separated by NUL bytes
+    Ok(list
+        .unwrap_or_default()
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| CString::new(name).expect("split on NUL"))
+        .collect())
+}
+
+/// Sets the extended attribute `name` of `path` to `value`, without following symlinks.
+pub fn lset_xattr(path: &Path, name: &std::ffi::CStr, value: &[u8]) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let res = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    match nix::errno::Errno::result(res) {
+        Err(nix::Error::Sys(nix::errno::Errno::ENOTSUP)) => anyhow::bail!(
+            "the file system of {} does not support the extended attribute {:?}",
+            path.display(),
+            name
+        ),
+        res => res.with_context(|| format!("lsetxattr({}, {:?})", path.display(), name))?,
+    };
+    Ok(())
+}
+
+/// Removes the extended attribute `name` of `path`, without following symlinks.
+pub fn lremove_xattr(path: &Path, name: &std::ffi::CStr) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let res = unsafe { libc::lremovexattr(c_path.as_ptr(), name.as_ptr()) };
+    nix::errno::Errno::result(res)
+        .with_context(|| format!("lremovexattr({}, {:?})", path.display(), name))?;
+    Ok(())
+}
+
+/// Returns the offset of the first region of data of `file` at or after `offset`, and the offset
+/// of the hole which follows it, with lseek(SEEK_DATA) and lseek(SEEK_HOLE). Past the last
+/// region of data, both are `len`, the length of the file. Some block devices report their
+/// unmapped regions as holes. Returns `None` if the file system or device cannot tell holes
+/// apart. Moves the file offset of `file`.
+pub fn next_data_region(
+    file: &std::fs::File,
+    offset: u64,
+    len: u64,
+) -> anyhow::Result<Option<(u64, u64)>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    let fd = file.as_raw_fd();
+    let data = match lseek(fd, offset as libc::off_t, Whence::SeekData) {
+        Ok(data) => data,
+        // only holes after offset
+        Err(nix::Error::Sys(Errno::ENXIO)) => return Ok(Some((len.max(offset), len.max(offset)))),
+        Err(nix::Error::Sys(Errno::EINVAL)) | Err(nix::Error::Sys(Errno::ESPIPE)) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context("lseek(SEEK_DATA)"),
+    };
+    let hole = lseek(fd, data, Whence::SeekHole).context("lseek(SEEK_HOLE)")?;
+    Ok(Some((data as u64, hole as u64)))
+}
+
+/// Returns how many pages of the first `len` bytes of `file` are in the page cache.
+pub fn cached_pages(file: &std::fs::File, len: usize) -> anyhow::Result<usize> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error()).context("mmap to count cached pages");
+    }
+    let mut pages = vec![0u8; (len + page_size - 1) / page_size];
+    let res = unsafe { libc::mincore(addr, len, pages.as_mut_ptr()) };
+    let error = std::io::Error::last_os_error();
+    unsafe { libc::munmap(addr, len) };
+    if res != 0 {
+        return Err(error).context("mincore to count cached pages");
+    }
+    Ok(pages.iter().filter(|&&p| p & 1 != 0).count())
+}
+
+/// An I/O scheduling class and level, as set by `ioprio_set(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only gets disk time when nobody else needs it.
+    Idle,
+    /// The default class, with a level from 0 (highest priority) to 7.
+    BestEffort(u8),
+}
+
+impl std::str::FromStr for IoPriority {
+    type Err = String;
+    /// Parses `idle`, `best-effort` or `best-effort:<level>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (class, level) {
+            ("idle", None) => Ok(IoPriority::Idle),
+            ("idle", Some(_)) => Err("the idle I/O scheduling class has no level".to_string()),
+            ("best-effort", None) => Ok(IoPriority::BestEffort(4)),
+            ("best-effort", Some(level)) => match level.parse::<u8>() {
+                Ok(l) if l <= 7 => Ok(IoPriority::BestEffort(l)),
+                _ => Err(format!(
+                    "invalid best-effort level {}, expected 0 to 7",
+                    level
+                )),
+            },
+            _ => Err(format!(
+                "unknown I/O scheduling class {}, expected idle or best-effort",
+                class
+            )),
+        }
+    }
+}
+
+/// A range of bytes of a file, written `start:len` on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub len: u64,
+}
+
+impl std::str::FromStr for ByteRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .find(':')
+            .ok_or_else(|| format!("invalid range {:?}, expected start:len", s))?;
+        let parse = |n: &str| {
+            n.parse::<u64>()
+                .map_err(|e| format!("invalid range {:?}: {}", s, e))
+        };
+        let range = ByteRange {
+            start: parse(&s[..i])?,
+            len: parse(&s[i + 1..])?,
+        };
+        match range.start.checked_add(range.len) {
+            Some(_) => Ok(range),
+            None => Err(format!("range {:?} ends past 2^64", s)),
+        }
+    }
+}
+
+/// One comma separated clause of a `ChmodSpec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChmodClause {
+    /// `Some(true)` if the clause only applies to directories (`D` prefix), `Some(false)` if it
+    /// only applies to other files (`F` prefix).
+    directories: Option<bool>,
+    action: ChmodAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ChmodAction {
+    /// Octal mode, replacing the permissions.
+    Set(u32),
+    /// `who`, `op` and `perms` of a symbolic mode like `go-w`. `who` is a mask of the affected
+    /// bits, `perms` are the letters `rwxX`.
+    Symbolic { who: u32, op: u8, perms: String },
+}
+
+/// Permissions to apply to copies, in the syntax of rsync's `--chmod`: comma separated octal
+/// modes or symbolic modes like `a+r,u+w`, optionally prefixed with `D` or `F` to only apply to
+/// directories or to other files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChmodSpec(Vec<ChmodClause>);
+
+impl std::str::FromStr for ChmodSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut clauses = Vec::new();
+        for clause in s.split(',') {
+            let (directories, rest) = if let Some(rest) = clause.strip_prefix('D') {
+                (Some(true), rest)
+            } else if let Some(rest) = clause.strip_prefix('F') {
+                (Some(false), rest)
+            } else {
+                (None, clause)
+            };
+            let action = if !rest.is_empty() && rest.bytes().all(|b| b.is_ascii_digit()) {
+                let mode = u32::from_str_radix(rest, 8)
+                    .map_err(|e| format!("invalid octal mode {:?}: {}", rest, e))?;
+                if mode > 0o7777 {
+                    return Err(format!("invalid octal mode {:?}", rest));
+                }
+                ChmodAction::Set(mode)
+            } else {
+                let i = rest
+                    .find(['+', '-', '='])
+                    .ok_or_else(|| format!("no +, - or = in mode {:?}", clause))?;
+                let mut who = 0;
+                for c in rest[..i].chars() {
+                    who |= match c {
+                        'u' => 0o4700,
+                        'g' => 0o2070,
+                        'o' => 0o1007,
+                        'a' => 0o7777,
+                        _ => return Err(format!("invalid user class {:?} in {:?}", c, clause)),
+                    }
+                }
+                let perms = &rest[i + 1..];
+                if let Some(c) = perms.chars().find(|c| !"rwxX".contains(*c)) {
+                    return Err(format!("invalid permission {:?} in {:?}", c, clause));
+                }
+                ChmodAction::Symbolic {
+                    who: if who == 0 { 0o7777 } else { who },
+                    op: rest.as_bytes()[i],
+                    perms: perms.to_owned(),
+                }
+            };
+            clauses.push(ChmodClause {
+                directories,
+                action,
+            });
+        }
+        Ok(ChmodSpec(clauses))
+    }
+}
+
+impl ChmodSpec {
+    /// Returns the permissions resulting from applying this spec to a directory if `directory`,
+    /// or another file otherwise, which had permissions `mode`.
+    pub fn apply(&self, mode: u32, directory: bool) -> u32 {
+        let mut mode = mode & 0o7777;
+        for clause in &self.0 {
+            if clause.directories.map_or(false, |d| d != directory) {
+                continue;
+            }
+            match &clause.action {
+                ChmodAction::Set(new) => mode = *new,
+                ChmodAction::Symbolic { who, op, perms } => {
+                    let mut bits = 0;
+                    for c in perms.chars() {
+                        bits |= match c {
+                            'r' => 0o444,
+                            'w' => 0o222,
+                            'x' => 0o111,
+                            // X: execute only for directories and already executable files
+                            _ if directory || mode & 0o111 != 0 => 0o111,
+                            _ => 0,
+                        };
+                    }
+                    let bits = bits & who;
+                    mode = match op {
+                        b'+' => mode | bits,
+                        b'-' => mode & !bits,
+                        _ => (mode & !who) | bits,
+                    };
+                }
+            }
+        }
+        mode
+    }
+}
+
+/// Parses a duration like `90`, `90s`, `5m` or `2h`. Seconds are the default unit.
+pub fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => {
+            return Err(format!(
+                "unknown unit {:?} in duration {:?}, expected s, m or h",
+                unit, s
+            ))
+        }
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid duration {:?}: {}", s, e))?;
+    number
+        .checked_mul(factor)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("duration {:?} is too large", s))
+}
+
+/// Parses a size in bytes like `4096`, `512K`, `4M` or `1G`, with binary multiples.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    };
+    let shift = match unit {
+        "" => 0,
+        "k" | "K" => 10,
+        "m" | "M" => 20,
+        "g" | "G" => 30,
+        _ => {
+            return Err(format!(
+                "unknown unit {:?} in size {:?}, expected K, M or G",
+                unit, s
+            ))
+        }
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid size {:?}: {}", s, e))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", s))
+}
+
+/// Sets the I/O scheduling class of the current thread, and threads it spawns later.
+pub fn set_io_priority(priority: IoPriority) -> anyhow::Result<()> {
+    // from linux/ioprio.h
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    const IOPRIO_CLASS_BE: libc::c_long = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    let value = match priority {
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoPriority::BestEffort(level) => {
+            (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level as libc::c_long
+        }
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0 as libc::c_long, /* current thread */
+            value,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("ioprio_set({:?})", priority));
+    }
+    Ok(())
+}
+
+/// Returns `s` as a JSON string literal, including the surrounding quotes.
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// A small seedable pseudo random number generator (splitmix64), good enough to draw samples
+/// reproducibly but not for cryptography.
+#[derive(Debug, Clone)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`, with a negligible bias. `n` must not be 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Roughly how many rounds copying `blocks` blocks takes, if `bad` of `sampled` blocks read back
+/// wrong in a sample and blocks are independent: the first round copies everything, and each
+/// following one fixes the blocks found wrong by the previous one, until none is. Returns `None`
+/// if every sampled block was wrong, as the copy would never end.
+pub fn expected_rounds(sampled: u64, bad: u64, blocks: u64) -> Option<u64> {
+    if bad >= sampled {
+        return None;
+    }
+    let p = bad as f64 / sampled as f64;
+    // fixing rounds until fewer than one block is expected to be wrong: blocks * p^k < 1
+    let mut k = 1;
+    let mut wrong = blocks as f64 * p;
+    while wrong >= 1. {
+        wrong *= p;
+        k += 1;
+    }
+    Some(1 + k)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -178,4 +796,92 @@ mod test {
     fn test_change_prefixes_wrong_prefix() {
         test_change_prefix("/a", "/b", "/c", None)
     }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("abc"), r#""abc""#);
+        assert_eq!(json_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(json_string("a\nb\u{1}"), r#""a\nb\u0001""#);
+        assert_eq!(json_string("é"), r#""é""#);
+    }
+
+    #[test]
+    fn test_chmod_spec() {
+        let spec: ChmodSpec = "D755,F644".parse().unwrap();
+        assert_eq!(spec.apply(0o40700, true), 0o755);
+        assert_eq!(spec.apply(0o100600, false), 0o644);
+        let spec: ChmodSpec = "a+r,u+w,go-w".parse().unwrap();
+        assert_eq!(spec.apply(0o600, false), 0o644);
+        assert_eq!(spec.apply(0o777, false), 0o755);
+        let spec: ChmodSpec = "a+X".parse().unwrap();
+        assert_eq!(spec.apply(0o700, true), 0o711);
+        assert_eq!(spec.apply(0o600, false), 0o600);
+        assert_eq!(spec.apply(0o700, false), 0o711);
+        let spec: ChmodSpec = "o=r".parse().unwrap();
+        assert_eq!(spec.apply(0o777, false), 0o774);
+        assert!("a+q".parse::<ChmodSpec>().is_err());
+        assert!("v+r".parse::<ChmodSpec>().is_err());
+        assert!("u".parse::<ChmodSpec>().is_err());
+        assert!("9".parse::<ChmodSpec>().is_err());
+        assert!("17777".parse::<ChmodSpec>().is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(
+            "512:1024".parse(),
+            Ok(ByteRange {
+                start: 512,
+                len: 1024
+            })
+        );
+        assert_eq!("0:0".parse(), Ok(ByteRange { start: 0, len: 0 }));
+        assert!("512".parse::<ByteRange>().is_err());
+        assert!("a:1".parse::<ByteRange>().is_err());
+        assert!("1:-1".parse::<ByteRange>().is_err());
+        assert!("18446744073709551615:1".parse::<ByteRange>().is_err());
+    }
+
+    #[test]
+    fn test_expected_rounds() {
+        assert_eq!(expected_rounds(100, 0, 1_000_000), Some(2));
+        assert_eq!(expected_rounds(100, 1, 50), Some(2));
+        assert_eq!(expected_rounds(100, 1, 1_000_000), Some(5));
+        assert_eq!(expected_rounds(100, 100, 1), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("4m"), Ok(4 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("17179869184G").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("-5").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_parse_io_priority() {
+        assert_eq!("idle".parse(), Ok(IoPriority::Idle));
+        assert_eq!("best-effort".parse(), Ok(IoPriority::BestEffort(4)));
+        assert_eq!("best-effort:7".parse(), Ok(IoPriority::BestEffort(7)));
+        assert!("best-effort:8".parse::<IoPriority>().is_err());
+        assert!("idle:1".parse::<IoPriority>().is_err());
+        assert!("realtime".parse::<IoPriority>().is_err());
+    }
 }
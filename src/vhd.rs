@@ -0,0 +1,144 @@
+//! Footer of fixed size VHD images, see the "Virtual Hard Disk Image Format Specification".
+
+use crate::checksum::{ChecksumAlgorithm, Hasher};
+use clap::arg_enum;
+use std::os::unix::fs::MetadataExt;
+
+arg_enum! {
+    /// Container format of a single file destination.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum DestFormat {
+        Raw,
+        Img,
+        Vhd,
+    }
+}
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Seconds between the unix epoch and the VHD epoch, 2000-01-01 00:00:00 UTC.
+const VHD_EPOCH: u64 = 946_684_800;
+
+/// The parts of a VHD footer which do not depend on the size of the disk. They are derived from
+/// the source, so that the footer is the same every time it is written and checked, even by
+/// another run of cccp.
+#[derive(Debug, Clone)]
+pub struct VhdFooter {
+    timestamp: u32,
+    unique_id: [u8; 16],
+}
+
+impl VhdFooter {
+    /// Creates the footer of the image of a source with metadata `source`: it is stamped with
+    /// the modification time of the source, and its unique id is a hash of its size and
+    /// modification time.
+    pub fn new(source: &std::fs::Metadata) -> Self {
+        let timestamp = (source.mtime().max(0) as u64).saturating_sub(VHD_EPOCH) as u32;
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(source.size().to_be_bytes());
+        hasher.update(source.rdev().to_be_bytes());
+        hasher.update(source.mtime().to_be_bytes());
+        hasher.update(source.mtime_nsec().to_be_bytes());
+        let mut unique_id = [0; 16];
+        unique_id.copy_from_slice(&hasher.finish().as_bytes()[..16]);
+        // name based UUID, version 5, though hashed with SHA-256 instead of SHA-1
+        unique_id[6] = (unique_id[6] & 0x0f) | 0x50;
+        unique_id[8] = (unique_id[8] & 0x3f) | 0x80;
+        VhdFooter {
+            timestamp,
+            unique_id,
+        }
+    }
+
+    /// Returns what must follow `data_len` bytes of disk data to make a fixed VHD: zero padding
+    /// up to a whole sector, then the 512 bytes footer.
+    pub fn trailer(&self, data_len: u64) -> Vec<u8> {
+        let padding = (SECTOR_SIZE - data_len % SECTOR_SIZE) % SECTOR_SIZE;
+        let size = data_len + padding;
+        let mut res = vec![0; padding as usize];
+        res.extend_from_slice(&self.footer(size));
+        res
+    }
+
+    fn footer(&self, size: u64) -> [u8; 512] {
+        let mut footer = [0u8; 512];
+        footer[0..8].copy_from_slice(b"conectix");
+        // features: reserved bit, always set
+        footer[8..12].copy_from_slice(&2u32.to_be_bytes());
+        // file format version
+        footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        // data offset: none for fixed disks
+        footer[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+        footer[24..28].copy_from_slice(&self.timestamp.to_be_bytes());
+        footer[28..32].copy_from_slice(b"cccp");
+        // creator version
+        footer[32..36].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        // creator host os
+        footer[36..40].copy_from_slice(b"Wi2k");
+        // original and current size
+        footer[40..48].copy_from_slice(&size.to_be_bytes());
+        footer[48..56].copy_from_slice(&size.to_be_bytes());
+        let (cylinders, heads, sectors) = geometry(size / SECTOR_SIZE);
+        footer[56..58].copy_from_slice(&cylinders.to_be_bytes());
+        footer[58] = heads;
+        footer[59] = sectors;
+        // disk type: fixed
+        footer[60..64].copy_from_slice(&2u32.to_be_bytes());
+        footer[68..84].copy_from_slice(&self.unique_id);
+        let checksum = !footer
+            .iter()
+            .fold(0u32, |acc, &b| acc.wrapping_add(b as u32));
+        footer[64..68].copy_from_slice(&checksum.to_be_bytes());
+        footer
+    }
+}
+
+/// Computes the CHS geometry of a disk of `total_sectors` sectors, with the algorithm of the
+/// specification.
+fn geometry(total_sectors: u64) -> (u16, u8, u8) {
+    let total_sectors = total_sectors.min(65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = ((cylinder_times_heads + 1023) / 1024).max(4);
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors_per_track as u8,
+    )
+}
+
+#[test]
+fn test_vhd_trailer() {
+    let footer = VhdFooter {
+        timestamp: 0,
+        unique_id: [0; 16],
+    };
+    for &len in &[0u64, 1, 511, 512, 1 << 30] {
+        let trailer = footer.trailer(len);
+        assert_eq!((len + trailer.len() as u64) % SECTOR_SIZE, 0);
+        let f = &trailer[trailer.len() - 512..];
+        assert_eq!(&f[0..8], b"conectix");
+        let sum = f[..64]
+            .iter()
+            .chain(&f[68..])
+            .fold(0u32, |acc, &b| acc.wrapping_add(b as u32));
+        assert_eq!(u32::from_be_bytes([f[64], f[65], f[66], f[67]]), !sum);
+    }
+    // 1 GiB
+    assert_eq!(geometry(2097152), (2080, 16, 63));
+}
@@ -0,0 +1,232 @@
+//! Checksums of verified copies stored in extended attributes of the copy, as requested with
+//! `--checksum-store-in-xattr` and `--checksum-tree-root-xattr`, so that `--verify-only` can
+//! detect bit rot later without the source.
+
+use crate::cache::CacheManager;
+use crate::checksum::{Checksum, ChecksumAlgorithm, Hasher};
+use crate::copy::MetadataFields;
+use crate::progress::Progress;
+use crate::throttle::Throttle;
+use crate::utils::{change_prefixes, get_xattr, set_xattr};
+use anyhow::Context;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Prefix of the names of the extended attributes stored by cccp.
+pub const XATTR_PREFIX: &str = "user.cccp.";
+
+/// Name of the extended attribute of regular files. Its value is
+/// `<algorithm>:<hexadecimal checksum>`.
+pub const XATTR_NAME: &str = "user.cccp.checksum";
+
+/// Name of the extended attribute of the root of the copy with the checksum of the whole tree,
+/// in the same format as `XATTR_NAME`.
+pub const TREE_XATTR_NAME: &str = "user.cccp.tree";
+
+fn format_value(algorithm: ChecksumAlgorithm, checksum: Checksum) -> String {
+    format!("{}:{}", algorithm.to_string().to_lowercase(), checksum)
+}
+
+pub fn parse_value(value: &[u8]) -> anyhow::Result<(ChecksumAlgorithm, Checksum)> {
+    let value = std::str::from_utf8(value).context("value is not utf8")?;
+    let mut parts = value.splitn(2, ':');
+    let (algorithm, checksum) = match (parts.next(), parts.next()) {
+        (Some(a), Some(c)) => (a, c),
+        _ => anyhow::bail!("value {:?} has no colon", value),
+    };
+    let algorithm: ChecksumAlgorithm = algorithm
+        .parse()
+        .map_err(|e| anyhow::anyhow!("unknown checksum algorithm {:?}: {}", algorithm, e))?;
+    anyhow::ensure!(
+        algorithm != ChecksumAlgorithm::None,
+        "no checksum stored in {:?}",
+        value
+    );
+    let checksum = checksum
+        .parse()
+        .with_context(|| format!("invalid checksum {:?}", checksum))?;
+    Ok((algorithm, checksum))
+}
+
+/// Stamps the regular file `path`, whose content has checksum `checksum`.
+pub fn store_checksum(
+    path: &Path,
+    algorithm: ChecksumAlgorithm,
+    checksum: Checksum,
+) -> anyhow::Result<()> {
+    set_xattr(
+        path,
+        XATTR_NAME,
+        format_value(algorithm, checksum).as_bytes(),
+    )
+}
+
+fn stored_value(path: &Path, name: &str) -> anyhow::Result<Option<(ChecksumAlgorithm, Checksum)>> {
+    match get_xattr(path, name)? {
+        None => Ok(None),
+        Some(value) => parse_value(&value)
+            .with_context(|| format!("parsing {} of {}", name, path.display()))
+            .map(Some),
+    }
+}
+
+/// Returns the checksum stored by `store_checksum` for `path`, if any.
+pub fn stored_checksum(path: &Path) -> anyhow::Result<Option<(ChecksumAlgorithm, Checksum)>> {
+    stored_value(path, XATTR_NAME)
+}
+
+/// Returns a checksum accumulating the checksums of the entries of a tree with `add_to_tree`.
+pub fn new_tree_checksum(algorithm: ChecksumAlgorithm) -> Checksum {
+    Hasher::new(algorithm).finish()
+}
+
+/// Adds the entry at `relative` path from the root of a tree, whose checksum is `checksum`, to
+/// the checksum of the tree. The root itself has the empty relative path. As for directories,
+/// entries are combined with XOR, so the order in which they are added does not matter.
+pub fn add_to_tree(
+    tree: &mut Checksum,
+    algorithm: ChecksumAlgorithm,
+    relative: &Path,
+    checksum: Checksum,
+) {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(relative.as_os_str().as_bytes());
+    hasher.update([0]);
+    hasher.update(checksum.to_string());
+    *tree ^= hasher.finish();
+}
+
+/// Stamps the root `path` of a copy with the checksum of the whole tree.
+pub fn store_tree_checksum(
+    path: &Path,
+    algorithm: ChecksumAlgorithm,
+    checksum: Checksum,
+) -> anyhow::Result<()> {
+    set_xattr(
+        path,
+        TREE_XATTR_NAME,
+        format_value(algorithm, checksum).as_bytes(),
+    )
+}
+
+/// Checks that the regular files below `path` still have the checksum stored by
+/// `store_checksum`, and that the tree has the checksum stored by `store_tree_checksum`,
+/// reading them without cache, `block_size` bytes at a time at the rate allowed by `throttle`.
+/// `metadata` must be what the checksums of the copy covered. Files without a stored checksum
+/// are skipped. Returns an error if anything does not match.
+pub fn verify_tree(
+    cache_manager: &mut dyn CacheManager,
+    mut progress: Progress,
+    path: &Path,
+    block_size: usize,
+    throttle: Option<&Throttle>,
+    metadata: Option<MetadataFields>,
+) -> anyhow::Result<()> {
+    progress.syncing();
+    let path = match cache_manager
+        .drop_cache(&progress, path)
+        .with_context(|| format!("Dropping cache below {}", path.display()))?
+    {
+        Some(replacement) => change_prefixes(&replacement.before, &replacement.after)(path),
+        None => path.to_path_buf(),
+    };
+    progress.next_round(0);
+    let tree_stamp = stored_value(&path, TREE_XATTR_NAME)?;
+    let mut tree = tree_stamp.map(|(algorithm, _)| new_tree_checksum(algorithm));
+    let mut checked = 0u64;
+    let mut unstamped = 0u64;
+    let mut mismatches = 0u64;
+    for entry in walkdir::WalkDir::new(&path) {
+        let entry = entry.with_context(|| format!("iterating in {}", path.display()))?;
+        let file = entry.path();
+        let stamp = if entry.file_type().is_file() {
+            let stamp = stored_checksum(file)?;
+            if stamp.is_none() {
+                unstamped += 1;
+            }
+            stamp
+        } else {
+            None
+        };
+        progress.set_status(format!("Verifying {}", file.display()));
+        let mut computed: Option<(ChecksumAlgorithm, Checksum)> = None;
+        if let Some((algorithm, expected)) = stamp {
+            let actual = crate::copy::checksum_path(
+                cache_manager,
+                algorithm,
+                block_size,
+                throttle,
+                metadata,
+                false,
+                file,
+            )?;
+            computed = Some((algorithm, actual));
+            checked += 1;
+            if actual != expected {
+                mismatches += 1;
+                progress.warn(format!(
+                    "{} has checksum {} instead of {}",
+                    file.display(),
+                    actual,
+                    expected
+                ));
+            }
+        }
+        if let (Some(tree), Some((algorithm, _))) = (tree.as_mut(), tree_stamp) {
+            let actual = match computed {
+                Some((a, actual)) if a == algorithm => actual,
+                _ => crate::copy::checksum_path(
+                    cache_manager,
+                    algorithm,
+                    block_size,
+                    throttle,
+                    metadata,
+                    false,
+                    file,
+                )?,
+            };
+            let relative = file.strip_prefix(&path)?;
+            add_to_tree(tree, algorithm, relative, actual);
+        }
+    }
+    if let (Some(actual), Some((_, expected))) = (tree, tree_stamp) {
+        if actual != expected {
+            mismatches += 1;
+            progress.warn(format!(
+                "the tree below {} has checksum {} instead of {}",
+                path.display(),
+                actual,
+                expected
+            ));
+        }
+    } else if unstamped != 0 {
+        progress.warn(format!(
+            "{} files below {} have no {} attribute and were not checked",
+            unstamped,
+            path.display(),
+            XATTR_NAME
+        ));
+    }
+    progress.done();
+    anyhow::ensure!(
+        mismatches == 0,
+        "{} of {} checked files or trees do not match their stored checksum",
+        mismatches,
+        checked + tree_stamp.is_some() as u64
+    );
+    Ok(())
+}
+
+#[test]
+fn test_xattr_value() {
+    let value = format_value(
+        ChecksumAlgorithm::Crc64,
+        "00000000deadbeef".parse().unwrap(),
+    );
+    assert_eq!(value, "crc64:00000000deadbeef");
+    let (algorithm, checksum) = parse_value(value.as_bytes()).unwrap();
+    assert_eq!(algorithm, ChecksumAlgorithm::Crc64);
+    assert_eq!(format_value(algorithm, checksum), value);
+    assert!(parse_value(b"none:0").is_err());
+    assert!(parse_value(b"crc64").is_err());
+}
@@ -7,16 +7,25 @@ use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::process::Command;
 
-/// runs cccp with corresponding arguments
+/// runs cccp to make destination a copy of source, even if it is an existing directory
 fn run(t: &TestDir, source: &Path, destination: &Path) {
     let mut c = t.cmd();
     c.env("CCCP_NO_ROOT", "1");
     c.current_dir(t.path("."));
-    c.arg("--once");
+    c.args(["--once", "--no-target-directory"]);
     c.args(&[source, destination]);
     dbg!(c).expect_success();
 }
 
+/// returns a command running cccp with `args` in the test directory, without root
+fn cccp(t: &TestDir, args: &[impl AsRef<OsStr>]) -> Command {
+    let mut c = t.cmd();
+    c.env("CCCP_NO_ROOT", "1");
+    c.current_dir(t.path("."));
+    c.args(args);
+    c
+}
+
 /// panics if source and destination are different (with diffoscope)
 fn compare(t: &TestDir, source: &Path, destination: &Path) {
     let mut c = Command::new("diffoscope");
@@ -72,3 +81,540 @@ fn main() -> anyhow::Result<()> {
 fn run_all_tests() {
     main().unwrap();
 }
+
+/// git cannot store hard links, so this fixture is created at runtime
+#[test]
+fn hard_links() {
+    use std::os::unix::fs::MetadataExt;
+    let t = TestDir::new("cccp", "hard_links");
+    let source = t.path("hard_links.orig");
+    std::fs::create_dir(&source).unwrap();
+    std::fs::write(source.join("a"), b"twice").unwrap();
+    std::fs::hard_link(source.join("a"), source.join("b")).unwrap();
+    let working = "./dest".as_ref();
+    run(&t, "hard_links.orig".as_ref(), working);
+    compare(&t, "hard_links.orig".as_ref(), working);
+    let a = std::fs::metadata(t.path("dest/a")).unwrap();
+    let b = std::fs::metadata(t.path("dest/b")).unwrap();
+    assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+}
+
+#[test]
+fn dest_symlink_to_source() {
+    let t = TestDir::new("cccp", "dest_symlink_to_source");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::os::unix::fs::symlink("source", t.path("dest")).unwrap();
+    let c = cccp(&t, &["--once", "source", "dest"]);
+    let output = dbg!(c).output().expect("running cccp");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("resolve to the same path"));
+}
+
+#[test]
+fn exclude_by_size() {
+    let t = TestDir::new("cccp", "exclude_by_size");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/small"), b"1").unwrap();
+    std::fs::write(t.path("source/medium"), [0u8; 100]).unwrap();
+    std::fs::write(t.path("source/large"), [0u8; 10000]).unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--exclude-smaller-than=10",
+            "--exclude-larger-than=1K",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    assert!(t.path("dest/medium").exists());
+    assert!(!t.path("dest/small").exists());
+    assert!(!t.path("dest/large").exists());
+}
+
+#[test]
+fn write_then_verify_manifest() {
+    let t = TestDir::new("cccp", "write_then_verify_manifest");
+    std::fs::create_dir_all(t.path("source/dir")).unwrap();
+    std::fs::write(t.path("source/dir/file"), b"content").unwrap();
+    let c = cccp(
+        &t,
+        &["--once", "--write-manifest=manifest", "source", "dest"],
+    );
+    dbg!(c).expect_success();
+    let manifest = std::fs::read_to_string(t.path("manifest")).unwrap();
+    assert!(manifest.contains(":7  dir/file\n"));
+    let c = cccp(&t, &["--verify-manifest=manifest", "dest"]);
+    dbg!(c).expect_success();
+}
+
+#[test]
+fn manifest_relative_to() {
+    let t = TestDir::new("cccp", "manifest_relative_to");
+    std::fs::create_dir_all(t.path("source/dir")).unwrap();
+    std::fs::write(t.path("source/dir/file"), b"content").unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--write-manifest=manifest",
+            "--manifest-relative-to=.",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    let manifest = std::fs::read_to_string(t.path("manifest")).unwrap();
+    assert!(manifest.contains(":7  dest/dir/file\n"));
+    assert!(manifest.contains("  dest\n"));
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--write-manifest=manifest",
+            "--manifest-relative-to=source",
+            "-T",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    let manifest = std::fs::read_to_string(t.path("manifest")).unwrap();
+    assert!(manifest.contains(":7  dir/file\n"));
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--write-manifest=manifest",
+            "--manifest-relative-to=source/dir",
+            "-T",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_failure();
+}
+
+#[test]
+fn verify_manifest_follow_symlinks() {
+    let t = TestDir::new("cccp", "verify_manifest_follow_symlinks");
+    std::fs::create_dir_all(t.path("source/dir")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::os::unix::fs::symlink("../file", t.path("source/dir/link")).unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "-L",
+            "--write-manifest=manifest",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    // the source still has the symlink, which counts as the file it points to
+    let c = cccp(&t, &["-L", "--verify-manifest=manifest", "source"]);
+    dbg!(c).expect_success();
+}
+
+#[test]
+fn checksum_parallel_chunks() {
+    let t = TestDir::new("cccp", "checksum_parallel_chunks");
+    std::fs::create_dir(t.path("source")).unwrap();
+    let content: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    std::fs::write(t.path("source/file"), &content).unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--checksum-parallel-chunks",
+            "--block-size=4194304",
+            "--write-manifest=manifest",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), content);
+    // the checksums are those of sequential crc64
+    let c = cccp(&t, &["--verify-manifest=manifest", "dest"]);
+    dbg!(c).expect_success();
+}
+
+#[test]
+fn checksum_include_metadata() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let t = TestDir::new("cccp", "checksum_include_metadata");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::set_permissions(
+        t.path("source/file"),
+        std::fs::Permissions::from_mode(0o666),
+    )
+    .unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--checksum-include-metadata",
+            "--write-manifest=manifest",
+            "source",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    let mode = std::fs::metadata(t.path("dest/file")).unwrap().mode();
+    assert_eq!(mode & 0o7777, 0o666);
+    let c = cccp(
+        &t,
+        &[
+            "--checksum-include-metadata",
+            "--verify-manifest=manifest",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    std::fs::set_permissions(t.path("dest/file"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--checksum-include-metadata",
+            "--verify-manifest=manifest",
+            "dest",
+        ],
+    );
+    let output = dbg!(c).expect_failure();
+    // the manifest was found, and the file no longer matches it
+    assert!(String::from_utf8_lossy(&output.stderr).contains("FAILED, checksum"));
+}
+
+#[test]
+fn verify_subcommand() {
+    let t = TestDir::new("cccp", "verify_subcommand");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    let c = cccp(&t, &["--once", "source", "dest"]);
+    dbg!(c).expect_success();
+    let c = cccp(&t, &["verify", "source", "dest"]);
+    dbg!(c).expect_success();
+    std::fs::write(t.path("dest/file"), b"corrupt").unwrap();
+    let c = cccp(&t, &["verify", "source", "dest"]);
+    dbg!(c).expect_failure();
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"corrupt");
+}
+
+#[test]
+fn resume_with_state() {
+    let t = TestDir::new("cccp", "resume_with_state");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::write(t.path("source/other"), b"other content").unwrap();
+    // as if a previous run was interrupted after copying the file
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::fs::write(t.path("dest/file"), b"content").unwrap();
+    let c = cccp(&t, &["--resume", "--resume-state=state", "source/", "dest"]);
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"content");
+    assert_eq!(
+        std::fs::read(t.path("dest/other")).unwrap(),
+        b"other content"
+    );
+    assert!(!t.path("state").exists());
+}
+
+#[test]
+fn several_sources() {
+    let t = TestDir::new("cccp", "several_sources");
+    std::fs::create_dir(t.path("dir")).unwrap();
+    std::fs::write(t.path("dir/file"), b"in dir").unwrap();
+    std::fs::write(t.path("file"), b"alone").unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    let c = cccp(&t, &["--once", "dir", "file", "dest"]);
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/dir/file")).unwrap(), b"in dir");
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"alone");
+    let c = cccp(&t, &["--once", "dir", "file", "missing"]);
+    dbg!(c).expect_failure();
+}
+
+#[test]
+fn into_existing_directory() {
+    let t = TestDir::new("cccp", "into_existing_directory");
+    std::fs::create_dir(t.path("dir")).unwrap();
+    std::fs::write(t.path("dir/file"), b"in dir").unwrap();
+    std::fs::write(t.path("file"), b"alone").unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    // the second time, dest/content exists
+    let runs = [
+        ["file", "dest"],
+        ["dir", "dest"],
+        ["dir/", "dest/content"],
+        ["dir/", "dest/content"],
+    ];
+    for args in runs {
+        let mut c = cccp(&t, &["--once"]);
+        c.args(args);
+        dbg!(c).expect_success();
+    }
+    // a file into a directory
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"alone");
+    // a directory into a directory
+    assert_eq!(std::fs::read(t.path("dest/dir/file")).unwrap(), b"in dir");
+    // the content of a directory into a directory
+    assert_eq!(
+        std::fs::read(t.path("dest/content/file")).unwrap(),
+        b"in dir"
+    );
+    assert!(!t.path("dest/content/dir").exists());
+}
+
+#[test]
+fn exclude_globs() {
+    let t = TestDir::new("cccp", "exclude_globs");
+    std::fs::create_dir_all(t.path("source/.git")).unwrap();
+    std::fs::write(t.path("source/.git/HEAD"), b"ref").unwrap();
+    std::fs::write(t.path("source/main.o"), b"object").unwrap();
+    std::fs::write(t.path("source/main.c"), b"code").unwrap();
+    // copies of excluded entries in an existing copy are kept
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::fs::write(t.path("dest/old.o"), b"old object").unwrap();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--exclude=.git",
+            "--exclude=*.o",
+            "source/",
+            "dest",
+        ],
+    );
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/main.c")).unwrap(), b"code");
+    assert!(!t.path("dest/.git").exists());
+    assert!(!t.path("dest/main.o").exists());
+    assert_eq!(std::fs::read(t.path("dest/old.o")).unwrap(), b"old object");
+}
+
+#[test]
+fn verbose_logs_actions() {
+    let t = TestDir::new("cccp", "verbose_logs_actions");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::fs::write(t.path("dest/extra"), b"extra").unwrap();
+    let mut c = cccp(&t, &["--once", "-vv", "source/", "dest"]);
+    c.env_remove("RUST_LOG");
+    let output = dbg!(c).expect_success();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("copying ") && stderr.contains("dest/file"));
+    assert!(stderr.contains("removing ") && stderr.contains("dest/extra"));
+    // -vv also logs checksums
+    assert!(stderr.contains("checksum"));
+}
+
+#[test]
+fn dry_run() {
+    let t = TestDir::new("cccp", "dry_run");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    let c = cccp(&t, &["--dry-run", "source", "dest"]);
+    let output = dbg!(c).output().expect("running cccp");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Would copy"));
+    assert!(!t.path("dest").exists());
+    let c = cccp(&t, &["--once", "source", "dest"]);
+    dbg!(c).expect_success();
+    let c = cccp(&t, &["--dry-run", "source/", "dest"]);
+    dbg!(c).expect_success();
+}
+
+#[test]
+fn dry_run_directory_in_the_way() {
+    let t = TestDir::new("cccp", "dry_run_directory_in_the_way");
+    std::fs::create_dir_all(t.path("source/dir")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::create_dir_all(t.path("dest/file")).unwrap();
+    std::fs::write(t.path("dest/dir"), b"content").unwrap();
+    let c = cccp(&t, &["--dry-run", "source/", "dest"]);
+    let output = dbg!(c).output().expect("running cccp");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("panicked"));
+    assert!(stderr.contains("is not a regular file"));
+    assert!(stderr.contains("is not a directory"));
+    assert!(stderr.contains("would be copied or fixed"));
+    assert!(t.path("dest/file").is_dir());
+    assert_eq!(std::fs::read(t.path("dest/dir")).unwrap(), b"content");
+}
+
+#[test]
+fn delete_never_keeps_extra_files() {
+    let t = TestDir::new("cccp", "delete_never_keeps_extra_files");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::fs::write(t.path("dest/extra"), b"extra").unwrap();
+    let c = cccp(&t, &["--once", "--delete=never", "source/", "dest"]);
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"content");
+    assert_eq!(std::fs::read(t.path("dest/extra")).unwrap(), b"extra");
+    let c = cccp(&t, &["--once", "source/", "dest"]);
+    dbg!(c).expect_success();
+    assert!(!t.path("dest/extra").exists());
+}
+
+#[test]
+fn delete_never_keeps_longer_files() {
+    let t = TestDir::new("cccp", "delete_never_keeps_longer_files");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/file"), b"content").unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::fs::write(t.path("dest/file"), b"content and more").unwrap();
+    let c = cccp(&t, &["--once", "--delete=never", "source/", "dest"]);
+    let output = dbg!(c).expect_failure();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("its end was kept"));
+    assert_eq!(
+        std::fs::read(t.path("dest/file")).unwrap(),
+        b"content and more"
+    );
+    let c = cccp(&t, &["--once", "source/", "dest"]);
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/file")).unwrap(), b"content");
+}
+
+#[test]
+fn keep_going() {
+    let t = TestDir::new("cccp", "keep_going");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/a"), b"a").unwrap();
+    std::fs::write(t.path("source/b"), b"b").unwrap();
+    // in the way of the copy of a
+    std::fs::create_dir_all(t.path("dest/a")).unwrap();
+    let c = cccp(&t, &["--once", "--delete=never", "source/", "dest"]);
+    dbg!(c).expect_failure();
+    let c = cccp(
+        &t,
+        &[
+            "--once",
+            "--delete=never",
+            "--keep-going",
+            "--write-manifest",
+            "manifest",
+            "source/",
+            "dest",
+        ],
+    );
+    let output = dbg!(c).expect_failure();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("skipped with --keep-going"));
+    assert_eq!(std::fs::read(t.path("dest/b")).unwrap(), b"b");
+    assert!(t.path("dest/a").is_dir());
+    // the manifest would not describe the copy
+    assert!(!t.path("manifest").exists());
+}
+
+/// git cannot store symlinks to non-utf8 paths portably, so this fixture is created at runtime
+#[test]
+fn symlink_targets() {
+    let t = TestDir::new("cccp", "symlink_targets");
+    let source = t.path("symlink_targets.orig");
+    std::fs::create_dir(&source).unwrap();
+    let targets: [(&str, &[u8]); 3] = [
+        ("non_utf8", b"caf\xff"),
+        ("absolute", b"/nonexistent/file"),
+        ("relative", b"../sibling/file"),
+    ];
+    for (name, target) in targets.iter() {
+        std::os::unix::fs::symlink(OsStr::from_bytes(target), source.join(name)).unwrap();
+    }
+    // the same as the source once decoded lossily as utf8
+    std::fs::create_dir(t.path("dest")).unwrap();
+    std::os::unix::fs::symlink(OsStr::from_bytes(b"caf\xfe"), t.path("dest/non_utf8")).unwrap();
+    let working = "./dest".as_ref();
+    run(&t, "symlink_targets.orig".as_ref(), working);
+    for (name, target) in targets.iter() {
+        let copy = std::fs::read_link(t.path("dest").join(name)).unwrap();
+        assert_eq!(copy.as_os_str().as_bytes(), *target);
+    }
+}
+
+#[test]
+fn follow_symlinks() {
+    let t = TestDir::new("cccp", "follow_symlinks");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("file"), b"content").unwrap();
+    std::os::unix::fs::symlink("../file", t.path("source/link")).unwrap();
+    let c = cccp(&t, &["--once", "--follow-symlinks", "source", "dest"]);
+    dbg!(c).expect_success();
+    let copy = t.path("dest/link");
+    assert!(std::fs::symlink_metadata(&copy).unwrap().is_file());
+    assert_eq!(std::fs::read(&copy).unwrap(), b"content");
+    std::os::unix::fs::symlink("nonexistent", t.path("source/dangling")).unwrap();
+    let c = cccp(&t, &["--once", "-L", "source/", "dest"]);
+    let output = dbg!(c).output().expect("running cccp");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a dangling symlink"));
+}
+
+#[test]
+fn no_temporary_files_left() {
+    let t = TestDir::new("cccp", "no_temporary_files_left");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::write(t.path("source/new"), b"new").unwrap();
+    std::fs::write(t.path("source/type_changed"), b"file").unwrap();
+    std::fs::create_dir_all(t.path("dest/type_changed")).unwrap();
+    run(&t, "source".as_ref(), "dest".as_ref());
+    assert_eq!(std::fs::read(t.path("dest/new")).unwrap(), b"new");
+    assert_eq!(std::fs::read(t.path("dest/type_changed")).unwrap(), b"file");
+    for entry in std::fs::read_dir(t.path("dest")).unwrap() {
+        let name = entry.unwrap().file_name();
+        assert!(!name.as_bytes().starts_with(b".cccp-tmp-"), "{:?}", name);
+    }
+}
+
+#[test]
+fn files_from() {
+    let t = TestDir::new("cccp", "files_from");
+    std::fs::create_dir_all(t.path("source/a/b")).unwrap();
+    std::fs::create_dir(t.path("source/c")).unwrap();
+    std::fs::write(t.path("source/a/b/listed"), b"listed").unwrap();
+    std::fs::write(t.path("source/a/unlisted"), b"unlisted").unwrap();
+    std::fs::write(t.path("source/c/new\nline"), b"newline").unwrap();
+    std::fs::write(t.path("list"), b"a/b/listed\n\n./c/../c\n").unwrap();
+    let c = cccp(&t, &["--once", "--files-from=list", "source", "dest"]);
+    let output = dbg!(c).expect_failure();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not below SOURCE"));
+    assert!(!t.path("dest").exists());
+    std::fs::write(t.path("list"), b"a/b/listed\0c/new\nline\0").unwrap();
+    let c = cccp(
+        &t,
+        &["--once", "--files-from=list", "--null", "source", "dest"],
+    );
+    dbg!(c).expect_success();
+    assert_eq!(std::fs::read(t.path("dest/a/b/listed")).unwrap(), b"listed");
+    assert_eq!(
+        std::fs::read(t.path("dest/c/new\nline")).unwrap(),
+        b"newline"
+    );
+    assert!(!t.path("dest/a/unlisted").exists());
+}
+
+#[test]
+fn sparse_fixes_stale_data() {
+    let t = TestDir::new("cccp", "sparse_fixes_stale_data");
+    std::fs::create_dir(t.path("source")).unwrap();
+    std::fs::create_dir(t.path("dest")).unwrap();
+    // a hole in the source, but data in an existing copy
+    std::fs::File::create(t.path("source/file"))
+        .unwrap()
+        .set_len(1 << 20)
+        .unwrap();
+    std::fs::write(t.path("dest/file"), vec![0xffu8; 1 << 20]).unwrap();
+    let c = cccp(&t, &["--once", "--sparse", "-T", "source", "dest"]);
+    dbg!(c).expect_success();
+    assert_eq!(
+        std::fs::read(t.path("dest/file")).unwrap(),
+        vec![0u8; 1 << 20]
+    );
+}